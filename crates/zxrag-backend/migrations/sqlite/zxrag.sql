@@ -4,6 +4,7 @@ CREATE TABLE IF NOT EXISTS file (
   filename TEXT NOT NULL,
   bytes INTEGER NOT NULL,
   purpose TEXT NOT NULL,
+  content_hash TEXT NOT NULL DEFAULT '',
   created_at INTEGER NOT NULL,
   updated_at INTEGER NOT NULL
 );
@@ -19,9 +20,52 @@ CREATE TABLE IF NOT EXISTS knowledge_base (
 
 CREATE UNIQUE INDEX IF NOT EXISTS idx_unique_name ON knowledge_base (name);
 
-CREATE TABLE IF NOT EXISTS chat_session (
+CREATE TABLE IF NOT EXISTS knowledge_base_setting (
+  kb_id INTEGER PRIMARY KEY,
+  retrieval TEXT NOT NULL,
+  created_at INTEGER NOT NULL,
+  updated_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS vector_index (
+  kb_id INTEGER PRIMARY KEY,
+  params TEXT NOT NULL,
+  created_at INTEGER NOT NULL,
+  updated_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS session (
   id INTEGER PRIMARY KEY AUTOINCREMENT,
-  name TEXT NOT NULL,
+  kb_id INTEGER,
+  title TEXT NOT NULL,
+  created_at INTEGER NOT NULL,
+  updated_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_session_kb_id ON session (kb_id);
+
+CREATE TABLE IF NOT EXISTS message (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  session_id INTEGER NOT NULL,
+  role TEXT NOT NULL,
+  content TEXT NOT NULL,
+  created_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_message_session_id ON message (session_id);
+
+CREATE TABLE IF NOT EXISTS job (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  kb_id INTEGER NOT NULL,
+  file_id INTEGER NOT NULL,
+  status TEXT NOT NULL,
+  error TEXT NOT NULL,
+  total INTEGER NOT NULL,
+  processed INTEGER NOT NULL,
   created_at INTEGER NOT NULL,
   updated_at INTEGER NOT NULL
 );
+
+CREATE INDEX IF NOT EXISTS idx_job_kb_id ON job (kb_id);
+
+CREATE INDEX IF NOT EXISTS idx_job_status ON job (status);
@@ -0,0 +1,139 @@
+use axum::extract::{Path, Query, State};
+use axum::response::{sse::Event, IntoResponse, Json, Sse};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::channel;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+use zxrag_core::types::job::JobStatus;
+use zxrag_core::types::sqlx::Job;
+
+use crate::error::BackendError;
+use crate::BackendState;
+
+const JOB_EVENTS_BUFFER: usize = 16;
+
+pub async fn list_jobs(
+  State(state): State<BackendState>,
+  Query(params): Query<ListJobsParams>,
+) -> Result<impl IntoResponse, BackendError> {
+  let jobs = match params.kb_id {
+    Some(kb_id) => {
+      sqlx::query_as::<_, Job>(
+        r#"
+SELECT * FROM job where kb_id = ? ORDER BY id DESC;
+        "#,
+      )
+      .bind(kb_id)
+      .fetch_all(&state.pool.clone())
+      .await
+    }
+    None => {
+      sqlx::query_as::<_, Job>(
+        r#"
+SELECT * FROM job ORDER BY id DESC;
+        "#,
+      )
+      .fetch_all(&state.pool.clone())
+      .await
+    }
+  }
+  .map_err(|e| anyhow::anyhow!(e))?;
+
+  Ok(Json(ListJobsResponse { data: jobs }))
+}
+
+pub async fn get_job(
+  State(state): State<BackendState>,
+  Path(job_id): Path<String>,
+) -> Result<impl IntoResponse, BackendError> {
+  let job = sqlx::query_as::<_, Job>(
+    r#"
+SELECT * FROM job where id = ?;
+    "#,
+  )
+  .bind(job_id)
+  .fetch_one(&state.pool.clone())
+  .await
+  .map_err(|e| anyhow::anyhow!(e))?;
+
+  Ok(Json(job))
+}
+
+/// Streams the job as it is now, then every update of it until it has finished.
+pub async fn job_events(
+  State(state): State<BackendState>,
+  Path(job_id): Path<String>,
+) -> Result<impl IntoResponse, BackendError> {
+  // Subscribed before reading the job, so no update falls in between
+  let mut updates = state.jobs.subscribe();
+
+  let mut job = sqlx::query_as::<_, Job>(
+    r#"
+SELECT * FROM job where id = ?;
+    "#,
+  )
+  .bind(job_id)
+  .fetch_one(&state.pool.clone())
+  .await
+  .map_err(|e| anyhow::anyhow!(e))?;
+
+  let (sender, receiver) = channel(JOB_EVENTS_BUFFER);
+
+  tokio::spawn(async move {
+    let job_id = job.id;
+
+    loop {
+      let finished = is_finished(&job);
+
+      if sender.send(job).await.is_err() || finished {
+        break;
+      }
+
+      job = loop {
+        match updates.recv().await {
+          Ok(update) if update.id == job_id => break update,
+          Ok(_) => continue,
+          // Updates were missed, the stored row is the latest one
+          Err(RecvError::Lagged(_)) => {
+            match sqlx::query_as::<_, Job>(
+              r#"
+SELECT * FROM job where id = ?;
+              "#,
+            )
+            .bind(job_id)
+            .fetch_one(&state.pool)
+            .await
+            {
+              Ok(job) => break job,
+              Err(e) => {
+                tracing::error!("job {} events: {}", job_id, e);
+                return;
+              }
+            }
+          }
+          Err(RecvError::Closed) => return,
+        }
+      };
+    }
+  });
+
+  let events = ReceiverStream::new(receiver).map(|job| Event::default().json_data(job));
+
+  Ok(Sse::new(events))
+}
+
+fn is_finished(job: &Job) -> bool {
+  JobStatus::from_str(&job.status).is_ok_and(|status| status.is_finished())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ListJobsParams {
+  kb_id: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ListJobsResponse {
+  data: Vec<Job>,
+}
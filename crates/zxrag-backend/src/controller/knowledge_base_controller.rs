@@ -1,36 +1,55 @@
-use arrow_array::types::Float32Type;
-use arrow_array::{
-  FixedSizeListArray, Int64Array, PrimitiveArray, RecordBatch, RecordBatchIterator, StringArray,
-};
-use axum::extract::{Multipart, Path, State};
+use axum::extract::{Multipart, Path, Query, State};
 use axum::response::{sse::Event, IntoResponse, Json, Sse};
-use futures::TryStreamExt;
 use opendal::services::Fs;
 use opendal::Operator;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::borrow::Cow;
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::{HashMap, HashSet};
 use time::OffsetDateTime;
 use tinyvec::tiny_vec;
 use tokio_stream::StreamExt;
 use uuid::Uuid;
-use zxrag_core::types::handle::{get_embedding_model, get_text_gen};
+use zxrag_core::retriever::{
+  fit_token_budget, format_source, highlight, reciprocal_rank_fusion, rewrite_query, Bm25Index,
+  RetrievalMode, RetrievalSetting, RetrievedChunk, RewrittenQuery,
+};
+use zxrag_core::types::handle::{get_embedding_model, get_llm_tokenizer, get_reranker_model};
+use zxrag_core::types::inference::get_inference_worker;
 use zxrag_core::types::knowledge_base::{Embedding, EmbeddingResponse, EmbeddingsUsage};
-use zxrag_core::types::lancedb::get_embedding_schema;
-use zxrag_core::types::llm::{TextGenerationSetting, TextGenerationStream};
+use zxrag_core::types::llm::{prompt_budget, TextGenerationSetting, MAX_SEQ_LEN};
 use zxrag_core::types::openai::{
-  ChatCompletion, ChatCompletionChoice, ChatCompletionChunk, ChatCompletionChunkChoice,
-  ChatCompletionChunkDelta, ChatCompletionRequest, ChatCompletionUsage, ChatMessage,
-  DeleteFileResponse, File, ListFilesResponse,
+  ChatCompletion, ChatCompletionChoice, ChatCompletionChunk, ChatCompletionRequest,
+  ChatCompletionSource, ChatMessage, DeleteFileResponse, File, ListFilesResponse,
 };
 use zxrag_core::types::sqlx::File as SqlxFile;
-use zxrag_core::types::sqlx::KnowledgeBase;
+use zxrag_core::types::sqlx::{Job, KnowledgeBase, KnowledgeBaseSetting};
+use zxrag_core::vector_store::{ChunkFilter, VectorIndexSetting, VectorIndexStats, VectorQuery};
 
-use crate::error::BackendError;
-use crate::openai_controller::ChatCompletionResponse;
+use crate::controller::session_controller::SessionTurn;
+use crate::error::{
+  BackendError, HTTP_STATUS_ERROR_EMBEDDING_MODEL, HTTP_STATUS_ERROR_SEARCH,
+  HTTP_STATUS_ERROR_VECTOR_INDEX,
+};
+use crate::gc::{delete_file_cascade, delete_knowledge_base_cascade};
+use crate::index::{build_index, drop_index};
+use crate::ingestion::{enqueue_embedding, is_embedded};
+use crate::openai_controller::{completion_events, ChatCompletionResponse};
 use crate::BackendState;
 
+/// Tokens kept for the instruction wrapping retrieved chunks in a knowledge base chat prompt.
+const CONTEXT_PROMPT_TOKENS: usize = 64;
+
+/// Tokens of the context window the session history leaves to retrieved chunks.
+const SESSION_CONTEXT_TOKENS: usize = 1024;
+
+/// Rows of a page of `list_embeddings` when the request doesn't say, and the most it may ask for.
+const LIST_EMBEDDINGS_LIMIT: usize = 100;
+const MAX_LIST_EMBEDDINGS_LIMIT: usize = 1000;
+
+/// Length in characters of the highlighted snippets of search results.
+const SEARCH_SNIPPET_CHARS: usize = 200;
+
 pub async fn create_knowledge_base(
   State(state): State<BackendState>,
   Json(req): Json<CreateKnowledgeBaseRequest>,
@@ -58,23 +77,18 @@ SELECT * FROM knowledge_base where name = ?;
   .await
   .map_err(|e| anyhow::anyhow!(e))?;
 
-  let db = vectordb::connect(&state.config.lancedb_path)
-    .await
-    .map_err(|e| anyhow::anyhow!(e))?;
-
-  let tables = db.table_names().await.map_err(|e| anyhow::anyhow!(e))?;
-
   let kb_table_name = format!("kb_{}", knowledge_base.id);
 
-  if !tables.contains(&kb_table_name) {
-    let schema = get_embedding_schema()?;
+  state
+    .vector_store
+    .create_collection(knowledge_base.id)
+    .await?;
 
-    let batches = RecordBatchIterator::new(vec![], schema);
-
-    db.create_table(&kb_table_name, Box::new(batches), None)
-      .await
-      .map_err(|e| anyhow::anyhow!(e))?;
-  }
+  state
+    .vector_store
+    .check_collection(knowledge_base.id)
+    .await
+    .map_err(|e| embedding_model_error(&kb_table_name, e))?;
 
   Ok(Json(CreateKnowledgeBaseResponse {
     id: knowledge_base.id,
@@ -114,38 +128,122 @@ SELECT * FROM knowledge_base where id = ?;
   .await
   .map_err(|e| anyhow::anyhow!(e))?;
 
-  let kb_table_name = format!("kb_{}", knowledge_base.id);
+  delete_knowledge_base_cascade(
+    &state.pool,
+    &state.config,
+    state.vector_store.as_ref(),
+    knowledge_base.id,
+  )
+  .await?;
 
-  let db = vectordb::connect(&state.config.lancedb_path)
-    .await
-    .map_err(|e| anyhow::anyhow!(e))?;
+  state.lexical_indexes.invalidate(knowledge_base.id);
 
-  let tables = db.table_names().await.map_err(|e| anyhow::anyhow!(e))?;
+  Ok(Json(DeleteKnowledgeBaseResponse {
+    name: knowledge_base.name,
+  }))
+}
 
-  if tables.contains(&kb_table_name) {
-    db.drop_table(&kb_table_name)
-      .await
-      .map_err(|e| anyhow::anyhow!(e))?;
+/// Rebuilds the table of a knowledge base from its files, with the embedding model loaded now.
+pub async fn reindex_knowledge_base(
+  State(state): State<BackendState>,
+  Path(kb_id): Path<String>,
+) -> Result<impl IntoResponse, BackendError> {
+  let knowledge_base = sqlx::query_as::<_, KnowledgeBase>(
+    r#"
+SELECT * FROM knowledge_base where id = ?;
+    "#,
+  )
+  .bind(kb_id)
+  .fetch_one(&state.pool.clone())
+  .await
+  .map_err(|e| anyhow::anyhow!(e))?;
+
+  state
+    .vector_store
+    .recreate_collection(knowledge_base.id)
+    .await?;
+
+  state.lexical_indexes.invalidate(knowledge_base.id);
+
+  let sqlx_files = sqlx::query_as::<_, SqlxFile>(
+    r#"
+SELECT * FROM file WHERE kb_id = ?;
+    "#,
+  )
+  .bind(knowledge_base.id)
+  .fetch_all(&state.pool.clone())
+  .await
+  .map_err(|e| anyhow::anyhow!(e))?;
+
+  let mut jobs = vec![];
+
+  for sqlx_file in sqlx_files {
+    jobs.push(enqueue_embedding(&state, knowledge_base.id, sqlx_file.id).await?);
   }
 
+  Ok(Json(ReindexKnowledgeBaseResponse { data: jobs }))
+}
+
+pub async fn get_knowledge_base_setting(
+  State(state): State<BackendState>,
+  Path(kb_id): Path<String>,
+) -> Result<impl IntoResponse, BackendError> {
+  let knowledge_base = sqlx::query_as::<_, KnowledgeBase>(
+    r#"
+SELECT * FROM knowledge_base where id = ?;
+    "#,
+  )
+  .bind(&kb_id)
+  .fetch_one(&state.pool.clone())
+  .await
+  .map_err(|e| anyhow::anyhow!(e))?;
+
+  Ok(Json(KnowledgeBaseSettingResponse {
+    kb_id: knowledge_base.id,
+    retrieval: knowledge_base_retrieval(&state, knowledge_base.id).await?,
+  }))
+}
+
+pub async fn update_knowledge_base_setting(
+  State(state): State<BackendState>,
+  Path(kb_id): Path<String>,
+  Json(req): Json<UpdateKnowledgeBaseSettingRequest>,
+) -> Result<impl IntoResponse, BackendError> {
+  let knowledge_base = sqlx::query_as::<_, KnowledgeBase>(
+    r#"
+SELECT * FROM knowledge_base where id = ?;
+    "#,
+  )
+  .bind(&kb_id)
+  .fetch_one(&state.pool.clone())
+  .await
+  .map_err(|e| anyhow::anyhow!(e))?;
+
   sqlx::query(
     r#"
-DELETE FROM knowledge_base where id = ?;
+INSERT INTO knowledge_base_setting ( kb_id, retrieval, created_at, updated_at )
+VALUES ( ?, ?, ?, ? )
+ON CONFLICT ( kb_id ) DO UPDATE SET retrieval = excluded.retrieval, updated_at = excluded.updated_at;
     "#,
   )
   .bind(knowledge_base.id)
+  .bind(serde_json::to_string(&req.retrieval).map_err(|e| anyhow::anyhow!(e))?)
+  .bind(OffsetDateTime::now_utc().unix_timestamp())
+  .bind(OffsetDateTime::now_utc().unix_timestamp())
   .execute(&state.pool.clone())
   .await
   .map_err(|e| anyhow::anyhow!(e))?;
 
-  Ok(Json(DeleteKnowledgeBaseResponse {
-    name: knowledge_base.name,
+  Ok(Json(KnowledgeBaseSettingResponse {
+    kb_id: knowledge_base.id,
+    retrieval: req.retrieval,
   }))
 }
 
 pub async fn upload_file(
   State(state): State<BackendState>,
   Path(kb_id): Path<String>,
+  Query(params): Query<UploadFileParams>,
   mut multipart: Multipart,
 ) -> Result<impl IntoResponse, BackendError> {
   let knowledge_base = sqlx::query_as::<_, KnowledgeBase>(
@@ -160,6 +258,12 @@ SELECT * FROM knowledge_base where id = ?;
 
   let kb_table_name = format!("kb_{}", knowledge_base.id);
 
+  let embed = params
+    .embed
+    .unwrap_or(state.config.ingestion_conf.auto_embed);
+
+  let mut uploaded = vec![];
+
   while let Some(mut field) = multipart
     .next_field()
     .await
@@ -181,41 +285,86 @@ SELECT * FROM knowledge_base where id = ?;
         .map_err(|e| anyhow::anyhow!(e))?
         .finish();
 
+      let existing = sqlx::query_as::<_, SqlxFile>(
+        r#"
+SELECT * FROM file where kb_id = ? AND filename = ?;
+        "#,
+      )
+      .bind(knowledge_base.id)
+      .bind(&file_name)
+      .fetch_optional(&state.pool.clone())
+      .await
+      .map_err(|e| anyhow::anyhow!(e))?;
+
+      // A new version replaces the old bytes rather than being appended to them
       let mut w = op
-        .writer_with(&file_name)
-        .append(true)
+        .writer(&file_name)
         .await
         .map_err(|e| anyhow::anyhow!(e))?;
 
       let mut bytes: i64 = 0;
+      let mut hasher = Sha256::new();
 
       while let Some(chunk) = field.chunk().await.map_err(|e| anyhow::anyhow!(e))? {
         bytes += chunk.len() as i64;
+        hasher.update(&chunk);
 
         w.write(chunk).await.map_err(|e| anyhow::anyhow!(e))?;
       }
 
       w.close().await.map_err(|e| anyhow::anyhow!(e))?;
 
+      let content_hash = hex::encode(hasher.finalize());
+
+      // The row is updated in place, so vectors and jobs keep pointing at the file
       sqlx::query(
         r#"
-REPLACE INTO file ( kb_id, filename, bytes, purpose, created_at, updated_at )
-VALUES ( ?, ?, ?, ?, ?, ? );
+INSERT INTO file ( kb_id, filename, bytes, purpose, content_hash, created_at, updated_at )
+VALUES ( ?, ?, ?, ?, ?, ?, ? )
+ON CONFLICT ( kb_id, filename ) DO UPDATE SET bytes = excluded.bytes, content_hash = excluded.content_hash, updated_at = excluded.updated_at;
         "#,
       )
       .bind(knowledge_base.id)
       .bind(&file_name)
       .bind(bytes)
       .bind("embedding")
+      .bind(&content_hash)
       .bind(OffsetDateTime::now_utc().unix_timestamp())
       .bind(OffsetDateTime::now_utc().unix_timestamp())
       .execute(&state.pool.clone())
       .await
       .map_err(|e| anyhow::anyhow!(e))?;
+
+      let sqlx_file = sqlx::query_as::<_, SqlxFile>(
+        r#"
+SELECT * FROM file where kb_id = ? AND filename = ?;
+        "#,
+      )
+      .bind(knowledge_base.id)
+      .bind(&file_name)
+      .fetch_one(&state.pool.clone())
+      .await
+      .map_err(|e| anyhow::anyhow!(e))?;
+
+      let unchanged = existing.is_some_and(|existing| existing.content_hash == content_hash);
+
+      let job = if embed && !(unchanged && is_embedded(&state, sqlx_file.id).await?) {
+        Some(enqueue_embedding(&state, knowledge_base.id, sqlx_file.id).await?)
+      } else {
+        None
+      };
+
+      uploaded.push(UploadedFile {
+        id: sqlx_file.id,
+        filename: file_name,
+        bytes,
+        unchanged,
+        job,
+      });
     }
   }
 
-  Ok(())
+  Ok(Json(UploadFileResponse { data: uploaded }))
 }
 
 pub async fn list_files(
@@ -272,8 +421,6 @@ SELECT * FROM knowledge_base where id = ?;
   .await
   .map_err(|e| anyhow::anyhow!(e))?;
 
-  let kb_table_name = format!("kb_{}", knowledge_base.id);
-
   let sqlx_file = sqlx::query_as::<_, SqlxFile>(
     r#"
 SELECT * FROM file where id = ? AND kb_id = ?;
@@ -285,28 +432,15 @@ SELECT * FROM file where id = ? AND kb_id = ?;
   .await
   .map_err(|e| anyhow::anyhow!(e))?;
 
-  let mut builder = Fs::default();
-
-  builder.root(&format!("{}/{}", &state.config.opendal_path, kb_table_name));
-
-  let op: Operator = Operator::new(builder)
-    .map_err(|e| anyhow::anyhow!(e))?
-    .finish();
-
-  op.delete(&sqlx_file.filename)
-    .await
-    .map_err(|e| anyhow::anyhow!(e))?;
-
-  sqlx::query(
-    r#"
-DELETE FROM file where id = ? AND kb_id = ?;
-    "#,
+  delete_file_cascade(
+    &state.pool,
+    &state.config,
+    state.vector_store.as_ref(),
+    &sqlx_file,
   )
-  .bind(sqlx_file.id)
-  .bind(knowledge_base.id)
-  .execute(&state.pool.clone())
-  .await
-  .map_err(|e| anyhow::anyhow!(e))?;
+  .await?;
+
+  state.lexical_indexes.invalidate(knowledge_base.id);
 
   Ok(Json(DeleteFileResponse {
     id: Cow::Owned(sqlx_file.id.to_string()),
@@ -329,100 +463,28 @@ SELECT * FROM knowledge_base where id = ?;
   .await
   .map_err(|e| anyhow::anyhow!(e))?;
 
-  let kb_table_name = format!("kb_{}", knowledge_base.id);
-
   let sqlx_file = sqlx::query_as::<_, SqlxFile>(
     r#"
 SELECT * FROM file where id = ? AND kb_id = ?;
     "#,
   )
   .bind(&file_id)
-  .bind(&kb_id)
+  .bind(knowledge_base.id)
   .fetch_one(&state.pool.clone())
   .await
   .map_err(|e| anyhow::anyhow!(e))?;
 
-  let mut builder = Fs::default();
-
-  builder.root(&format!("{}/{}", &state.config.opendal_path, kb_table_name));
-
-  let op: Operator = Operator::new(builder)
-    .map_err(|e| anyhow::anyhow!(e))?
-    .finish();
-
-  let file_bytes = op
-    .read(&sqlx_file.filename)
-    .await
-    .map_err(|e| anyhow::anyhow!(e))?;
-
-  let bert_model = get_embedding_model(state.config.embedding_conf.model_id)?;
-
-  let prompts: Vec<&str> = vec![std::str::from_utf8(&file_bytes).map_err(|e| anyhow::anyhow!(e))?];
-
-  let embeddings: Vec<Vec<f32>> = bert_model.embedding_batch(&prompts)?;
-
-  let vectors: Vec<Option<Vec<Option<f32>>>> = embeddings
-    .into_iter()
-    .map(|t| Some(t.into_iter().map(Some).collect()))
-    .collect();
-
-  let schema = get_embedding_schema()?;
-
-  let db = vectordb::connect(&state.config.lancedb_path)
-    .await
-    .map_err(|e| anyhow::anyhow!(e))?;
-
-  let tbl = db
-    .open_table(&kb_table_name)
-    .await
-    .map_err(|e| anyhow::anyhow!(e))?;
-
-  let uuid_strings: Vec<Option<String>> = (0..vectors.len())
-    .map(|_| Some(Uuid::new_v4().to_string()))
-    .collect();
-
-  let uuid_str_slices: Vec<Option<&str>> =
-    uuid_strings.iter().map(|uuid| uuid.as_deref()).collect();
-
-  let batches = RecordBatchIterator::new(
-    vec![RecordBatch::try_new(
-      schema.clone(),
-      vec![
-        Arc::new(StringArray::from(uuid_str_slices)),
-        Arc::new(Int64Array::from_iter_values(
-          (0..vectors.len()).map(|_| knowledge_base.id),
-        )),
-        Arc::new(Int64Array::from_iter_values(
-          (0..vectors.len()).map(|_| sqlx_file.id),
-        )),
-        Arc::new(StringArray::from(
-          (0..vectors.len())
-            .map(|_| Some(sqlx_file.filename.as_str()))
-            .collect::<Vec<Option<&str>>>(),
-        )),
-        Arc::new(StringArray::from(
-          prompts.into_iter().map(Some).collect::<Vec<Option<&str>>>(),
-        )),
-        Arc::new(FixedSizeListArray::from_iter_primitive::<Float32Type, _, _>(vectors, 1024)),
-      ],
-    )
-    .map_err(|e| anyhow::anyhow!(e))?]
-    .into_iter()
-    .map(Ok),
-    schema.clone(),
-  );
-
-  tbl
-    .add(Box::new(batches), None)
-    .await
-    .map_err(|e| anyhow::anyhow!(e))?;
+  let job = enqueue_embedding(&state, knowledge_base.id, sqlx_file.id).await?;
 
-  Ok(())
+  Ok(Json(job))
 }
 
+/// Lists the rows of a knowledge base table a page at a time, optionally restricted to a file or
+/// to rows whose text contains a substring.
 pub async fn list_embeddings(
   State(state): State<BackendState>,
   Path(kb_id): Path<String>,
+  Query(params): Query<ListEmbeddingsParams>,
 ) -> Result<impl IntoResponse, BackendError> {
   let knowledge_base = sqlx::query_as::<_, KnowledgeBase>(
     r#"
@@ -434,59 +496,64 @@ SELECT * FROM knowledge_base where id = ?;
   .await
   .map_err(|e| anyhow::anyhow!(e))?;
 
-  let kb_table_name = format!("kb_{}", knowledge_base.id);
+  let offset = params.offset.unwrap_or(0);
+  let limit = params
+    .limit
+    .unwrap_or(LIST_EMBEDDINGS_LIMIT)
+    .clamp(1, MAX_LIST_EMBEDDINGS_LIMIT);
+  let include_vectors = params.vectors.unwrap_or(true);
+
+  let filter = ChunkFilter {
+    file_ids: params.file_id.into_iter().collect(),
+    text: params.text.filter(|text| !text.is_empty()),
+    ..Default::default()
+  };
 
-  let db = vectordb::connect(&state.config.lancedb_path)
-    .await
-    .map_err(|e| anyhow::anyhow!(e))?;
+  // One row past the page tells whether there is a next one
+  let mut chunks = state
+    .vector_store
+    .list(
+      knowledge_base.id,
+      &filter,
+      offset,
+      Some(limit + 1),
+      include_vectors,
+    )
+    .await?;
 
-  let tbl = db
-    .open_table(&kb_table_name)
-    .await
-    .map_err(|e| anyhow::anyhow!(e))?;
+  let next_offset = if chunks.len() > limit {
+    chunks.truncate(limit);
 
-  let result = tbl
-    .query()
-    .filter(format!("kb_id = {}", &knowledge_base.id))
-    .execute_stream()
-    .await
-    .map_err(|e| anyhow::anyhow!(e))?
-    .try_collect::<Vec<_>>()
-    .await
-    .map_err(|e| anyhow::anyhow!(e))?;
+    Some(offset + limit)
+  } else {
+    None
+  };
+
+  let embeddings = chunks
+    .into_iter()
+    .enumerate()
+    .map(|(index, chunk)| Embedding {
+      id: Cow::Owned(chunk.id),
+      kb_id: chunk.kb_id,
+      file_id: chunk.file_id,
+      filename: Cow::Owned(chunk.file_name),
+      object: Cow::Owned("embedding".to_string()),
+      text: Cow::Owned(chunk.text),
+      heading_path: Cow::Owned(chunk.heading_path),
+      embedding: include_vectors.then_some(chunk.vector),
+      index: offset + index,
+    })
+    .collect();
 
   Ok(Json(EmbeddingResponse {
     object: Cow::Owned("list".to_string()),
-    embeddings: result
-      .into_iter()
-      .enumerate()
-      .map(|(index, batch)| {
-        let embedding_id: StringArray = batch.column(0).to_data().into();
-        let kb_id: Int64Array = batch.column(1).to_data().into();
-        let file_id: Int64Array = batch.column(2).to_data().into();
-        let filename: StringArray = batch.column(3).to_data().into();
-        let text: StringArray = batch.column(4).to_data().into();
-        let vector: FixedSizeListArray = batch.column(5).to_data().into();
-
-        Embedding {
-          id: Cow::Owned(embedding_id.value(0).to_string()),
-          kb_id: kb_id.value(0),
-          file_id: file_id.value(0),
-          filename: Cow::Owned(filename.value(0).to_string()),
-          object: Cow::Owned("embedding".to_string()),
-          text: Cow::Owned(text.value(0).to_string()),
-          embedding: Into::<PrimitiveArray<Float32Type>>::into(vector.value(0).to_data())
-            .values()
-            .to_vec(),
-          index,
-        }
-      })
-      .collect(),
+    embeddings,
     model: Cow::Owned(state.config.embedding_conf.model_id.to_string()),
     usage: EmbeddingsUsage {
       prompt_tokens: 0,
       total_tokens: 0,
     },
+    next_offset,
   }))
 }
 
@@ -504,21 +571,18 @@ SELECT * FROM knowledge_base where id = ?;
   .await
   .map_err(|e| anyhow::anyhow!(e))?;
 
-  let kb_table_name = format!("kb_{}", knowledge_base.id);
-
-  let db = vectordb::connect(&state.config.lancedb_path)
-    .await
-    .map_err(|e| anyhow::anyhow!(e))?;
-
-  let tbl = db
-    .open_table(&kb_table_name)
-    .await
-    .map_err(|e| anyhow::anyhow!(e))?;
+  state
+    .vector_store
+    .delete(
+      knowledge_base.id,
+      &ChunkFilter {
+        ids: vec![embedding_id],
+        ..Default::default()
+      },
+    )
+    .await?;
 
-  tbl
-    .delete(&format!("id = '{}'", embedding_id))
-    .await
-    .map_err(|e| anyhow::anyhow!(e))?;
+  state.lexical_indexes.invalidate(knowledge_base.id);
 
   Ok(())
 }
@@ -542,55 +606,172 @@ SELECT * FROM knowledge_base where id = ?;
 
   let kb_table_name = format!("kb_{}", knowledge_base.id);
 
-  let db = vectordb::connect(&state.config.lancedb_path)
+  state
+    .vector_store
+    .check_collection(knowledge_base.id)
     .await
-    .map_err(|e| anyhow::anyhow!(e))?;
+    .map_err(|e| embedding_model_error(&kb_table_name, e))?;
+
+  let retrieval_conf = &state.config.retrieval_conf;
+  let reranker_conf = &state.config.reranker_conf;
+
+  let setting = req
+    .retrieval
+    .take()
+    .unwrap_or_default()
+    .or(knowledge_base_retrieval(&state, knowledge_base.id).await?);
+
+  let sample_len = req
+    .max_tokens
+    .map_or(128, |value| value.try_into().unwrap_or(128));
+
+  // The question is stored as asked, before the retrieved sources are added to it
+  let session_turn = match req.session_id {
+    Some(session_id) => Some(
+      SessionTurn::begin(
+        &state,
+        session_id,
+        Some(knowledge_base.id),
+        &mut req.messages,
+        sample_len + CONTEXT_PROMPT_TOKENS + SESSION_CONTEXT_TOKENS,
+      )
+      .await?,
+    ),
+    None => None,
+  };
 
-  let tbl = db
-    .open_table(&kb_table_name)
-    .await
-    .map_err(|e| anyhow::anyhow!(e))?;
+  let retrieval_mode = setting.mode.unwrap_or(retrieval_conf.mode);
+  let top_k = setting.top_k.unwrap_or(retrieval_conf.top_k);
+
+  let llm_tokenizer = get_llm_tokenizer()?;
+
+  // Retrieved chunks get whatever the conversation and the answer leave of the context window
+  let prompt_tokens = llm_tokenizer
+    .encode(
+      req.messages.to_prompt(state.config.llm_conf.model_id)?,
+      false,
+    )
+    .map_err(anyhow::Error::msg)?
+    .len();
+
+  let context_budget =
+    MAX_SEQ_LEN.saturating_sub(prompt_tokens + sample_len + CONTEXT_PROMPT_TOKENS);
+
+  let rewrite = setting.rewrite.unwrap_or(retrieval_conf.rewrite);
+
+  let rewritten = match rewrite_query(
+    rewrite,
+    &req.messages,
+    state.config.llm_conf.model_id,
+    retrieval_conf.multi_queries.max(1),
+  )
+  .await
+  {
+    Ok(rewritten) => rewritten,
+    Err(e) => {
+      tracing::error!("rewriting query failed, searching the message as is: {}", e);
+
+      RewrittenQuery::new(
+        req
+          .messages
+          .last()
+          .ok_or(anyhow::anyhow!("messages is empty"))?
+          .to_string(),
+      )
+    }
+  };
 
   let last_message = req
     .messages
     .last_mut()
     .ok_or(anyhow::anyhow!("messages is empty"))?;
 
-  let bert_model = get_embedding_model(state.config.embedding_conf.model_id)?;
-
   let last_message_str = last_message.to_string();
 
-  let prompts: Vec<&str> = vec![&last_message_str];
+  let mut candidates = retrieval_conf.candidates.max(top_k).max(1);
 
-  let embeddings: Vec<Vec<f32>> = bert_model.embedding_batch(&prompts)?;
+  if reranker_conf.enabled {
+    candidates = candidates.max(reranker_conf.candidates);
+  }
 
-  let result: Vec<String> = tbl
-    .search(&embeddings[0])
-    .limit(1)
-    .execute_stream()
-    .await
-    .map_err(|e| anyhow::anyhow!(e))?
-    .try_collect::<Vec<_>>()
-    .await
-    .map_err(|e| anyhow::anyhow!(e))?
-    .iter()
-    .map(|batch| {
-      let text: StringArray = batch.column(4).to_data().into();
+  let embeddings = if retrieval_mode != RetrievalMode::Lexical {
+    let bert_model = get_embedding_model(state.config.embedding_conf.model_id)?;
 
-      text.value(0).to_string()
+    let texts = rewritten.embedding_texts.clone();
+
+    tokio::task::spawn_blocking(move || {
+      let prompts: Vec<&str> = texts.iter().map(|text| text.as_str()).collect();
+
+      bert_model.embedding_batch(&prompts)
     })
-    .collect();
+    .await
+    .map_err(|e| anyhow::anyhow!(e))??
+  } else {
+    vec![]
+  };
 
-  *last_message = ChatMessage::User {
-    content: either::Left(Cow::Owned(format!(
-      "{} answer question use the follwing information: {}",
-      last_message_str,
-      result.join("\n")
-    ))),
-    name: None,
+  let lexical_queries = if retrieval_mode != RetrievalMode::Vector {
+    rewritten.lexical_queries.as_slice()
+  } else {
+    &[]
   };
 
-  let untokenized_context = req.messages.to_prompt(state.config.llm_conf.model_id)?;
+  let mut chunks = rank_chunks(
+    &state,
+    knowledge_base.id,
+    &setting,
+    &embeddings,
+    lexical_queries,
+    candidates,
+  )
+  .await?;
+
+  if reranker_conf.enabled {
+    chunks = rerank_chunks(&state, &rewritten.query, chunks, &setting).await?;
+  }
+
+  chunks.truncate(top_k);
+
+  let chunks = fit_token_budget(chunks, context_budget, llm_tokenizer)?;
+
+  tracing::info!(
+    "mode={} rewrite={} reranked={} chunks={}",
+    retrieval_mode,
+    rewrite,
+    reranker_conf.enabled,
+    chunks.len()
+  );
+
+  let sources: Vec<ChatCompletionSource> = chunks
+    .iter()
+    .enumerate()
+    .map(|(index, chunk)| ChatCompletionSource::new(index + 1, chunk))
+    .collect();
+
+  // Nothing relevant enough was found, so the question goes to the model as is
+  if !chunks.is_empty() {
+    let context: Vec<String> = chunks
+      .iter()
+      .enumerate()
+      .map(|(index, chunk)| format_source(index + 1, chunk))
+      .collect();
+
+    *last_message = ChatMessage::User {
+      content: either::Left(Cow::Owned(format!(
+        "{}\n\nAnswer the question using the sources below. Cite every source you use with its \
+         marker, such as [1].\n\n{}",
+        last_message_str,
+        context.join("\n\n")
+      ))),
+      name: None,
+    };
+  }
+
+  let untokenized_context = req.messages.to_fitted_prompt(
+    state.config.llm_conf.model_id,
+    llm_tokenizer,
+    prompt_budget(sample_len),
+  )?;
 
   let text_gen_setting = TextGenerationSetting {
     temperature: req.temperature.unwrap_or(0.8),
@@ -598,61 +779,63 @@ SELECT * FROM knowledge_base where id = ?;
     seed: req.seed.unwrap_or(299792458),
     repeat_penalty: req.frequency_penalty.unwrap_or(1.1),
     repeat_last_n: 64,
-    sample_len: req
-      .max_tokens
-      .map_or(128, |value| value.try_into().unwrap_or(128)),
+    sample_len,
     prompt: untokenized_context,
+    stop: req.stop_sequences(),
   };
 
-  let mut text_gen = get_text_gen(text_gen_setting)?;
+  let inference_worker = get_inference_worker()?;
 
   let stream_response = req.stream.unwrap_or(false);
 
+  let include_usage = req.include_usage();
+
   let response = if stream_response {
-    let stream = TextGenerationStream::new(text_gen)?.throttle(Duration::from_millis(10));
-
-    let completions_stream = stream.map(move |chunk| {
-      Event::default().json_data(ChatCompletionChunk {
-        id: Uuid::new_v4().to_string().into(),
-        choices: tiny_vec![ChatCompletionChunkChoice {
-          index: 0,
-          finish_reason: None,
-          delta: ChatCompletionChunkDelta {
-            content: Some(Cow::Owned(chunk)),
-            role: None,
-          },
-        }],
-        created: OffsetDateTime::now_utc().unix_timestamp(),
-        model: Cow::Borrowed("main"),
-        system_fingerprint: Cow::Borrowed(&fp),
-        object: Cow::Borrowed("text_completion"),
-      })
+    let sources_event = Event::default().json_data(ChatCompletionChunk {
+      id: Uuid::new_v4().to_string().into(),
+      choices: tiny_vec![],
+      created: OffsetDateTime::now_utc().unix_timestamp(),
+      model: Cow::Borrowed("main"),
+      system_fingerprint: Cow::Borrowed(&fp),
+      object: Cow::Borrowed("text_completion"),
+      usage: None,
+      sources: Some(sources),
     });
 
-    ChatCompletionResponse::Stream(Sse::new(completions_stream))
+    let completions_stream = completion_events(
+      inference_worker.submit(text_gen_setting)?,
+      session_turn,
+      include_usage,
+      fp,
+    );
+
+    ChatCompletionResponse::Stream(Sse::new(
+      tokio_stream::once(sources_event).chain(completions_stream),
+    ))
   } else {
-    let content_str = text_gen.generate()?;
+    let output = inference_worker.generate(text_gen_setting).await?;
+
+    if let Some(session_turn) = session_turn {
+      session_turn.finish(output.text.clone());
+    }
 
     let response = ChatCompletion {
       id: Uuid::new_v4().to_string().into(),
       choices: vec![ChatCompletionChoice {
         message: ChatMessage::Assistant {
-          content: Some(Cow::Owned(content_str)),
+          content: Some(Cow::Owned(output.text)),
           name: None,
           tool_calls: None,
         },
-        finish_reason: None,
+        finish_reason: Some(Cow::Owned(output.finish_reason.to_string())),
         index: 0,
       }],
       created: OffsetDateTime::now_utc().unix_timestamp(),
       model: Cow::Borrowed("main"),
       object: Cow::Borrowed("text_completion"),
       system_fingerprint: Cow::Owned(fp),
-      usage: ChatCompletionUsage {
-        completion_tokens: 0,
-        prompt_tokens: 0,
-        total_tokens: 0,
-      },
+      usage: output.usage.into(),
+      sources: Some(sources),
     };
 
     ChatCompletionResponse::Full(Json(response))
@@ -661,6 +844,437 @@ SELECT * FROM knowledge_base where id = ?;
   Ok(response)
 }
 
+/// Ranks the chunks of a knowledge base against a query, a raw vector or both, the way a
+/// knowledge base chat retrieves them but without the LLM.
+pub async fn search_knowledge_base(
+  State(state): State<BackendState>,
+  Path(kb_id): Path<String>,
+  Json(req): Json<SearchRequest>,
+) -> Result<impl IntoResponse, BackendError> {
+  let knowledge_base = sqlx::query_as::<_, KnowledgeBase>(
+    r#"
+SELECT * FROM knowledge_base where id = ?;
+    "#,
+  )
+  .bind(&kb_id)
+  .fetch_one(&state.pool.clone())
+  .await
+  .map_err(|e| anyhow::anyhow!(e))?;
+
+  let query = req.query.filter(|query| !query.trim().is_empty());
+
+  if query.is_none() && req.vector.is_none() {
+    return Err(search_error("query or vector is required".to_string()));
+  }
+
+  let kb_table_name = format!("kb_{}", knowledge_base.id);
+
+  state
+    .vector_store
+    .check_collection(knowledge_base.id)
+    .await
+    .map_err(|e| embedding_model_error(&kb_table_name, e))?;
+
+  let retrieval_conf = &state.config.retrieval_conf;
+  let reranker_conf = &state.config.reranker_conf;
+
+  let setting = req
+    .retrieval
+    .or(knowledge_base_retrieval(&state, knowledge_base.id).await?);
+
+  let retrieval_mode = setting.mode.unwrap_or(retrieval_conf.mode);
+  let top_k = setting.top_k.unwrap_or(retrieval_conf.top_k);
+
+  // A raw vector is searched as given, in place of the embedded query
+  let embeddings = match (&req.vector, &query) {
+    _ if retrieval_mode == RetrievalMode::Lexical => vec![],
+    (Some(vector), _) => {
+      let dimension = state.vector_store.dimension(knowledge_base.id).await?;
+
+      if vector.len() != dimension {
+        return Err(search_error(format!(
+          "vector has {} dims but {} holds {} dims",
+          vector.len(),
+          kb_table_name,
+          dimension
+        )));
+      }
+
+      vec![vector.clone()]
+    }
+    (None, Some(query)) => {
+      let bert_model = get_embedding_model(state.config.embedding_conf.model_id)?;
+      let query = query.clone();
+
+      tokio::task::spawn_blocking(move || bert_model.embedding_batch(&[query.as_str()]))
+        .await
+        .map_err(|e| anyhow::anyhow!(e))??
+    }
+    (None, None) => vec![],
+  };
+
+  let lexical_queries = match &query {
+    Some(query) if retrieval_mode != RetrievalMode::Vector => vec![query.clone()],
+    _ => vec![],
+  };
+
+  if embeddings.is_empty() && lexical_queries.is_empty() {
+    return Err(search_error(format!(
+      "a {} search needs a query",
+      retrieval_mode
+    )));
+  }
+
+  let rerank = reranker_conf.enabled && req.rerank.unwrap_or(true) && query.is_some();
+
+  let mut candidates = retrieval_conf.candidates.max(top_k).max(1);
+
+  if rerank {
+    candidates = candidates.max(reranker_conf.candidates);
+  }
+
+  let mut chunks = rank_chunks(
+    &state,
+    knowledge_base.id,
+    &setting,
+    &embeddings,
+    &lexical_queries,
+    candidates,
+  )
+  .await?;
+
+  if let Some(query) = query.as_deref().filter(|_| rerank) {
+    chunks = rerank_chunks(&state, query, chunks, &setting).await?;
+  }
+
+  chunks.truncate(top_k);
+
+  let files: HashMap<i64, SqlxFile> = sqlx::query_as::<_, SqlxFile>(
+    r#"
+SELECT * FROM file where kb_id = ?;
+    "#,
+  )
+  .bind(knowledge_base.id)
+  .fetch_all(&state.pool.clone())
+  .await
+  .map_err(|e| anyhow::anyhow!(e))?
+  .into_iter()
+  .map(|sqlx_file| (sqlx_file.id, sqlx_file))
+  .collect();
+
+  let data = chunks
+    .into_iter()
+    .enumerate()
+    .map(|(index, chunk)| SearchResult {
+      rank: index + 1,
+      highlight: match &query {
+        Some(query) if req.highlight.unwrap_or(false) => {
+          highlight(&chunk.text, query, SEARCH_SNIPPET_CHARS)
+        }
+        _ => None,
+      },
+      file: files.get(&chunk.file_id).cloned(),
+      id: chunk.id,
+      file_id: chunk.file_id,
+      filename: chunk.file_name,
+      heading_path: chunk.heading_path,
+      text: chunk.text,
+      score: chunk.score,
+    })
+    .collect();
+
+  Ok(Json(SearchResponse {
+    data,
+    mode: retrieval_mode,
+    reranked: rerank,
+  }))
+}
+
+pub async fn get_vector_index(
+  State(state): State<BackendState>,
+  Path(kb_id): Path<String>,
+) -> Result<impl IntoResponse, BackendError> {
+  let knowledge_base = sqlx::query_as::<_, KnowledgeBase>(
+    r#"
+SELECT * FROM knowledge_base where id = ?;
+    "#,
+  )
+  .bind(kb_id)
+  .fetch_one(&state.pool.clone())
+  .await
+  .map_err(|e| anyhow::anyhow!(e))?;
+
+  Ok(Json(
+    vector_index_response(&state, knowledge_base.id).await?,
+  ))
+}
+
+/// Builds the index in the request, replacing the current one, and answers once it is built.
+pub async fn build_vector_index(
+  State(state): State<BackendState>,
+  Path(kb_id): Path<String>,
+  Json(req): Json<VectorIndexSetting>,
+) -> Result<impl IntoResponse, BackendError> {
+  let knowledge_base = sqlx::query_as::<_, KnowledgeBase>(
+    r#"
+SELECT * FROM knowledge_base where id = ?;
+    "#,
+  )
+  .bind(kb_id)
+  .fetch_one(&state.pool.clone())
+  .await
+  .map_err(|e| anyhow::anyhow!(e))?;
+
+  build_index(
+    &state.pool,
+    state.vector_store.clone(),
+    knowledge_base.id,
+    req.params(&state.config),
+  )
+  .await
+  .map_err(|e| vector_index_error(knowledge_base.id, e))?;
+
+  Ok(Json(
+    vector_index_response(&state, knowledge_base.id).await?,
+  ))
+}
+
+/// Drops the index, searches of the knowledge base scan every vector again.
+pub async fn drop_vector_index(
+  State(state): State<BackendState>,
+  Path(kb_id): Path<String>,
+) -> Result<impl IntoResponse, BackendError> {
+  let knowledge_base = sqlx::query_as::<_, KnowledgeBase>(
+    r#"
+SELECT * FROM knowledge_base where id = ?;
+    "#,
+  )
+  .bind(kb_id)
+  .fetch_one(&state.pool.clone())
+  .await
+  .map_err(|e| anyhow::anyhow!(e))?;
+
+  drop_index(&state.pool, state.vector_store.as_ref(), knowledge_base.id)
+    .await
+    .map_err(|e| vector_index_error(knowledge_base.id, e))?;
+
+  Ok(Json(
+    vector_index_response(&state, knowledge_base.id).await?,
+  ))
+}
+
+async fn vector_index_response(
+  state: &BackendState,
+  kb_id: i64,
+) -> anyhow::Result<VectorIndexResponse> {
+  Ok(VectorIndexResponse {
+    kb_id,
+    rows: state.vector_store.count(kb_id).await?,
+    index: state.vector_store.index_stats(kb_id).await?,
+  })
+}
+
+/// Ranks the chunks of a knowledge base collection once per embedding and once per lexical query.
+/// A single ranking keeps its own scores, several are fused.
+async fn rank_chunks(
+  state: &BackendState,
+  kb_id: i64,
+  setting: &RetrievalSetting,
+  embeddings: &[Vec<f32>],
+  lexical_queries: &[String],
+  candidates: usize,
+) -> anyhow::Result<Vec<RetrievedChunk>> {
+  let retrieval_conf = &state.config.retrieval_conf;
+
+  let metric = setting.metric.unwrap_or(retrieval_conf.metric);
+  let nprobes = setting.nprobes.unwrap_or(retrieval_conf.nprobes);
+  let refine_factor = Some(
+    setting
+      .refine_factor
+      .unwrap_or(retrieval_conf.refine_factor),
+  )
+  .filter(|refine_factor| *refine_factor > 0);
+
+  let mut rankings: Vec<Vec<RetrievedChunk>> = vec![];
+
+  for embedding in embeddings {
+    let query = VectorQuery {
+      vector: embedding.clone(),
+      limit: candidates,
+      metric,
+      filter: ChunkFilter::from(&setting.filter),
+      nprobes,
+      refine_factor,
+    };
+
+    let ranking = state
+      .vector_store
+      .search(kb_id, &query)
+      .await?
+      .into_iter()
+      .filter(|chunk| {
+        !setting
+          .max_distance
+          .is_some_and(|max| metric.normalize(chunk.score) > max)
+      })
+      .collect();
+
+    rankings.push(ranking);
+  }
+
+  let vector_rankings = rankings.len();
+
+  if !lexical_queries.is_empty() {
+    let index = match state.lexical_indexes.get(kb_id) {
+      Some(index) => index,
+      None => {
+        let generation = state.lexical_indexes.generation();
+
+        let chunks: Vec<RetrievedChunk> = state
+          .vector_store
+          .list(kb_id, &ChunkFilter::default(), 0, None, false)
+          .await?
+          .into_iter()
+          .map(|chunk| chunk.into_retrieved(0.0))
+          .collect();
+
+        let index = tokio::task::spawn_blocking(move || Bm25Index::new(chunks)).await?;
+
+        tracing::info!(
+          "lexical index of kb_{} built, {} chunks",
+          kb_id,
+          index.len()
+        );
+
+        state.lexical_indexes.insert(kb_id, generation, index)
+      }
+    };
+
+    for query in lexical_queries {
+      rankings.push(index.search(query, candidates, &setting.filter));
+    }
+  }
+
+  // Lexical hits are held to the same distance as vector hits, measured to the closest embedding
+  if let Some(max_distance) = setting.max_distance.filter(|_| !embeddings.is_empty()) {
+    let lexical_ids = ChunkFilter {
+      ids: rankings[vector_rankings..]
+        .iter()
+        .flatten()
+        .map(|chunk| chunk.id.clone())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect(),
+      ..Default::default()
+    };
+
+    if !lexical_ids.ids.is_empty() {
+      let close: HashSet<String> = state
+        .vector_store
+        .list(kb_id, &lexical_ids, 0, None, true)
+        .await?
+        .into_iter()
+        .filter(|chunk| {
+          embeddings.iter().any(|embedding| {
+            metric.normalize(metric.distance(embedding, &chunk.vector)) <= max_distance
+          })
+        })
+        .map(|chunk| chunk.id)
+        .collect();
+
+      for ranking in &mut rankings[vector_rankings..] {
+        ranking.retain(|chunk| close.contains(&chunk.id));
+      }
+    }
+  }
+
+  let chunks = if rankings.len() == 1 {
+    rankings.pop().unwrap_or_default()
+  } else {
+    reciprocal_rank_fusion(&rankings, retrieval_conf.rrf_k)
+  };
+
+  Ok(chunks)
+}
+
+/// Scores the leading `reranker_conf.candidates` chunks against `query` with the reranker, best
+/// first, and drops those below `min_relevance`.
+async fn rerank_chunks(
+  state: &BackendState,
+  query: &str,
+  mut chunks: Vec<RetrievedChunk>,
+  setting: &RetrievalSetting,
+) -> anyhow::Result<Vec<RetrievedChunk>> {
+  let reranker_conf = &state.config.reranker_conf;
+
+  chunks.truncate(reranker_conf.candidates.max(1));
+
+  let reranker = get_reranker_model(reranker_conf.model_id)?;
+
+  let query = query.to_string();
+  let texts: Vec<String> = chunks.iter().map(|chunk| chunk.text.clone()).collect();
+
+  let scores = tokio::task::spawn_blocking(move || {
+    let texts: Vec<&str> = texts.iter().map(|t| t.as_str()).collect();
+
+    reranker.rerank(&query, &texts)
+  })
+  .await??;
+
+  for (chunk, score) in chunks.iter_mut().zip(scores) {
+    chunk.score = score;
+  }
+
+  chunks.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+  chunks.retain(|chunk| !setting.min_relevance.is_some_and(|min| chunk.score < min));
+
+  Ok(chunks)
+}
+
+async fn knowledge_base_retrieval(
+  state: &BackendState,
+  kb_id: i64,
+) -> anyhow::Result<RetrievalSetting> {
+  let setting = sqlx::query_as::<_, KnowledgeBaseSetting>(
+    r#"
+SELECT * FROM knowledge_base_setting where kb_id = ?;
+    "#,
+  )
+  .bind(kb_id)
+  .fetch_optional(&state.pool.clone())
+  .await?;
+
+  match setting {
+    Some(setting) => Ok(serde_json::from_str(&setting.retrieval)?),
+    None => Ok(RetrievalSetting::default()),
+  }
+}
+
+fn search_error(msg: String) -> BackendError {
+  BackendError::CommonException {
+    status: HTTP_STATUS_ERROR_SEARCH,
+    msg,
+  }
+}
+
+fn vector_index_error(kb_id: i64, e: anyhow::Error) -> BackendError {
+  BackendError::CommonException {
+    status: HTTP_STATUS_ERROR_VECTOR_INDEX,
+    msg: format!("kb_{}: {}", kb_id, e),
+  }
+}
+
+fn embedding_model_error(kb_table_name: &str, e: anyhow::Error) -> BackendError {
+  BackendError::CommonException {
+    status: HTTP_STATUS_ERROR_EMBEDDING_MODEL,
+    msg: format!(
+      "{}: {}, reindex the knowledge base to rebuild it",
+      kb_table_name, e
+    ),
+  }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct CreateKnowledgeBaseRequest {
   name: String,
@@ -682,3 +1296,197 @@ pub struct ListKnowledgeBaseResponse {
 pub struct DeleteKnowledgeBaseResponse {
   name: String,
 }
+
+#[derive(Serialize, Deserialize)]
+pub struct UpdateKnowledgeBaseSettingRequest {
+  retrieval: RetrievalSetting,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct KnowledgeBaseSettingResponse {
+  kb_id: i64,
+  retrieval: RetrievalSetting,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UploadFileParams {
+  /// Queues embedding the uploaded files, `ingestion_conf.auto_embed` when unset.
+  embed: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UploadFileResponse {
+  data: Vec<UploadedFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UploadedFile {
+  id: i64,
+  filename: String,
+  bytes: i64,
+  /// The file was uploaded before with the same content.
+  unchanged: bool,
+  job: Option<Job>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ListEmbeddingsParams {
+  file_id: Option<i64>,
+  /// Keeps rows whose text contains this, ignoring case.
+  text: Option<String>,
+  offset: Option<usize>,
+  limit: Option<usize>,
+  /// Includes the vector of every row, true when unset.
+  vectors: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReindexKnowledgeBaseResponse {
+  data: Vec<Job>,
+}
+
+/// `query` is searched as given, the `rewrite` setting only applies to chats.
+#[derive(Serialize, Deserialize)]
+pub struct SearchRequest {
+  query: Option<String>,
+  /// Searched by similarity instead of the embedded `query`, which is still used for lexical
+  /// search, reranking and highlighting.
+  vector: Option<Vec<f32>>,
+  /// Reranks the results when a reranker is loaded and there is a query, true when unset.
+  rerank: Option<bool>,
+  /// Adds a snippet around the query terms to every result.
+  highlight: Option<bool>,
+  #[serde(flatten)]
+  retrieval: RetrievalSetting,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SearchResponse {
+  data: Vec<SearchResult>,
+  mode: RetrievalMode,
+  reranked: bool,
+}
+
+/// A ranked chunk. `score` is the vector distance, the BM25 score, the fused RRF score or the
+/// reranker relevance, as in [`RetrievedChunk`].
+#[derive(Serialize, Deserialize)]
+pub struct SearchResult {
+  rank: usize,
+  id: String,
+  file_id: i64,
+  filename: String,
+  heading_path: String,
+  text: String,
+  score: f32,
+  highlight: Option<String>,
+  /// `None` when the file was deleted since its chunks were stored.
+  file: Option<SqlxFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct VectorIndexResponse {
+  kb_id: i64,
+  rows: usize,
+  /// `None` when searches scan every vector.
+  index: Option<VectorIndexStats>,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use zxrag_core::types::conf::BackendConf;
+
+  use crate::testing::{chunk, execute, state};
+
+  async fn listed(state: &BackendState, params: serde_json::Value) -> serde_json::Value {
+    let response = list_embeddings(
+      State(state.clone()),
+      Path("1".to_string()),
+      Query(serde_json::from_value(params).unwrap()),
+    )
+    .await
+    .unwrap()
+    .into_response();
+
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+      .await
+      .unwrap();
+
+    serde_json::from_slice(&body).unwrap()
+  }
+
+  fn ids(listed: &serde_json::Value) -> Vec<&str> {
+    listed["embeddings"]
+      .as_array()
+      .unwrap()
+      .iter()
+      .map(|embedding| embedding["id"].as_str().unwrap())
+      .collect()
+  }
+
+  async fn listing_state() -> BackendState {
+    let state = state(BackendConf::default()).await;
+
+    execute(
+      &state,
+      r#"
+INSERT INTO knowledge_base ( id, name, created_at, updated_at ) VALUES ( 1, 'kb', 0, 0 );
+      "#,
+    )
+    .await;
+
+    state.vector_store.create_collection(1).await.unwrap();
+    state
+      .vector_store
+      .upsert(
+        1,
+        vec![
+          chunk("a", 1, 1, "Install on Linux"),
+          chunk("b", 1, 1, "Install on macOS"),
+          chunk("c", 1, 2, "Uninstall"),
+          chunk("d", 1, 2, "Configure"),
+          chunk("e", 1, 2, "Upgrade"),
+        ],
+      )
+      .await
+      .unwrap();
+
+    state
+  }
+
+  #[tokio::test]
+  async fn embeddings_are_listed_page_by_page() {
+    let state = listing_state().await;
+
+    let first = listed(&state, serde_json::json!({ "limit": 2 })).await;
+    let last = listed(&state, serde_json::json!({ "offset": 4, "limit": 2 })).await;
+
+    assert_eq!(ids(&first), vec!["a", "b"]);
+    assert_eq!(first["next_offset"], 2);
+    assert_eq!(first["embeddings"][1]["index"], 1);
+    assert_eq!(
+      first["embeddings"][0]["embedding"],
+      serde_json::json!([1.0, 0.0])
+    );
+
+    assert_eq!(ids(&last), vec!["e"]);
+    assert_eq!(last["next_offset"], serde_json::Value::Null);
+    assert_eq!(last["embeddings"][0]["index"], 4);
+  }
+
+  #[tokio::test]
+  async fn embeddings_are_filtered_by_file_and_text() {
+    let state = listing_state().await;
+
+    let by_file = listed(
+      &state,
+      serde_json::json!({ "file_id": 2, "vectors": false }),
+    )
+    .await;
+    let by_text = listed(&state, serde_json::json!({ "text": "INSTALL" })).await;
+
+    assert_eq!(ids(&by_file), vec!["c", "d", "e"]);
+    assert!(by_file["embeddings"][0].get("embedding").is_none());
+    assert_eq!(ids(&by_text), vec!["a", "b", "c"]);
+  }
+}
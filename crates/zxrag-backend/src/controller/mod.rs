@@ -1,2 +1,4 @@
+pub mod job_controller;
 pub mod knowledge_base_controller;
 pub mod openai_controller;
+pub mod session_controller;
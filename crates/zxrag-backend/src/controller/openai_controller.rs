@@ -3,28 +3,48 @@ use axum::response::{sse::Event, IntoResponse, Json, Response, Sse};
 use futures::{Stream, TryStream};
 use opendal::services::Fs;
 use opendal::Operator;
+use serde_json::json;
+use sha2::{Digest, Sha256};
 use std::borrow::Cow;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{SystemTime, UNIX_EPOCH};
 use time::OffsetDateTime;
 use tinyvec::tiny_vec;
+use tokio_stream::wrappers::UnboundedReceiverStream;
 use tokio_stream::StreamExt;
 use uuid::Uuid;
-use zxrag_core::types::handle::{get_embedding_model, get_text_gen};
-use zxrag_core::types::llm::{TextGenerationSetting, TextGenerationStream};
+use zxrag_core::types::handle::{get_embedding_model, get_llm_tokenizer, get_reranker_model};
+use zxrag_core::types::inference::{get_inference_worker, ChunkReceiver};
+use zxrag_core::types::llm::{prompt_budget, TextGenerationSetting};
 use zxrag_core::types::openai::*;
 use zxrag_core::types::sqlx::File as SqlxFile;
 
-use crate::error::BackendError;
+use crate::controller::session_controller::SessionTurn;
+use crate::error::{BackendError, HTTP_STATUS_ERROR_GENERATION, HTTP_STATUS_ERROR_RERANKER_MODEL};
 
 use crate::BackendState;
 
 pub async fn create_chat_completion(
   State(state): State<BackendState>,
-  Json(req): Json<ChatCompletionRequest<'_>>,
+  Json(mut req): Json<ChatCompletionRequest<'_>>,
 ) -> Result<impl IntoResponse, BackendError> {
   let fp = format!("zxrag-{}", env!("CARGO_PKG_VERSION"));
 
-  let untokenized_context = req.messages.to_prompt(state.config.llm_conf.model_id)?;
+  let sample_len = req
+    .max_tokens
+    .map_or(128, |value| value.try_into().unwrap_or(128));
+
+  let session_turn = match req.session_id {
+    Some(session_id) => {
+      Some(SessionTurn::begin(&state, session_id, None, &mut req.messages, sample_len).await?)
+    }
+    None => None,
+  };
+
+  let untokenized_context = req.messages.to_fitted_prompt(
+    state.config.llm_conf.model_id,
+    get_llm_tokenizer()?,
+    prompt_budget(sample_len),
+  )?;
 
   let text_gen_setting = TextGenerationSetting {
     temperature: req.temperature.unwrap_or(0.8),
@@ -32,61 +52,50 @@ pub async fn create_chat_completion(
     seed: req.seed.unwrap_or(299792458),
     repeat_penalty: req.frequency_penalty.unwrap_or(1.1),
     repeat_last_n: 64,
-    sample_len: req
-      .max_tokens
-      .map_or(128, |value| value.try_into().unwrap_or(128)),
+    sample_len,
     prompt: untokenized_context,
+    stop: req.stop_sequences(),
   };
 
-  let mut text_gen = get_text_gen(text_gen_setting)?;
+  let inference_worker = get_inference_worker()?;
 
   let stream_response = req.stream.unwrap_or(false);
 
-  let response = if stream_response {
-    let stream = TextGenerationStream::new(text_gen)?.throttle(Duration::from_millis(10));
+  let include_usage = req.include_usage();
 
-    let completions_stream = stream.map(move |chunk| {
-      Event::default().json_data(ChatCompletionChunk {
-        id: Uuid::new_v4().to_string().into(),
-        choices: tiny_vec![ChatCompletionChunkChoice {
-          index: 0,
-          finish_reason: None,
-          delta: ChatCompletionChunkDelta {
-            content: Some(Cow::Owned(chunk)),
-            role: None,
-          },
-        }],
-        created: OffsetDateTime::now_utc().unix_timestamp(),
-        model: Cow::Borrowed("main"),
-        system_fingerprint: Cow::Borrowed(&fp),
-        object: Cow::Borrowed("text_completion"),
-      })
-    });
+  let response = if stream_response {
+    let completions_stream = completion_events(
+      inference_worker.submit(text_gen_setting)?,
+      session_turn,
+      include_usage,
+      fp,
+    );
 
     ChatCompletionResponse::Stream(Sse::new(completions_stream))
   } else {
-    let content_str = text_gen.generate()?;
+    let output = inference_worker.generate(text_gen_setting).await?;
+
+    if let Some(session_turn) = session_turn {
+      session_turn.finish(output.text.clone());
+    }
 
     let response = ChatCompletion {
       id: Uuid::new_v4().to_string().into(),
       choices: vec![ChatCompletionChoice {
         message: ChatMessage::Assistant {
-          content: Some(Cow::Owned(content_str)),
+          content: Some(Cow::Owned(output.text)),
           name: None,
           tool_calls: None,
         },
-        finish_reason: None,
+        finish_reason: Some(Cow::Owned(output.finish_reason.to_string())),
         index: 0,
       }],
       created: OffsetDateTime::now_utc().unix_timestamp(),
       model: Cow::Borrowed("main"),
       object: Cow::Borrowed("text_completion"),
       system_fingerprint: Cow::Owned(fp),
-      usage: ChatCompletionUsage {
-        completion_tokens: 0,
-        prompt_tokens: 0,
-        total_tokens: 0,
-      },
+      usage: output.usage.into(),
+      sources: None,
     };
 
     ChatCompletionResponse::Full(Json(response))
@@ -106,9 +115,13 @@ pub async fn create_embeddings(
 
   let bert_model = get_embedding_model(state.config.embedding_conf.model_id)?;
 
-  let prompts: Vec<&str> = input.iter().map(|s| s.as_str()).collect();
+  let (mut embeddings, prompt_tokens) = tokio::task::spawn_blocking(move || {
+    let prompts: Vec<&str> = input.iter().map(|s| s.as_str()).collect();
 
-  let mut embeddings: Vec<Vec<f32>> = bert_model.embedding_batch(&prompts)?;
+    bert_model.embedding_batch_with_usage(&prompts)
+  })
+  .await
+  .map_err(|e| anyhow::anyhow!(e))??;
 
   Ok(Json(EmbeddingResponse {
     object: Cow::Owned("list".to_string()),
@@ -123,14 +136,66 @@ pub async fn create_embeddings(
       .collect(),
     model: Cow::Owned(req.model.to_string()),
     usage: EmbeddingsUsage {
-      prompt_tokens: 0,
-      total_tokens: 0,
+      prompt_tokens,
+      total_tokens: prompt_tokens,
     },
   }))
 }
 
+pub async fn rerank(
+  State(state): State<BackendState>,
+  Json(req): Json<RerankRequest<'_>>,
+) -> Result<impl IntoResponse, BackendError> {
+  if !state.config.reranker_conf.enabled {
+    return Err(BackendError::CommonException {
+      status: HTTP_STATUS_ERROR_RERANKER_MODEL,
+      msg: "reranker is not enabled".to_string(),
+    });
+  }
+
+  let reranker = get_reranker_model(state.config.reranker_conf.model_id)?;
+
+  let query = req.query.to_string();
+  let documents: Vec<String> = req.documents.iter().map(|d| d.text().to_string()).collect();
+
+  let (scores, total_tokens, documents) = tokio::task::spawn_blocking(move || {
+    let texts: Vec<&str> = documents.iter().map(|d| d.as_str()).collect();
+
+    let scores = reranker.rerank(&query, &texts)?;
+    let total_tokens = reranker.count_tokens(&query, &texts)?;
+
+    anyhow::Ok((scores, total_tokens, documents))
+  })
+  .await
+  .map_err(|e| anyhow::anyhow!(e))??;
+
+  let mut ranked: Vec<(usize, f32)> = scores.into_iter().enumerate().collect();
+
+  ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+  ranked.truncate(req.top_n.unwrap_or(ranked.len()));
+
+  let return_documents = req.return_documents.unwrap_or(true);
+
+  Ok(Json(RerankResponse {
+    id: Uuid::new_v4().to_string().into(),
+    model: Cow::Owned(state.config.reranker_conf.model_id.to_string()),
+    results: ranked
+      .into_iter()
+      .map(|(index, relevance_score)| RerankResult {
+        index,
+        relevance_score,
+        document: return_documents.then(|| RerankDocument {
+          text: Cow::Owned(documents[index].to_string()),
+        }),
+      })
+      .collect(),
+    usage: RerankUsage { total_tokens },
+  }))
+}
+
 pub async fn models(State(state): State<BackendState>) -> Result<impl IntoResponse, BackendError> {
-  let models = vec![
+  let mut models = vec![
     Model {
       id: Cow::Owned(state.config.llm_conf.model_id.to_string()),
       created: SystemTime::now()
@@ -151,12 +216,28 @@ pub async fn models(State(state): State<BackendState>) -> Result<impl IntoRespon
     },
   ];
 
+  if state.config.reranker_conf.enabled {
+    models.push(Model {
+      id: Cow::Owned(state.config.reranker_conf.model_id.to_string()),
+      created: SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs(),
+      object: Cow::Owned("model".to_string()),
+      owned_by: Cow::Owned("reranker".to_string()),
+    });
+  }
+
   Ok(Json(ModelsResponse {
     object: Cow::Owned("list".to_string()),
     data: models,
   }))
 }
 
+pub async fn inference_status() -> Result<impl IntoResponse, BackendError> {
+  Ok(Json(get_inference_worker()?.status()))
+}
+
 pub async fn upload_file(
   State(state): State<BackendState>,
   mut multipart: Multipart,
@@ -185,15 +266,16 @@ pub async fn upload_file(
         .finish();
 
       let mut w = op
-        .writer_with(&file_name)
-        .append(true)
+        .writer(&file_name)
         .await
         .map_err(|e| anyhow::anyhow!(e))?;
 
       let mut bytes: i64 = 0;
+      let mut hasher = Sha256::new();
 
       while let Some(chunk) = field.chunk().await.map_err(|e| anyhow::anyhow!(e))? {
         bytes += chunk.len() as i64;
+        hasher.update(&chunk);
 
         w.write(chunk).await.map_err(|e| anyhow::anyhow!(e))?;
       }
@@ -202,14 +284,15 @@ pub async fn upload_file(
 
       sqlx::query(
         r#"
-REPLACE INTO file ( kb_id, filename, bytes, purpose, created_at, updated_at )
-VALUES ( ?, ?, ?, ?, ?, ? );
+REPLACE INTO file ( kb_id, filename, bytes, purpose, content_hash, created_at, updated_at )
+VALUES ( ?, ?, ?, ?, ?, ?, ? );
         "#,
       )
       .bind(0)
       .bind(file_name)
       .bind(bytes)
       .bind("fine-tune")
+      .bind(hex::encode(hasher.finalize()))
       .bind(OffsetDateTime::now_utc().unix_timestamp())
       .bind(OffsetDateTime::now_utc().unix_timestamp())
       .execute(&state.pool.clone())
@@ -296,6 +379,84 @@ DELETE FROM file where id = ?;
   }))
 }
 
+/// Turns the chunks of a generation into the SSE events of a streamed chat completion, and
+/// appends the session turn once the answer is complete. A failed generation ends the stream with
+/// an `error` event, and its turn isn't stored.
+pub fn completion_events(
+  chunks: ChunkReceiver,
+  mut session_turn: Option<SessionTurn>,
+  include_usage: bool,
+  fp: String,
+) -> impl Stream<Item = Result<Event, axum::Error>> {
+  let mut answer = String::new();
+  let mut failed = false;
+
+  UnboundedReceiverStream::new(chunks).filter_map(move |chunk| {
+    if failed {
+      return None;
+    }
+
+    let chunk = match chunk {
+      Ok(chunk) => chunk,
+      Err(e) => {
+        tracing::error!("generation failed: {}", e);
+        failed = true;
+        session_turn = None;
+
+        return Some(Event::default().event("error").json_data(json!({
+          "status": HTTP_STATUS_ERROR_GENERATION,
+          "msg": format!("generation failed: {}", e),
+        })));
+      }
+    };
+
+    answer.push_str(&chunk.text);
+
+    if chunk.finish_reason.is_some() {
+      if let Some(session_turn) = session_turn.take() {
+        session_turn.finish(std::mem::take(&mut answer));
+      }
+    }
+
+    if let Some(usage) = chunk.usage {
+      if !include_usage {
+        return None;
+      }
+
+      return Some(Event::default().json_data(ChatCompletionChunk {
+        id: Uuid::new_v4().to_string().into(),
+        choices: tiny_vec![],
+        created: OffsetDateTime::now_utc().unix_timestamp(),
+        model: Cow::Borrowed("main"),
+        system_fingerprint: Cow::Borrowed(&fp),
+        object: Cow::Borrowed("text_completion"),
+        usage: Some(usage.into()),
+        sources: None,
+      }));
+    }
+
+    Some(Event::default().json_data(ChatCompletionChunk {
+      id: Uuid::new_v4().to_string().into(),
+      choices: tiny_vec![ChatCompletionChunkChoice {
+        index: 0,
+        finish_reason: chunk
+          .finish_reason
+          .map(|finish_reason| Cow::Owned(finish_reason.to_string())),
+        delta: ChatCompletionChunkDelta {
+          content: Some(Cow::Owned(chunk.text)),
+          role: None,
+        },
+      }],
+      created: OffsetDateTime::now_utc().unix_timestamp(),
+      model: Cow::Borrowed("main"),
+      system_fingerprint: Cow::Borrowed(&fp),
+      object: Cow::Borrowed("text_completion"),
+      usage: None,
+      sources: None,
+    }))
+  })
+}
+
 pub enum ChatCompletionResponse<'a, S>
 where
   S: TryStream<Ok = Event> + Send + 'static,
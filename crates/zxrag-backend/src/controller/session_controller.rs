@@ -0,0 +1,495 @@
+use axum::extract::{Path, Query, State};
+use axum::response::{IntoResponse, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+use std::borrow::Cow;
+use time::OffsetDateTime;
+use zxrag_core::types::handle::get_llm_tokenizer;
+use zxrag_core::types::inference::get_inference_worker;
+use zxrag_core::types::llm::{TextGenerationSetting, MAX_SEQ_LEN};
+use zxrag_core::types::model::ModelId;
+use zxrag_core::types::openai::{ChatMessage, ChatMessages};
+use zxrag_core::types::sqlx::{Message, Session};
+
+use crate::error::{BackendError, HTTP_STATUS_ERROR_SESSION};
+use crate::BackendState;
+
+/// Characters of the first question used as title when the model can't come up with one.
+const FALLBACK_TITLE_CHARS: usize = 48;
+
+/// Tokens a message adds to the prompt on top of its text, for the role markers of the template.
+const MESSAGE_OVERHEAD_TOKENS: usize = 8;
+
+pub async fn create_session(
+  State(state): State<BackendState>,
+  Json(req): Json<CreateSessionRequest>,
+) -> Result<impl IntoResponse, BackendError> {
+  let now = OffsetDateTime::now_utc().unix_timestamp();
+
+  let result = sqlx::query(
+    r#"
+INSERT INTO session ( kb_id, title, created_at, updated_at )
+VALUES ( ?, ?, ?, ? );
+    "#,
+  )
+  .bind(req.kb_id)
+  .bind(req.title.unwrap_or_default())
+  .bind(now)
+  .bind(now)
+  .execute(&state.pool.clone())
+  .await
+  .map_err(|e| anyhow::anyhow!(e))?;
+
+  let session = fetch_session(&state.pool, result.last_insert_rowid()).await?;
+
+  Ok(Json(session))
+}
+
+pub async fn list_sessions(
+  State(state): State<BackendState>,
+  Query(params): Query<ListSessionsParams>,
+) -> Result<impl IntoResponse, BackendError> {
+  let sessions = match params.kb_id {
+    Some(kb_id) => {
+      sqlx::query_as::<_, Session>(
+        r#"
+SELECT * FROM session where kb_id = ? ORDER BY updated_at DESC;
+        "#,
+      )
+      .bind(kb_id)
+      .fetch_all(&state.pool.clone())
+      .await
+    }
+    None => {
+      sqlx::query_as::<_, Session>(
+        r#"
+SELECT * FROM session ORDER BY updated_at DESC;
+        "#,
+      )
+      .fetch_all(&state.pool.clone())
+      .await
+    }
+  }
+  .map_err(|e| anyhow::anyhow!(e))?;
+
+  Ok(Json(ListSessionsResponse { data: sessions }))
+}
+
+pub async fn get_session(
+  State(state): State<BackendState>,
+  Path(session_id): Path<String>,
+) -> Result<impl IntoResponse, BackendError> {
+  let session = sqlx::query_as::<_, Session>(
+    r#"
+SELECT * FROM session where id = ?;
+    "#,
+  )
+  .bind(session_id)
+  .fetch_one(&state.pool.clone())
+  .await
+  .map_err(|e| anyhow::anyhow!(e))?;
+
+  let messages = fetch_messages(&state.pool, session.id).await?;
+
+  Ok(Json(GetSessionResponse { session, messages }))
+}
+
+pub async fn update_session(
+  State(state): State<BackendState>,
+  Path(session_id): Path<String>,
+  Json(req): Json<UpdateSessionRequest>,
+) -> Result<impl IntoResponse, BackendError> {
+  let session = sqlx::query_as::<_, Session>(
+    r#"
+SELECT * FROM session where id = ?;
+    "#,
+  )
+  .bind(session_id)
+  .fetch_one(&state.pool.clone())
+  .await
+  .map_err(|e| anyhow::anyhow!(e))?;
+
+  sqlx::query(
+    r#"
+UPDATE session SET title = ?, updated_at = ? where id = ?;
+    "#,
+  )
+  .bind(&req.title)
+  .bind(OffsetDateTime::now_utc().unix_timestamp())
+  .bind(session.id)
+  .execute(&state.pool.clone())
+  .await
+  .map_err(|e| anyhow::anyhow!(e))?;
+
+  let session = fetch_session(&state.pool, session.id).await?;
+
+  Ok(Json(session))
+}
+
+pub async fn delete_session(
+  State(state): State<BackendState>,
+  Path(session_id): Path<String>,
+) -> Result<impl IntoResponse, BackendError> {
+  let session = sqlx::query_as::<_, Session>(
+    r#"
+SELECT * FROM session where id = ?;
+    "#,
+  )
+  .bind(session_id)
+  .fetch_one(&state.pool.clone())
+  .await
+  .map_err(|e| anyhow::anyhow!(e))?;
+
+  sqlx::query(
+    r#"
+DELETE FROM message where session_id = ?;
+    "#,
+  )
+  .bind(session.id)
+  .execute(&state.pool.clone())
+  .await
+  .map_err(|e| anyhow::anyhow!(e))?;
+
+  sqlx::query(
+    r#"
+DELETE FROM session where id = ?;
+    "#,
+  )
+  .bind(session.id)
+  .execute(&state.pool.clone())
+  .await
+  .map_err(|e| anyhow::anyhow!(e))?;
+
+  Ok(Json(DeleteSessionResponse {
+    id: session.id,
+    deleted: true,
+  }))
+}
+
+pub async fn list_messages(
+  State(state): State<BackendState>,
+  Path(session_id): Path<String>,
+) -> Result<impl IntoResponse, BackendError> {
+  let session = sqlx::query_as::<_, Session>(
+    r#"
+SELECT * FROM session where id = ?;
+    "#,
+  )
+  .bind(session_id)
+  .fetch_one(&state.pool.clone())
+  .await
+  .map_err(|e| anyhow::anyhow!(e))?;
+
+  let messages = fetch_messages(&state.pool, session.id).await?;
+
+  Ok(Json(ListMessagesResponse { data: messages }))
+}
+
+/// A chat turn of a stored session, kept until the answer is known and the turn can be appended.
+pub struct SessionTurn {
+  pool: Pool<Sqlite>,
+  session: Session,
+  messages: Vec<(&'static str, String)>,
+  model_id: ModelId,
+}
+
+impl SessionTurn {
+  /// Puts the history of the session into `messages`, after their system messages, and keeps the
+  /// other messages of the request as the new turn. `kb_id` must match the session's. The oldest
+  /// turns are left out so that `reserved_tokens` of the context window stay free, for the answer
+  /// and whatever the caller adds to the prompt.
+  pub async fn begin(
+    state: &BackendState,
+    session_id: i64,
+    kb_id: Option<i64>,
+    messages: &mut ChatMessages<'_>,
+    reserved_tokens: usize,
+  ) -> Result<Self, BackendError> {
+    let session = fetch_session(&state.pool, session_id).await?;
+
+    if session.kb_id != kb_id {
+      return Err(BackendError::CommonException {
+        status: HTTP_STATUS_ERROR_SESSION,
+        msg: format!(
+          "session {} does not belong to this {}",
+          session.id,
+          if kb_id.is_some() {
+            "knowledge base"
+          } else {
+            "chat"
+          }
+        ),
+      });
+    }
+
+    // Tool results answer calls of their own turn, and can't be replayed without the call ids
+    let history = fetch_messages(&state.pool, session.id)
+      .await?
+      .into_iter()
+      .filter(|message| message.role != "tool")
+      .map(|message| ChatMessage::from_text(&message.role, message.content))
+      .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let (system, turn): (Vec<_>, Vec<_>) = std::mem::take(&mut **messages)
+      .into_iter()
+      .partition(|message| matches!(message, ChatMessage::System { .. }));
+
+    let turn_messages = turn
+      .iter()
+      .filter(|message| !matches!(message, ChatMessage::Tool { .. }))
+      .map(|message| (message.role(), message.to_string()))
+      .collect();
+
+    let request_tokens = count_tokens(system.iter().chain(turn.iter()))?;
+
+    let history = fit_history(
+      history,
+      MAX_SEQ_LEN.saturating_sub(request_tokens + reserved_tokens),
+    )?;
+
+    messages.extend(system);
+    messages.extend(history);
+    messages.extend(turn);
+
+    Ok(Self {
+      pool: state.pool.clone(),
+      session,
+      messages: turn_messages,
+      model_id: state.config.llm_conf.model_id,
+    })
+  }
+
+  /// Appends the turn and its answer to the session in the background, and names the session
+  /// after its first question if it has no title yet.
+  pub fn finish(self, answer: String) {
+    tokio::spawn(async move {
+      let session_id = self.session.id;
+
+      if let Err(e) = self.store(answer).await {
+        tracing::error!("storing turn of session {} failed: {}", session_id, e);
+      }
+    });
+  }
+
+  async fn store(self, answer: String) -> anyhow::Result<()> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+
+    for (role, content) in self
+      .messages
+      .iter()
+      .map(|(role, content)| (*role, content.as_str()))
+      .chain(std::iter::once(("assistant", answer.as_str())))
+    {
+      sqlx::query(
+        r#"
+INSERT INTO message ( session_id, role, content, created_at )
+VALUES ( ?, ?, ?, ? );
+        "#,
+      )
+      .bind(self.session.id)
+      .bind(role)
+      .bind(content)
+      .bind(now)
+      .execute(&self.pool)
+      .await?;
+    }
+
+    sqlx::query(
+      r#"
+UPDATE session SET updated_at = ? where id = ?;
+      "#,
+    )
+    .bind(now)
+    .bind(self.session.id)
+    .execute(&self.pool)
+    .await?;
+
+    if !self.session.title.is_empty() {
+      return Ok(());
+    }
+
+    let Some((_, question)) = self.messages.iter().find(|(role, _)| *role == "user") else {
+      return Ok(());
+    };
+
+    let title = generate_title(self.model_id, question)
+      .await
+      .unwrap_or_else(|e| {
+        tracing::error!(
+          "generating title of session {} failed: {}",
+          self.session.id,
+          e
+        );
+        String::new()
+      });
+
+    let title = if title.is_empty() {
+      question.chars().take(FALLBACK_TITLE_CHARS).collect()
+    } else {
+      title
+    };
+
+    sqlx::query(
+      r#"
+UPDATE session SET title = ? where id = ? AND title = '';
+      "#,
+    )
+    .bind(title)
+    .bind(self.session.id)
+    .execute(&self.pool)
+    .await?;
+
+    Ok(())
+  }
+}
+
+/// Tokens the messages take in a prompt, roughly as the chat template lays them out.
+fn count_tokens<'a, 'b: 'a>(
+  messages: impl Iterator<Item = &'a ChatMessage<'b>>,
+) -> anyhow::Result<usize> {
+  let tokenizer = get_llm_tokenizer()?;
+
+  let mut tokens = 0;
+
+  for message in messages {
+    tokens += tokenizer
+      .encode(message.to_string(), false)
+      .map_err(anyhow::Error::msg)?
+      .len()
+      + MESSAGE_OVERHEAD_TOKENS;
+  }
+
+  Ok(tokens)
+}
+
+/// The latest turns of `history` that fit in `budget` tokens. Turns are kept whole, so the kept
+/// history starts with a question.
+fn fit_history(
+  mut history: Vec<ChatMessage<'static>>,
+  budget: usize,
+) -> anyhow::Result<Vec<ChatMessage<'static>>> {
+  let mut start = history.len();
+  let mut used = 0;
+
+  for (index, message) in history.iter().enumerate().rev() {
+    used += count_tokens(std::iter::once(message))?;
+
+    if used > budget {
+      break;
+    }
+
+    start = index;
+  }
+
+  while history
+    .get(start)
+    .is_some_and(|message| !matches!(message, ChatMessage::User { .. }))
+  {
+    start += 1;
+  }
+
+  if start > 0 {
+    tracing::info!("{} oldest messages of the session left out", start);
+  }
+
+  Ok(history.split_off(start))
+}
+
+async fn generate_title(model_id: ModelId, question: &str) -> anyhow::Result<String> {
+  let messages: ChatMessages = vec![ChatMessage::User {
+    content: either::Left(Cow::Owned(format!(
+      "Write a title of at most six words for a conversation starting with the message below. \
+       Reply with the title only.\n\n{}",
+      question
+    ))),
+    name: None,
+  }]
+  .into();
+
+  let output = get_inference_worker()?
+    .generate(TextGenerationSetting {
+      temperature: 0.0,
+      top_p: None,
+      seed: 299792458,
+      repeat_penalty: 1.1,
+      repeat_last_n: 64,
+      sample_len: 16,
+      prompt: messages.to_prompt(model_id)?,
+      stop: vec!["\n".to_string()],
+    })
+    .await?;
+
+  Ok(
+    output
+      .text
+      .trim()
+      .trim_matches(|c: char| c == '"' || c == '\'')
+      .trim()
+      .to_string(),
+  )
+}
+
+async fn fetch_session(pool: &Pool<Sqlite>, session_id: i64) -> anyhow::Result<Session> {
+  let session = sqlx::query_as::<_, Session>(
+    r#"
+SELECT * FROM session where id = ?;
+    "#,
+  )
+  .bind(session_id)
+  .fetch_one(pool)
+  .await?;
+
+  Ok(session)
+}
+
+async fn fetch_messages(pool: &Pool<Sqlite>, session_id: i64) -> anyhow::Result<Vec<Message>> {
+  let messages = sqlx::query_as::<_, Message>(
+    r#"
+SELECT * FROM message where session_id = ? ORDER BY id;
+    "#,
+  )
+  .bind(session_id)
+  .fetch_all(pool)
+  .await?;
+
+  Ok(messages)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateSessionRequest {
+  kb_id: Option<i64>,
+  title: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ListSessionsParams {
+  kb_id: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ListSessionsResponse {
+  data: Vec<Session>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetSessionResponse {
+  #[serde(flatten)]
+  session: Session,
+  messages: Vec<Message>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UpdateSessionRequest {
+  title: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeleteSessionResponse {
+  id: i64,
+  deleted: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ListMessagesResponse {
+  data: Vec<Message>,
+}
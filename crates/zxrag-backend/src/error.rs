@@ -5,7 +5,14 @@ use serde_json::json;
 
 pub const HTTP_STATUS_OK: i32 = 0;
 pub const HTTP_STATUS_ERROR_SCOPE: i32 = 4000001;
+pub const HTTP_STATUS_ERROR_DOCUMENT: i32 = 4000002;
+pub const HTTP_STATUS_ERROR_EMBEDDING_MODEL: i32 = 4000003;
+pub const HTTP_STATUS_ERROR_RERANKER_MODEL: i32 = 4000004;
+pub const HTTP_STATUS_ERROR_SESSION: i32 = 4000005;
+pub const HTTP_STATUS_ERROR_SEARCH: i32 = 4000006;
+pub const HTTP_STATUS_ERROR_VECTOR_INDEX: i32 = 4000007;
 pub const HTTP_STATUS_ERROR_UNKNOWN: i32 = 5000001;
+pub const HTTP_STATUS_ERROR_GENERATION: i32 = 5000002;
 
 #[derive(Debug)]
 pub enum BackendError {
@@ -0,0 +1,420 @@
+use opendal::services::Fs;
+use opendal::{EntryMode, Operator};
+use sqlx::{Pool, Sqlite};
+use std::collections::HashSet;
+use zxrag_core::types::conf::BackendConf;
+use zxrag_core::types::sqlx::File as SqlxFile;
+use zxrag_core::vector_store::{new_vector_store, ChunkFilter, VectorStore};
+
+use crate::connect_database;
+
+/// Deletes a file of a knowledge base from every store: its vectors, its blob, its jobs and its
+/// row. The row goes last, so a failure leaves something to retry from.
+pub async fn delete_file_cascade(
+  pool: &Pool<Sqlite>,
+  config: &BackendConf,
+  vector_store: &dyn VectorStore,
+  sqlx_file: &SqlxFile,
+) -> anyhow::Result<()> {
+  if vector_store.has_collection(sqlx_file.kb_id).await? {
+    vector_store
+      .delete(
+        sqlx_file.kb_id,
+        &ChunkFilter {
+          file_ids: vec![sqlx_file.id],
+          ..Default::default()
+        },
+      )
+      .await?;
+  }
+
+  blob_operator(config)?
+    .delete(&format!("kb_{}/{}", sqlx_file.kb_id, sqlx_file.filename))
+    .await?;
+
+  sqlx::query(
+    r#"
+DELETE FROM job where file_id = ?;
+    "#,
+  )
+  .bind(sqlx_file.id)
+  .execute(pool)
+  .await?;
+
+  sqlx::query(
+    r#"
+DELETE FROM file where id = ?;
+    "#,
+  )
+  .bind(sqlx_file.id)
+  .execute(pool)
+  .await?;
+
+  Ok(())
+}
+
+/// Deletes a knowledge base from every store: its table, its blobs, and its rows along with those
+/// of its files, jobs, settings, index parameters and sessions.
+pub async fn delete_knowledge_base_cascade(
+  pool: &Pool<Sqlite>,
+  config: &BackendConf,
+  vector_store: &dyn VectorStore,
+  kb_id: i64,
+) -> anyhow::Result<()> {
+  vector_store.drop_collection(kb_id).await?;
+
+  blob_operator(config)?
+    .remove_all(&format!("kb_{}/", kb_id))
+    .await?;
+
+  // The rows go together, so a failure leaves none of them pointing at a deleted knowledge base
+  let mut tx = pool.begin().await?;
+
+  for sql in [
+    r#"
+DELETE FROM job where kb_id = ?;
+    "#,
+    r#"
+DELETE FROM file where kb_id = ?;
+    "#,
+    r#"
+DELETE FROM knowledge_base_setting where kb_id = ?;
+    "#,
+    r#"
+DELETE FROM vector_index where kb_id = ?;
+    "#,
+    r#"
+DELETE FROM message where session_id IN ( SELECT id FROM session where kb_id = ? );
+    "#,
+    r#"
+DELETE FROM session where kb_id = ?;
+    "#,
+    r#"
+DELETE FROM knowledge_base where id = ?;
+    "#,
+  ] {
+    sqlx::query(sql).bind(kb_id).execute(&mut *tx).await?;
+  }
+
+  tx.commit().await?;
+
+  Ok(())
+}
+
+/// Finds what deletions left behind in SQLite, LanceDB and the blob storage, and removes it unless
+/// `dry_run` is set.
+#[tokio::main]
+pub async fn run_gc(config: BackendConf, dry_run: bool) -> anyhow::Result<()> {
+  let pool = connect_database(&config).await?;
+
+  let vector_store = new_vector_store(&config).await?;
+
+  let removed = collect_garbage(&pool, &config, vector_store.as_ref(), dry_run).await?;
+
+  tracing::info!(
+    "{} {} orphans",
+    if dry_run { "found" } else { "removed" },
+    removed
+  );
+
+  Ok(())
+}
+
+/// Removes the orphans of every store, or only counts them when `dry_run` is set.
+async fn collect_garbage(
+  pool: &Pool<Sqlite>,
+  config: &BackendConf,
+  vector_store: &dyn VectorStore,
+  dry_run: bool,
+) -> anyhow::Result<usize> {
+  let action = if dry_run { "would remove" } else { "removing" };
+
+  let mut removed = 0;
+
+  let kb_ids: HashSet<i64> = sqlx::query_scalar(
+    r#"
+SELECT id FROM knowledge_base;
+    "#,
+  )
+  .fetch_all(pool)
+  .await?
+  .into_iter()
+  .collect();
+
+  // Files without a knowledge base, global files excepted
+  let files = sqlx::query_as::<_, SqlxFile>(
+    r#"
+SELECT * FROM file where kb_id != 0;
+    "#,
+  )
+  .fetch_all(pool)
+  .await?;
+
+  let (files, orphan_files): (Vec<SqlxFile>, Vec<SqlxFile>) = files
+    .into_iter()
+    .partition(|sqlx_file| kb_ids.contains(&sqlx_file.kb_id));
+
+  for sqlx_file in &orphan_files {
+    tracing::info!(
+      "{} file {} ({}) of missing knowledge base {}",
+      action,
+      sqlx_file.id,
+      sqlx_file.filename,
+      sqlx_file.kb_id
+    );
+
+    if !dry_run {
+      delete_file_cascade(pool, config, vector_store, sqlx_file).await?;
+    }
+
+    removed += 1;
+  }
+
+  let file_ids: HashSet<i64> = files.iter().map(|sqlx_file| sqlx_file.id).collect();
+
+  // Collections of missing knowledge bases, and vectors of missing files
+  for kb_id in vector_store.collections().await? {
+    if !kb_ids.contains(&kb_id) {
+      tracing::info!("{} collection kb_{}", action, kb_id);
+
+      if !dry_run {
+        vector_store.drop_collection(kb_id).await?;
+      }
+
+      removed += 1;
+
+      continue;
+    }
+
+    let mut orphan_file_ids: Vec<i64> = vector_store
+      .list(kb_id, &ChunkFilter::default(), 0, None, false)
+      .await?
+      .into_iter()
+      .map(|chunk| chunk.file_id)
+      .filter(|file_id| !file_ids.contains(file_id))
+      .collect();
+
+    orphan_file_ids.sort_unstable();
+    orphan_file_ids.dedup();
+
+    if !orphan_file_ids.is_empty() {
+      let ids: Vec<String> = orphan_file_ids.iter().map(|id| id.to_string()).collect();
+
+      tracing::info!(
+        "{} vectors of missing files {} from kb_{}",
+        action,
+        ids.join(", "),
+        kb_id
+      );
+
+      if !dry_run {
+        vector_store
+          .delete(
+            kb_id,
+            &ChunkFilter {
+              file_ids: orphan_file_ids.clone(),
+              ..Default::default()
+            },
+          )
+          .await?;
+      }
+
+      removed += orphan_file_ids.len();
+    }
+  }
+
+  // Blob directories of missing knowledge bases, and blobs without a file row
+  let op = blob_operator(config)?;
+
+  for entry in op.list("/").await? {
+    if op.stat(entry.path()).await?.mode() != EntryMode::DIR {
+      continue;
+    }
+
+    let Some(kb_id) = parse_kb_name(entry.name()) else {
+      continue;
+    };
+
+    if !kb_ids.contains(&kb_id) {
+      tracing::info!("{} blobs under {}", action, entry.path());
+
+      if !dry_run {
+        op.remove_all(entry.path()).await?;
+      }
+
+      removed += 1;
+
+      continue;
+    }
+
+    let filenames: HashSet<&str> = files
+      .iter()
+      .filter(|sqlx_file| sqlx_file.kb_id == kb_id)
+      .map(|sqlx_file| sqlx_file.filename.as_str())
+      .collect();
+
+    for blob in op.list(entry.path()).await? {
+      if blob.path() == entry.path() || filenames.contains(blob.name()) {
+        continue;
+      }
+
+      tracing::info!("{} blob {}", action, blob.path());
+
+      if !dry_run {
+        op.remove_all(blob.path()).await?;
+      }
+
+      removed += 1;
+    }
+  }
+
+  // Rows pointing at knowledge bases, files or sessions that are gone
+  for (what, count_sql, delete_sql) in [
+    (
+      "jobs",
+      r#"
+SELECT COUNT(*) FROM job where kb_id NOT IN ( SELECT id FROM knowledge_base ) OR file_id NOT IN ( SELECT id FROM file );
+      "#,
+      r#"
+DELETE FROM job where kb_id NOT IN ( SELECT id FROM knowledge_base ) OR file_id NOT IN ( SELECT id FROM file );
+      "#,
+    ),
+    (
+      "knowledge base settings",
+      r#"
+SELECT COUNT(*) FROM knowledge_base_setting where kb_id NOT IN ( SELECT id FROM knowledge_base );
+      "#,
+      r#"
+DELETE FROM knowledge_base_setting where kb_id NOT IN ( SELECT id FROM knowledge_base );
+      "#,
+    ),
+    (
+      "vector index parameters",
+      r#"
+SELECT COUNT(*) FROM vector_index where kb_id NOT IN ( SELECT id FROM knowledge_base );
+      "#,
+      r#"
+DELETE FROM vector_index where kb_id NOT IN ( SELECT id FROM knowledge_base );
+      "#,
+    ),
+    (
+      "sessions",
+      r#"
+SELECT COUNT(*) FROM session where kb_id IS NOT NULL AND kb_id NOT IN ( SELECT id FROM knowledge_base );
+      "#,
+      r#"
+DELETE FROM session where kb_id IS NOT NULL AND kb_id NOT IN ( SELECT id FROM knowledge_base );
+      "#,
+    ),
+    (
+      "messages",
+      r#"
+SELECT COUNT(*) FROM message where session_id NOT IN ( SELECT id FROM session );
+      "#,
+      r#"
+DELETE FROM message where session_id NOT IN ( SELECT id FROM session );
+      "#,
+    ),
+  ] {
+    let count: i64 = sqlx::query_scalar(count_sql).fetch_one(pool).await?;
+
+    if count == 0 {
+      continue;
+    }
+
+    tracing::info!("{} {} orphaned {}", action, count, what);
+
+    if !dry_run {
+      sqlx::query(delete_sql).execute(pool).await?;
+    }
+
+    removed += count as usize;
+  }
+
+  Ok(removed)
+}
+
+/// Blob storage rooted at `opendal_path`, holding a `kb_{id}` directory per knowledge base.
+fn blob_operator(config: &BackendConf) -> anyhow::Result<Operator> {
+  let mut builder = Fs::default();
+
+  builder.root(&config.opendal_path);
+
+  Ok(Operator::new(builder)?.finish())
+}
+
+/// The knowledge base id in a `kb_{id}` directory name.
+fn parse_kb_name(name: &str) -> Option<i64> {
+  name.trim_end_matches('/').strip_prefix("kb_")?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::{chunk, execute, state};
+
+  #[tokio::test]
+  async fn orphans_are_counted_then_removed() {
+    let state = state(BackendConf::default()).await;
+    let store = state.vector_store.as_ref();
+    let blobs = std::path::Path::new(&state.config.opendal_path);
+
+    // Knowledge base 1 holds file 1, knowledge base 9 is gone
+    execute(
+      &state,
+      r#"
+INSERT INTO knowledge_base ( id, name, created_at, updated_at ) VALUES ( 1, 'kb', 0, 0 );
+INSERT INTO file ( id, kb_id, filename, bytes, purpose, created_at, updated_at )
+VALUES ( 1, 1, 'a.md', 0, 'assistants', 0, 0 ), ( 2, 9, 'b.md', 0, 'assistants', 0, 0 );
+INSERT INTO job ( kb_id, file_id, status, error, total, processed, created_at, updated_at )
+VALUES ( 9, 2, 'succeeded', '', 0, 0, 0, 0 );
+      "#,
+    )
+    .await;
+
+    for kb_id in [1, 9] {
+      store.create_collection(kb_id).await.unwrap();
+    }
+
+    store
+      .upsert(
+        1,
+        vec![chunk("a", 1, 1, "kept"), chunk("c", 1, 3, "orphan")],
+      )
+      .await
+      .unwrap();
+
+    for (dir, name) in [("kb_1", "a.md"), ("kb_1", "stale.md"), ("kb_5", "c.md")] {
+      std::fs::create_dir_all(blobs.join(dir)).unwrap();
+      std::fs::write(blobs.join(dir).join(name), "blob").unwrap();
+    }
+
+    // File 2, collection kb_9, vectors of file 3, stale.md, kb_5 and the job of file 2
+    assert_eq!(
+      collect_garbage(&state.pool, &state.config, store, true)
+        .await
+        .unwrap(),
+      6
+    );
+
+    // The job goes along with file 2
+    assert_eq!(
+      collect_garbage(&state.pool, &state.config, store, false)
+        .await
+        .unwrap(),
+      5
+    );
+    assert_eq!(
+      collect_garbage(&state.pool, &state.config, store, true)
+        .await
+        .unwrap(),
+      0
+    );
+
+    assert_eq!(store.collections().await.unwrap(), vec![1]);
+    assert_eq!(store.count(1).await.unwrap(), 1);
+    assert!(blobs.join("kb_1/a.md").exists());
+    assert!(!blobs.join("kb_1/stale.md").exists());
+    assert!(!blobs.join("kb_5").exists());
+  }
+}
@@ -0,0 +1,282 @@
+use sqlx::{Pool, Sqlite};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Instant;
+use time::OffsetDateTime;
+use zxrag_core::types::conf::BackendConf;
+use zxrag_core::types::sqlx::VectorIndex;
+use zxrag_core::vector_store::{
+  new_vector_store, VectorIndexParams, VectorIndexSetting, VectorStore,
+};
+
+use crate::{connect_database, BackendState};
+
+/// What the `index` command does to the vector index of a knowledge base.
+pub enum IndexAction {
+  Build(VectorIndexSetting),
+  Stats,
+  Drop,
+}
+
+/// Knowledge bases whose index ingestion is rebuilding, so jobs finishing meanwhile don't start
+/// another build of the same index.
+#[derive(Clone, Default)]
+pub struct IndexRebuilds {
+  running: Arc<Mutex<HashSet<i64>>>,
+}
+
+impl IndexRebuilds {
+  /// Marks a rebuild of the index of `kb_id` running until the guard returned is dropped, `None`
+  /// when one already is.
+  fn start(&self, kb_id: i64) -> Option<IndexRebuild> {
+    let started = self
+      .running
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .insert(kb_id);
+
+    started.then(|| IndexRebuild {
+      rebuilds: self.clone(),
+      kb_id,
+    })
+  }
+}
+
+/// A rebuild in progress, released when dropped so a failed or panicking rebuild doesn't block the
+/// next ones.
+struct IndexRebuild {
+  rebuilds: IndexRebuilds,
+  kb_id: i64,
+}
+
+impl Drop for IndexRebuild {
+  fn drop(&mut self) {
+    self
+      .rebuilds
+      .running
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .remove(&self.kb_id);
+  }
+}
+
+/// Builds the index of a knowledge base on a task of its own, so a request giving up on the wait
+/// doesn't cancel the build, then keeps its parameters for the rebuilds.
+pub async fn build_index(
+  pool: &Pool<Sqlite>,
+  vector_store: Arc<dyn VectorStore>,
+  kb_id: i64,
+  params: VectorIndexParams,
+) -> anyhow::Result<()> {
+  let started = Instant::now();
+
+  tokio::spawn(async move { vector_store.create_index(kb_id, &params).await }).await??;
+
+  tracing::info!(
+    "index of kb_{} built in {:?}, {:?}",
+    kb_id,
+    started.elapsed(),
+    params
+  );
+
+  let now = OffsetDateTime::now_utc().unix_timestamp();
+
+  sqlx::query(
+    r#"
+INSERT INTO vector_index ( kb_id, params, created_at, updated_at )
+VALUES ( ?, ?, ?, ? )
+ON CONFLICT ( kb_id ) DO UPDATE SET params = excluded.params, updated_at = excluded.updated_at;
+    "#,
+  )
+  .bind(kb_id)
+  .bind(serde_json::to_string(&params)?)
+  .bind(now)
+  .bind(now)
+  .execute(pool)
+  .await?;
+
+  Ok(())
+}
+
+/// Drops the index of a knowledge base along with its parameters, so ingestion builds the next
+/// one from `vector_index_conf`.
+pub async fn drop_index(
+  pool: &Pool<Sqlite>,
+  vector_store: &dyn VectorStore,
+  kb_id: i64,
+) -> anyhow::Result<()> {
+  vector_store.drop_index(kb_id).await?;
+
+  sqlx::query(
+    r#"
+DELETE FROM vector_index where kb_id = ?;
+    "#,
+  )
+  .bind(kb_id)
+  .execute(pool)
+  .await?;
+
+  Ok(())
+}
+
+/// Parameters of the last index built for a knowledge base, those of `vector_index_conf` when
+/// none was.
+async fn index_params(
+  pool: &Pool<Sqlite>,
+  config: &BackendConf,
+  kb_id: i64,
+) -> anyhow::Result<VectorIndexParams> {
+  let vector_index = sqlx::query_as::<_, VectorIndex>(
+    r#"
+SELECT * FROM vector_index where kb_id = ?;
+    "#,
+  )
+  .bind(kb_id)
+  .fetch_optional(pool)
+  .await?;
+
+  match vector_index {
+    Some(vector_index) => Ok(serde_json::from_str(&vector_index.params)?),
+    None => Ok(VectorIndexSetting::default().params(config)),
+  }
+}
+
+/// Rebuilds the index of a knowledge base in the background once `vector_index_conf.rebuild_rows`
+/// rows are left out of it, or builds one once the collection holds that many rows. A rebuild
+/// already running for the knowledge base is left to finish instead.
+pub fn spawn_refresh_index(state: &BackendState, kb_id: i64) {
+  if state.config.vector_index_conf.rebuild_rows == 0 {
+    return;
+  }
+
+  let Some(rebuild) = state.index_rebuilds.start(kb_id) else {
+    return;
+  };
+
+  let state = state.clone();
+
+  tokio::spawn(async move {
+    let _rebuild = rebuild;
+
+    if let Err(e) = refresh_index(&state, kb_id).await {
+      tracing::warn!("index of kb_{} not rebuilt: {}", kb_id, e);
+    }
+  });
+}
+
+async fn refresh_index(state: &BackendState, kb_id: i64) -> anyhow::Result<()> {
+  let unindexed_rows = match state.vector_store.index_stats(kb_id).await? {
+    Some(stats) => stats.unindexed_rows,
+    None => state.vector_store.count(kb_id).await?,
+  };
+
+  if unindexed_rows < state.config.vector_index_conf.rebuild_rows {
+    return Ok(());
+  }
+
+  tracing::info!(
+    "kb_{} holds {} rows outside of its index, rebuilding it",
+    kb_id,
+    unindexed_rows
+  );
+
+  let params = index_params(&state.pool, &state.config, kb_id).await?;
+
+  build_index(&state.pool, state.vector_store.clone(), kb_id, params).await
+}
+
+/// Builds, drops or shows the vector index of a knowledge base, then reports what it covers.
+#[tokio::main]
+pub async fn run_index(config: BackendConf, kb_id: i64, action: IndexAction) -> anyhow::Result<()> {
+  let pool = connect_database(&config).await?;
+
+  let vector_store = new_vector_store(&config).await?;
+
+  if !vector_store.has_collection(kb_id).await? {
+    anyhow::bail!("kb_{} does not exist", kb_id);
+  }
+
+  match action {
+    IndexAction::Build(setting) => {
+      build_index(&pool, vector_store.clone(), kb_id, setting.params(&config)).await?;
+    }
+    IndexAction::Drop => {
+      drop_index(&pool, vector_store.as_ref(), kb_id).await?;
+
+      tracing::info!("index of kb_{} dropped", kb_id);
+    }
+    IndexAction::Stats => {}
+  }
+
+  let rows = vector_store.count(kb_id).await?;
+
+  match vector_store.index_stats(kb_id).await? {
+    Some(stats) => tracing::info!(
+      "kb_{} holds {} rows, index {} ({}) covers {} of them, {} are scanned",
+      kb_id,
+      rows,
+      stats.name,
+      stats.index_type,
+      stats.indexed_rows,
+      stats.unindexed_rows
+    ),
+    None => tracing::info!("kb_{} holds {} rows, no index", kb_id, rows),
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use zxrag_core::types::conf::VectorIndexConf;
+
+  use super::*;
+  use crate::testing::{chunk, state};
+
+  #[test]
+  fn one_rebuild_runs_per_knowledge_base() {
+    let rebuilds = IndexRebuilds::default();
+
+    let first = rebuilds.start(1);
+
+    assert!(first.is_some());
+    assert!(rebuilds.start(1).is_none());
+    assert!(rebuilds.start(2).is_some());
+
+    drop(first);
+
+    assert!(rebuilds.start(1).is_some());
+  }
+
+  #[tokio::test]
+  async fn indexes_are_rebuilt_from_rebuild_rows() {
+    let state = state(BackendConf {
+      vector_index_conf: VectorIndexConf {
+        rebuild_rows: 3,
+        ..Default::default()
+      },
+      ..Default::default()
+    })
+    .await;
+
+    state.vector_store.create_collection(1).await.unwrap();
+    state
+      .vector_store
+      .upsert(1, vec![chunk("a", 1, 1, "a"), chunk("b", 1, 1, "b")])
+      .await
+      .unwrap();
+
+    refresh_index(&state, 1).await.unwrap();
+
+    state
+      .vector_store
+      .upsert(1, vec![chunk("c", 1, 1, "c")])
+      .await
+      .unwrap();
+
+    // The in-memory store can't build an index, so only an attempt fails
+    let e = refresh_index(&state, 1).await.unwrap_err();
+
+    assert!(e.to_string().contains("can't be indexed"));
+  }
+}
@@ -0,0 +1,616 @@
+use opendal::services::Fs;
+use opendal::Operator;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use time::OffsetDateTime;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{broadcast, Mutex};
+use uuid::Uuid;
+use zxrag_core::document_loader::{load_document, DocumentFormat};
+use zxrag_core::models::bert::Model as BertModel;
+use zxrag_core::text_splitter::{new_text_splitter, TextSplitterKind};
+use zxrag_core::types::conf::BackendConf;
+use zxrag_core::types::handle::get_embedding_model;
+use zxrag_core::types::job::JobStatus;
+use zxrag_core::types::sqlx::File as SqlxFile;
+use zxrag_core::types::sqlx::Job;
+use zxrag_core::vector_store::{Chunk, ChunkFilter};
+
+use crate::index::spawn_refresh_index;
+use crate::BackendState;
+
+const EMBEDDING_BATCH_SIZE: usize = 32;
+
+/// Job updates kept for subscribers that fall behind.
+const JOB_EVENTS_CAPACITY: usize = 256;
+
+/// Embedding jobs waiting for an ingestion worker, and the updates of the running ones.
+#[derive(Clone)]
+pub struct JobQueue {
+  sender: UnboundedSender<i64>,
+  receiver: Arc<Mutex<UnboundedReceiver<i64>>>,
+  events: broadcast::Sender<Job>,
+  /// Held by the job embedding a file, so two jobs of the same file never run at once.
+  file_locks: Arc<std::sync::Mutex<HashMap<i64, Arc<Mutex<()>>>>>,
+}
+
+impl Default for JobQueue {
+  fn default() -> Self {
+    let (sender, receiver) = unbounded_channel();
+    let (events, _) = broadcast::channel(JOB_EVENTS_CAPACITY);
+
+    Self {
+      sender,
+      receiver: Arc::new(Mutex::new(receiver)),
+      events,
+      file_locks: Arc::default(),
+    }
+  }
+}
+
+impl JobQueue {
+  /// Receives every update of every job, as stored.
+  pub fn subscribe(&self) -> broadcast::Receiver<Job> {
+    self.events.subscribe()
+  }
+
+  fn file_lock(&self, file_id: i64) -> anyhow::Result<Arc<Mutex<()>>> {
+    let mut file_locks = self
+      .file_locks
+      .lock()
+      .map_err(|_| anyhow::anyhow!("job file locks poisoned"))?;
+
+    // Locks of files no job holds or waits for anymore
+    file_locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+
+    Ok(file_locks.entry(file_id).or_default().clone())
+  }
+}
+
+/// Queues embedding `file_id` into the table of `kb_id`. A job of the file still queued is
+/// returned instead, as it reads the file once it runs.
+pub async fn enqueue_embedding(
+  state: &BackendState,
+  kb_id: i64,
+  file_id: i64,
+) -> anyhow::Result<Job> {
+  let now = OffsetDateTime::now_utc().unix_timestamp();
+
+  let result = sqlx::query(
+    r#"
+INSERT INTO job ( kb_id, file_id, status, error, total, processed, created_at, updated_at )
+SELECT ?, ?, ?, '', 0, 0, ?, ?
+where NOT EXISTS ( SELECT 1 FROM job where file_id = ? AND status = ? );
+    "#,
+  )
+  .bind(kb_id)
+  .bind(file_id)
+  .bind(JobStatus::Queued.to_string())
+  .bind(now)
+  .bind(now)
+  .bind(file_id)
+  .bind(JobStatus::Queued.to_string())
+  .execute(&state.pool)
+  .await?;
+
+  if result.rows_affected() == 0 {
+    // The queued job, or the job it became if a worker picked it up meanwhile
+    let job = sqlx::query_as::<_, Job>(
+      r#"
+SELECT * FROM job where file_id = ? ORDER BY id DESC LIMIT 1;
+      "#,
+    )
+    .bind(file_id)
+    .fetch_one(&state.pool)
+    .await?;
+
+    return Ok(job);
+  }
+
+  let job = sqlx::query_as::<_, Job>(
+    r#"
+SELECT * FROM job where id = ?;
+    "#,
+  )
+  .bind(result.last_insert_rowid())
+  .fetch_one(&state.pool)
+  .await?;
+
+  state.jobs.sender.send(job.id)?;
+
+  Ok(job)
+}
+
+/// Whether the last job of the file stored its vectors.
+pub async fn is_embedded(state: &BackendState, file_id: i64) -> anyhow::Result<bool> {
+  let job = sqlx::query_as::<_, Job>(
+    r#"
+SELECT * FROM job where file_id = ? ORDER BY id DESC LIMIT 1;
+    "#,
+  )
+  .bind(file_id)
+  .fetch_optional(&state.pool)
+  .await?;
+
+  Ok(job.is_some_and(|job| job.status == JobStatus::Succeeded.to_string()))
+}
+
+/// Starts the ingestion workers. Jobs left unfinished by a previous run are queued again first.
+pub async fn spawn_workers(state: BackendState) -> anyhow::Result<()> {
+  let jobs = sqlx::query_as::<_, Job>(
+    r#"
+SELECT * FROM job where status = ? OR status = ? ORDER BY id;
+    "#,
+  )
+  .bind(JobStatus::Queued.to_string())
+  .bind(JobStatus::Running.to_string())
+  .fetch_all(&state.pool)
+  .await?;
+
+  for job in jobs {
+    tracing::info!("resuming job {}", job.id);
+
+    state.jobs.sender.send(job.id)?;
+  }
+
+  for worker in 0..state.config.ingestion_conf.workers.max(1) {
+    let state = state.clone();
+
+    tokio::spawn(async move {
+      loop {
+        let job_id = state.jobs.receiver.lock().await.recv().await;
+
+        let Some(job_id) = job_id else {
+          break;
+        };
+
+        tracing::info!("worker {} running job {}", worker, job_id);
+
+        if let Err(e) = run_job(&state, job_id).await {
+          tracing::error!("job {} could not be updated: {}", job_id, e);
+        }
+      }
+    });
+  }
+
+  Ok(())
+}
+
+async fn run_job(state: &BackendState, job_id: i64) -> anyhow::Result<()> {
+  let job = sqlx::query_as::<_, Job>(
+    r#"
+SELECT * FROM job where id = ?;
+    "#,
+  )
+  .bind(job_id)
+  .fetch_one(&state.pool)
+  .await?;
+
+  let file_lock = state.jobs.file_lock(job.file_id)?;
+
+  let _file_guard = file_lock.lock().await;
+
+  let mut reporter = JobReporter { state, job };
+
+  reporter
+    .update(|job| {
+      job.status = JobStatus::Running.to_string();
+      job.error = String::new();
+      job.total = 0;
+      job.processed = 0;
+    })
+    .await?;
+
+  match embed_file(&mut reporter).await {
+    Ok(()) => {
+      reporter
+        .update(|job| job.status = JobStatus::Succeeded.to_string())
+        .await?;
+
+      spawn_refresh_index(state, reporter.job.kb_id);
+
+      Ok(())
+    }
+    Err(e) => {
+      tracing::error!("job {} failed: {}", job_id, e);
+
+      reporter
+        .update(|job| {
+          job.status = JobStatus::Failed.to_string();
+          job.error = e.to_string();
+        })
+        .await
+    }
+  }
+}
+
+/// Keeps the stored row of a running job and the subscribers up to date.
+struct JobReporter<'a> {
+  state: &'a BackendState,
+  job: Job,
+}
+
+impl<'a> JobReporter<'a> {
+  async fn update(&mut self, f: impl FnOnce(&mut Job)) -> anyhow::Result<()> {
+    f(&mut self.job);
+
+    self.job.updated_at = OffsetDateTime::now_utc().unix_timestamp();
+
+    sqlx::query(
+      r#"
+UPDATE job SET status = ?, error = ?, total = ?, processed = ?, updated_at = ? where id = ?;
+      "#,
+    )
+    .bind(&self.job.status)
+    .bind(&self.job.error)
+    .bind(self.job.total)
+    .bind(self.job.processed)
+    .bind(self.job.updated_at)
+    .bind(self.job.id)
+    .execute(&self.state.pool)
+    .await?;
+
+    // Nobody may be listening
+    let _ = self.state.jobs.events.send(self.job.clone());
+
+    Ok(())
+  }
+}
+
+/// Extracts, splits, embeds and stores the file of the job in its knowledge base table.
+async fn embed_file(reporter: &mut JobReporter<'_>) -> anyhow::Result<()> {
+  let state = reporter.state;
+  let kb_id = reporter.job.kb_id;
+
+  let kb_table_name = format!("kb_{}", kb_id);
+
+  let sqlx_file = sqlx::query_as::<_, SqlxFile>(
+    r#"
+SELECT * FROM file where id = ? AND kb_id = ?;
+    "#,
+  )
+  .bind(reporter.job.file_id)
+  .bind(kb_id)
+  .fetch_one(&state.pool)
+  .await?;
+
+  let mut builder = Fs::default();
+
+  builder.root(&format!("{}/{}", &state.config.opendal_path, kb_table_name));
+
+  let op: Operator = Operator::new(builder)?.finish();
+
+  let file_bytes = op.read(&sqlx_file.filename).await?;
+
+  let content_hash = hex::encode(Sha256::digest(&file_bytes));
+
+  if content_hash != sqlx_file.content_hash {
+    sqlx::query(
+      r#"
+UPDATE file SET content_hash = ? where id = ?;
+      "#,
+    )
+    .bind(&content_hash)
+    .bind(sqlx_file.id)
+    .execute(&state.pool)
+    .await?;
+  }
+
+  state
+    .vector_store
+    .check_collection(kb_id)
+    .await
+    .map_err(|e| {
+      anyhow::anyhow!(
+        "{}: {}, reindex the knowledge base to rebuild it",
+        kb_table_name,
+        e
+      )
+    })?;
+
+  let file_filter = ChunkFilter {
+    file_ids: vec![sqlx_file.id],
+    ..Default::default()
+  };
+
+  let stored_chunks = state
+    .vector_store
+    .list(kb_id, &file_filter, 0, None, true)
+    .await?;
+
+  // Vectors already stored for the file, reused for the chunks whose text did not change
+  let mut stored = StoredChunks::default();
+
+  for chunk in &stored_chunks {
+    let key = (chunk.text.clone(), chunk.heading_path.clone());
+
+    stored.keys.push(key.clone());
+    stored.vectors.insert(key, chunk.vector.clone());
+  }
+
+  stored.keys.sort_unstable();
+
+  let (progress_sender, mut progress) = unbounded_channel();
+
+  let bert_model = get_embedding_model(state.config.embedding_conf.model_id)?;
+  let config = state.config.clone();
+
+  // Loading, splitting and embedding are CPU bound, and a panic in any of them fails the job
+  // instead of the worker
+  let embedding = tokio::task::spawn_blocking(move || {
+    split_and_embed(
+      &config,
+      &bert_model,
+      &sqlx_file,
+      &file_bytes,
+      &stored,
+      &progress_sender,
+    )
+  });
+
+  while let Some(update) = progress.recv().await {
+    match update {
+      Progress::Total(total) => reporter.update(|job| job.total = total as i64).await?,
+      Progress::Processed(processed) => {
+        reporter
+          .update(|job| job.processed += processed as i64)
+          .await?
+      }
+    }
+  }
+
+  let Some(embedded_chunks) = embedding.await?? else {
+    return reporter.update(|job| job.processed = job.total).await;
+  };
+
+  // The new version is stored before the previous one is deleted, whatever it was split into. A
+  // failure in between leaves both, which the next run of the file replaces
+  state.vector_store.upsert(kb_id, embedded_chunks).await?;
+
+  if !stored_chunks.is_empty() {
+    let stored_ids = ChunkFilter {
+      ids: stored_chunks.into_iter().map(|chunk| chunk.id).collect(),
+      ..Default::default()
+    };
+
+    state.vector_store.delete(kb_id, &stored_ids).await?;
+  }
+
+  state.lexical_indexes.invalidate(kb_id);
+
+  Ok(())
+}
+
+/// Chunks of a file stored by its previous run, keyed by text and heading path.
+#[derive(Default)]
+struct StoredChunks {
+  /// Sorted, with one key per stored chunk.
+  keys: Vec<(String, String)>,
+  vectors: HashMap<(String, String), Vec<f32>>,
+}
+
+/// Progress of [`split_and_embed`], reported to the job as it goes.
+enum Progress {
+  Total(usize),
+  Processed(usize),
+}
+
+/// Loads and splits the file, and embeds the chunks not found in `stored`. `None` is returned when
+/// the file splits into the stored chunks exactly. Nothing is stored until the whole file is
+/// embedded, so a failed batch leaves the previous version of the file in place.
+fn split_and_embed(
+  config: &BackendConf,
+  bert_model: &BertModel,
+  sqlx_file: &SqlxFile,
+  file_bytes: &[u8],
+  stored: &StoredChunks,
+  progress: &UnboundedSender<Progress>,
+) -> anyhow::Result<Option<Vec<Chunk>>> {
+  let document = load_document(&sqlx_file.filename, file_bytes)?;
+
+  tracing::info!(
+    "format={} metadata={:?}",
+    document.format,
+    document.metadata
+  );
+
+  let splitter_kind = match document.format {
+    DocumentFormat::Markdown => TextSplitterKind::Markdown,
+    _ => config.text_splitter_conf.splitter,
+  };
+
+  let text_splitter = new_text_splitter(
+    splitter_kind,
+    &config.text_splitter_conf,
+    bert_model.tokenizer(),
+  )?;
+
+  let chunks = text_splitter.split_chunks(&document.text)?;
+
+  tracing::info!("file={} chunks={}", sqlx_file.filename, chunks.len());
+
+  // The job may have stopped listening already
+  let _ = progress.send(Progress::Total(chunks.len()));
+
+  let mut chunk_keys: Vec<(String, String)> = chunks
+    .iter()
+    .map(|c| (c.text.clone(), c.heading_path.clone()))
+    .collect();
+
+  chunk_keys.sort_unstable();
+
+  if chunk_keys == stored.keys {
+    tracing::info!("file={} unchanged", sqlx_file.filename);
+
+    return Ok(None);
+  }
+
+  let mut embedded_chunks = Vec::with_capacity(chunks.len());
+
+  for batch in chunks.chunks(EMBEDDING_BATCH_SIZE) {
+    let missing: Vec<&str> = batch
+      .iter()
+      .filter(|c| {
+        !stored
+          .vectors
+          .contains_key(&(c.text.clone(), c.heading_path.clone()))
+      })
+      .map(|c| c.text.as_str())
+      .collect();
+
+    let mut embeddings = if missing.is_empty() {
+      vec![]
+    } else {
+      bert_model.embedding_batch(&missing)?
+    }
+    .into_iter();
+
+    for c in batch {
+      let vector = match stored
+        .vectors
+        .get(&(c.text.clone(), c.heading_path.clone()))
+      {
+        Some(vector) => vector.clone(),
+        None => embeddings
+          .next()
+          .ok_or(anyhow::anyhow!("embedding batch came back short"))?,
+      };
+
+      embedded_chunks.push(Chunk {
+        id: Uuid::new_v4().to_string(),
+        kb_id: sqlx_file.kb_id,
+        file_id: sqlx_file.id,
+        file_name: sqlx_file.filename.clone(),
+        text: c.text.clone(),
+        heading_path: c.heading_path.clone(),
+        vector,
+      });
+    }
+
+    let _ = progress.send(Progress::Processed(batch.len()));
+  }
+
+  Ok(Some(embedded_chunks))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::{execute, state};
+
+  #[tokio::test]
+  async fn files_are_queued_once() {
+    let state = state(BackendConf::default()).await;
+
+    let first = enqueue_embedding(&state, 1, 1).await.unwrap();
+    let second = enqueue_embedding(&state, 1, 1).await.unwrap();
+    let other = enqueue_embedding(&state, 1, 2).await.unwrap();
+
+    assert_eq!(first.id, second.id);
+    assert_ne!(first.id, other.id);
+    assert_eq!(first.status, JobStatus::Queued.to_string());
+
+    let mut receiver = state.jobs.receiver.lock().await;
+
+    assert_eq!(receiver.try_recv().unwrap(), first.id);
+    assert_eq!(receiver.try_recv().unwrap(), other.id);
+    assert!(receiver.try_recv().is_err());
+  }
+
+  #[tokio::test]
+  async fn files_queued_again_once_their_job_started() {
+    let state = state(BackendConf::default()).await;
+
+    let first = enqueue_embedding(&state, 1, 1).await.unwrap();
+
+    sqlx::query(
+      r#"
+UPDATE job SET status = ? where id = ?;
+      "#,
+    )
+    .bind(JobStatus::Running.to_string())
+    .bind(first.id)
+    .execute(&state.pool)
+    .await
+    .unwrap();
+
+    let second = enqueue_embedding(&state, 1, 1).await.unwrap();
+
+    assert_ne!(first.id, second.id);
+  }
+
+  #[tokio::test]
+  async fn files_are_embedded_once_their_last_job_succeeded() {
+    let state = state(BackendConf::default()).await;
+
+    assert!(!is_embedded(&state, 1).await.unwrap());
+
+    execute(
+      &state,
+      r#"
+INSERT INTO job ( kb_id, file_id, status, error, total, processed, created_at, updated_at )
+VALUES ( 1, 1, 'succeeded', '', 0, 0, 0, 0 ), ( 1, 2, 'succeeded', '', 0, 0, 0, 0 ),
+  ( 1, 2, 'failed', 'boom', 0, 0, 0, 0 );
+      "#,
+    )
+    .await;
+
+    assert!(is_embedded(&state, 1).await.unwrap());
+    assert!(!is_embedded(&state, 2).await.unwrap());
+  }
+
+  #[tokio::test]
+  async fn jobs_of_unreadable_files_fail() {
+    let state = state(BackendConf::default()).await;
+
+    execute(
+      &state,
+      r#"
+INSERT INTO file ( id, kb_id, filename, bytes, purpose, created_at, updated_at )
+VALUES ( 1, 1, 'missing.md', 0, 'assistants', 0, 0 );
+      "#,
+    )
+    .await;
+
+    let job = enqueue_embedding(&state, 1, 1).await.unwrap();
+    let mut events = state.jobs.subscribe();
+
+    run_job(&state, job.id).await.unwrap();
+
+    let running = events.try_recv().unwrap();
+    let failed = events.try_recv().unwrap();
+
+    assert_eq!(running.status, JobStatus::Running.to_string());
+    assert_eq!(failed.status, JobStatus::Failed.to_string());
+    assert!(!failed.error.is_empty());
+
+    let stored = sqlx::query_as::<_, Job>(
+      r#"
+SELECT * FROM job where id = ?;
+      "#,
+    )
+    .bind(job.id)
+    .fetch_one(&state.pool)
+    .await
+    .unwrap();
+
+    assert_eq!(stored.status, failed.status);
+    assert_eq!(stored.error, failed.error);
+  }
+
+  #[test]
+  fn jobs_of_a_file_share_its_lock_while_held() {
+    let jobs = JobQueue::default();
+
+    let first = jobs.file_lock(1).unwrap();
+
+    assert!(Arc::ptr_eq(&first, &jobs.file_lock(1).unwrap()));
+    assert!(!Arc::ptr_eq(&first, &jobs.file_lock(2).unwrap()));
+
+    drop(first);
+    jobs.file_lock(3).unwrap();
+
+    assert_eq!(jobs.file_locks.lock().unwrap().len(), 1);
+  }
+}
@@ -14,13 +14,22 @@ use std::{net::SocketAddr, str::FromStr};
 use tower_http::catch_panic::CatchPanicLayer;
 use tower_http::cors::{Any, CorsLayer};
 use tower_http::trace::TraceLayer;
+use zxrag_core::retriever::LexicalIndexCache;
 use zxrag_core::types::conf::BackendConf;
+use zxrag_core::vector_store::{new_vector_store, VectorStore};
 
+use crate::controller::job_controller;
 use crate::controller::knowledge_base_controller;
 use crate::controller::openai_controller;
+use crate::controller::session_controller;
+use crate::index::IndexRebuilds;
+use crate::ingestion::JobQueue;
 
 pub mod controller;
 pub mod error;
+pub mod gc;
+pub mod index;
+pub mod ingestion;
 
 #[derive(RustEmbed)]
 #[folder = "../../zxrag-ui/dist/"]
@@ -30,12 +39,14 @@ struct Dist;
 pub struct BackendState {
   config: Arc<BackendConf>,
   pool: Pool<Sqlite>,
+  vector_store: Arc<dyn VectorStore>,
+  lexical_indexes: LexicalIndexCache,
+  jobs: JobQueue,
+  index_rebuilds: IndexRebuilds,
 }
 
-#[tokio::main]
-pub async fn run_backend(config: BackendConf) -> anyhow::Result<()> {
-  let addr: SocketAddr = config.bind_addr.parse()?;
-
+/// Opens the SQLite database, creating and migrating it as needed.
+pub async fn connect_database(config: &BackendConf) -> anyhow::Result<Pool<Sqlite>> {
   SqliteConnectOptions::from_str(&config.database_url)?
     .journal_mode(SqliteJournalMode::Wal)
     .create_if_missing(true)
@@ -46,13 +57,67 @@ pub async fn run_backend(config: BackendConf) -> anyhow::Result<()> {
 
   let migration_sql = include_str!("../migrations/sqlite/zxrag.sql");
 
-  sqlx::query(migration_sql).execute(&pool).await?;
+  // Every statement of the migration is idempotent, so it runs on each start
+  sqlx::raw_sql(migration_sql).execute(&pool).await?;
+
+  // Databases created before files were hashed lack the column, which SQLite can't add if missing
+  let has_content_hash: bool = sqlx::query_scalar(
+    r#"
+SELECT COUNT(*) > 0 FROM pragma_table_info('file') where name = 'content_hash';
+    "#,
+  )
+  .fetch_one(&pool)
+  .await?;
+
+  if !has_content_hash {
+    sqlx::query(
+      r#"
+ALTER TABLE file ADD COLUMN content_hash TEXT NOT NULL DEFAULT '';
+      "#,
+    )
+    .execute(&pool)
+    .await?;
+  }
+
+  Ok(pool)
+}
+
+/// Warns about the knowledge bases whose table doesn't record its embedding model, which are only
+/// checked by dimension. Reindexing one rebuilds its table for the loaded model.
+async fn warn_unrecorded_embedding_models(vector_store: &dyn VectorStore) -> anyhow::Result<()> {
+  for kb_id in vector_store.collections().await? {
+    if vector_store.embedding_model(kb_id).await?.is_none() {
+      tracing::warn!(
+        "kb_{} doesn't record the embedding model it was built with, reindex it to record it",
+        kb_id
+      );
+    }
+  }
+
+  Ok(())
+}
+
+#[tokio::main]
+pub async fn run_backend(config: BackendConf) -> anyhow::Result<()> {
+  let addr: SocketAddr = config.bind_addr.parse()?;
+
+  let pool = connect_database(&config).await?;
+
+  let vector_store = new_vector_store(&config).await?;
+
+  warn_unrecorded_embedding_models(vector_store.as_ref()).await?;
 
   let shared_state = BackendState {
     config: Arc::new(config),
     pool,
+    vector_store,
+    lexical_indexes: LexicalIndexCache::default(),
+    jobs: JobQueue::default(),
+    index_rebuilds: IndexRebuilds::default(),
   };
 
+  ingestion::spawn_workers(shared_state.clone()).await?;
+
   let cors = CorsLayer::new()
     .allow_methods([Method::GET, Method::POST])
     .allow_headers(Any)
@@ -80,6 +145,29 @@ pub async fn run_backend(config: BackendConf) -> anyhow::Result<()> {
       "/:kb_id/chat/completions",
       post(knowledge_base_controller::create_chat_completion),
     )
+    .route(
+      "/:kb_id/search",
+      post(knowledge_base_controller::search_knowledge_base),
+    )
+    .route(
+      "/:kb_id/index",
+      get(knowledge_base_controller::get_vector_index)
+        .post(knowledge_base_controller::build_vector_index)
+        .delete(knowledge_base_controller::drop_vector_index),
+    )
+    .route(
+      "/:kb_id/reindex",
+      post(knowledge_base_controller::reindex_knowledge_base),
+    )
+    .route(
+      "/:kb_id/settings",
+      get(knowledge_base_controller::get_knowledge_base_setting)
+        .post(knowledge_base_controller::update_knowledge_base_setting),
+    )
+    .route(
+      "/:kb_id/files/:file_id",
+      delete(knowledge_base_controller::delete_file),
+    )
     .route(
       "/:kb_id/files",
       post(knowledge_base_controller::upload_file).get(knowledge_base_controller::list_files),
@@ -89,6 +177,27 @@ pub async fn run_backend(config: BackendConf) -> anyhow::Result<()> {
       delete(knowledge_base_controller::delete_knowledge_base),
     );
 
+  let session_routes = Router::new()
+    .route(
+      "/",
+      get(session_controller::list_sessions).post(session_controller::create_session),
+    )
+    .route(
+      "/:session_id/messages",
+      get(session_controller::list_messages),
+    )
+    .route(
+      "/:session_id",
+      get(session_controller::get_session)
+        .post(session_controller::update_session)
+        .delete(session_controller::delete_session),
+    );
+
+  let job_routes = Router::new()
+    .route("/", get(job_controller::list_jobs))
+    .route("/:job_id/events", get(job_controller::job_events))
+    .route("/:job_id", get(job_controller::get_job));
+
   let v1_routes = Router::new()
     .route(
       "/chat/completions",
@@ -96,12 +205,19 @@ pub async fn run_backend(config: BackendConf) -> anyhow::Result<()> {
     )
     .route("/embeddings", post(openai_controller::create_embeddings))
     .route("/models", post(openai_controller::models))
+    .route("/rerank", post(openai_controller::rerank))
+    .route(
+      "/inference/status",
+      get(openai_controller::inference_status),
+    )
     .route(
       "/files",
       post(openai_controller::upload_file).get(openai_controller::list_files),
     )
     .route("/files/:file_id", delete(openai_controller::delete_file))
-    .nest("/knowledgebases", knowledge_base_routes);
+    .nest("/knowledgebases", knowledge_base_routes)
+    .nest("/sessions", session_routes)
+    .nest("/jobs", job_routes);
 
   let app = Router::new()
     .nest("/v1", v1_routes)
@@ -153,3 +269,119 @@ where
     }
   }
 }
+
+#[cfg(test)]
+pub(crate) mod testing {
+  use uuid::Uuid;
+  use zxrag_core::types::lancedb::set_embedding_schema;
+  use zxrag_core::types::model::ModelId;
+  use zxrag_core::vector_store::{Chunk, MemoryVectorStore};
+
+  use super::*;
+
+  /// URL of a new SQLite database file.
+  pub fn database_url() -> String {
+    let path = std::env::temp_dir().join(format!("zxrag-test-{}.db", Uuid::new_v4()));
+
+    format!("sqlite://{}", path.display())
+  }
+
+  /// State over a new database, a new blob directory and an in-memory vector store.
+  pub async fn state(mut config: BackendConf) -> BackendState {
+    // The schema is global, every test uses the same dimension
+    let _ = set_embedding_schema(ModelId::default(), 2);
+
+    config.database_url = database_url();
+    config.opendal_path = std::env::temp_dir()
+      .join(format!("zxrag-test-{}", Uuid::new_v4()))
+      .display()
+      .to_string();
+
+    let pool = connect_database(&config).await.unwrap();
+
+    BackendState {
+      config: Arc::new(config),
+      pool,
+      vector_store: Arc::new(MemoryVectorStore::default()),
+      lexical_indexes: LexicalIndexCache::default(),
+      jobs: JobQueue::default(),
+      index_rebuilds: IndexRebuilds::default(),
+    }
+  }
+
+  pub fn chunk(id: &str, kb_id: i64, file_id: i64, text: &str) -> Chunk {
+    Chunk {
+      id: id.to_string(),
+      kb_id,
+      file_id,
+      file_name: format!("{}.md", file_id),
+      text: text.to_string(),
+      heading_path: String::new(),
+      vector: vec![1.0, 0.0],
+    }
+  }
+
+  /// Runs `sql`, which may hold several statements.
+  pub async fn execute(state: &BackendState, sql: &str) {
+    sqlx::raw_sql(sql).execute(&state.pool).await.unwrap();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn migrations_run_again_on_an_existing_database() {
+    let config = BackendConf {
+      database_url: testing::database_url(),
+      ..Default::default()
+    };
+
+    let pool = connect_database(&config).await.unwrap();
+
+    sqlx::query(
+      r#"
+INSERT INTO knowledge_base ( name, created_at, updated_at ) VALUES ( 'kb', 0, 0 );
+      "#,
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    pool.close().await;
+
+    let pool = connect_database(&config).await.unwrap();
+
+    let tables: Vec<String> = sqlx::query_scalar(
+      r#"
+SELECT name FROM sqlite_master where type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name;
+      "#,
+    )
+    .fetch_all(&pool)
+    .await
+    .unwrap();
+    let knowledge_bases: i64 = sqlx::query_scalar(
+      r#"
+SELECT COUNT(*) FROM knowledge_base;
+      "#,
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+
+    assert_eq!(
+      tables,
+      vec![
+        "file",
+        "job",
+        "knowledge_base",
+        "knowledge_base_setting",
+        "message",
+        "session",
+        "vector_index",
+      ]
+    );
+    assert_eq!(knowledge_bases, 1);
+  }
+}
@@ -0,0 +1,71 @@
+use std::collections::BTreeMap;
+
+use crate::document_loader::text::decode_text;
+use crate::document_loader::{Document, DocumentFormat, DocumentLoader};
+
+/// Loads CSV and TSV files row by row. Each record is rendered as `header: value` pairs on its own
+/// paragraph, so splitters only cut between rows and every chunk keeps its column names.
+#[derive(Debug, Clone)]
+pub struct DelimitedLoader {
+  delimiter: u8,
+}
+
+impl DelimitedLoader {
+  pub fn new(delimiter: u8) -> Self {
+    Self { delimiter }
+  }
+}
+
+impl DocumentLoader for DelimitedLoader {
+  fn load(&self, bytes: &[u8]) -> anyhow::Result<Document> {
+    let (content, encoding) = decode_text(bytes)?;
+
+    let mut reader = csv::ReaderBuilder::new()
+      .delimiter(self.delimiter)
+      .flexible(true)
+      .from_reader(content.as_bytes());
+
+    let headers: Vec<String> = reader
+      .headers()?
+      .iter()
+      .map(|h| h.trim().to_string())
+      .collect();
+
+    let mut rows = vec![];
+
+    for record in reader.records() {
+      let record = record?;
+
+      let row = record
+        .iter()
+        .enumerate()
+        .filter(|(_, value)| !value.trim().is_empty())
+        .map(|(i, value)| match headers.get(i) {
+          Some(header) if !header.is_empty() => format!("{}: {}", header, value.trim()),
+          _ => value.trim().to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+
+      if !row.is_empty() {
+        rows.push(row);
+      }
+    }
+
+    let format = if self.delimiter == b'\t' {
+      DocumentFormat::Tsv
+    } else {
+      DocumentFormat::Csv
+    };
+
+    Ok(Document {
+      text: rows.join("\n\n"),
+      format,
+      metadata: BTreeMap::from([
+        ("encoding".to_string(), encoding.to_string()),
+        ("columns".to_string(), headers.join(",")),
+        ("rows".to_string(), rows.len().to_string()),
+      ]),
+    })
+  }
+}
@@ -0,0 +1,123 @@
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::collections::BTreeMap;
+use std::io::{Cursor, Read};
+use zip::ZipArchive;
+
+use crate::document_loader::{normalize_whitespace, Document, DocumentFormat, DocumentLoader};
+
+#[derive(Debug, Clone)]
+pub struct DocxLoader;
+
+impl DocumentLoader for DocxLoader {
+  fn load(&self, bytes: &[u8]) -> anyhow::Result<Document> {
+    let mut archive = ZipArchive::new(Cursor::new(bytes))?;
+
+    let document_xml = read_entry(&mut archive, "word/document.xml")?
+      .ok_or(anyhow::anyhow!("word/document.xml not found"))?;
+
+    let text = extract_document_text(&document_xml)?;
+
+    let mut metadata = BTreeMap::new();
+
+    if let Some(core_xml) = read_entry(&mut archive, "docProps/core.xml")? {
+      for (tag, key) in [
+        ("dc:title", "title"),
+        ("dc:creator", "author"),
+        ("dc:subject", "subject"),
+        ("dcterms:created", "created"),
+        ("dcterms:modified", "modified"),
+      ] {
+        if let Some(value) = extract_element_text(&core_xml, tag)? {
+          metadata.insert(key.to_string(), value);
+        }
+      }
+    }
+
+    Ok(Document {
+      text: normalize_whitespace(&text),
+      format: DocumentFormat::Docx,
+      metadata,
+    })
+  }
+}
+
+fn read_entry(
+  archive: &mut ZipArchive<Cursor<&[u8]>>,
+  name: &str,
+) -> anyhow::Result<Option<String>> {
+  let mut entry = match archive.by_name(name) {
+    Ok(entry) => entry,
+    Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+    Err(e) => return Err(e.into()),
+  };
+
+  let mut content = String::new();
+  entry.read_to_string(&mut content)?;
+
+  Ok(Some(content))
+}
+
+/// Walks `w:body`, emitting `w:t` runs, tabs and breaks, with a blank line after each paragraph and
+/// table cells separated by ` | ` so rows stay on one line.
+fn extract_document_text(xml: &str) -> anyhow::Result<String> {
+  let mut reader = Reader::from_str(xml);
+
+  let mut text = String::new();
+  let mut in_text = false;
+  let mut in_cell = false;
+
+  loop {
+    match reader.read_event()? {
+      Event::Start(e) => match e.name().as_ref() {
+        b"w:t" => in_text = true,
+        b"w:tc" => in_cell = true,
+        b"w:tab" => text.push('\t'),
+        _ => {}
+      },
+      Event::Empty(e) => match e.name().as_ref() {
+        b"w:tab" => text.push('\t'),
+        b"w:br" | b"w:cr" => text.push('\n'),
+        _ => {}
+      },
+      Event::Text(e) if in_text => text.push_str(&e.unescape()?),
+      Event::End(e) => match e.name().as_ref() {
+        b"w:t" => in_text = false,
+        b"w:p" if in_cell => text.push(' '),
+        b"w:p" => text.push_str("\n\n"),
+        b"w:tc" => {
+          in_cell = false;
+          text.push_str(" | ");
+        }
+        b"w:tr" => text.push('\n'),
+        b"w:tbl" => text.push_str("\n\n"),
+        _ => {}
+      },
+      Event::Eof => break,
+      _ => {}
+    }
+  }
+
+  Ok(text)
+}
+
+fn extract_element_text(xml: &str, tag: &str) -> anyhow::Result<Option<String>> {
+  let mut reader = Reader::from_str(xml);
+
+  let mut inside = false;
+  let mut value = String::new();
+
+  loop {
+    match reader.read_event()? {
+      Event::Start(e) if e.name().as_ref() == tag.as_bytes() => inside = true,
+      Event::Text(e) if inside => value.push_str(&e.unescape()?),
+      Event::End(e) if e.name().as_ref() == tag.as_bytes() => break,
+      Event::Eof => break,
+      _ => {}
+    }
+  }
+
+  let value = value.trim();
+
+  Ok((!value.is_empty()).then(|| value.to_string()))
+}
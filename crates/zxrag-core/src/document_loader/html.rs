@@ -0,0 +1,144 @@
+use scraper::{ElementRef, Html, Node, Selector};
+use std::collections::BTreeMap;
+
+use crate::document_loader::text::decode_text;
+use crate::document_loader::{normalize_whitespace, Document, DocumentFormat, DocumentLoader};
+
+const BOILERPLATE_TAGS: [&str; 13] = [
+  "script", "style", "noscript", "template", "iframe", "svg", "canvas", "nav", "header", "footer",
+  "aside", "form", "button",
+];
+
+const BLOCK_TAGS: [&str; 24] = [
+  "address",
+  "article",
+  "blockquote",
+  "dd",
+  "div",
+  "dl",
+  "dt",
+  "figcaption",
+  "figure",
+  "h1",
+  "h2",
+  "h3",
+  "h4",
+  "h5",
+  "h6",
+  "hr",
+  "li",
+  "main",
+  "ol",
+  "p",
+  "pre",
+  "section",
+  "table",
+  "ul",
+];
+
+/// Extracts readable text from HTML. Content inside `<main>` or `<article>` is preferred over the
+/// whole `<body>`, and navigation, scripts, forms and similar boilerplate are dropped.
+#[derive(Debug, Clone)]
+pub struct HtmlLoader;
+
+impl DocumentLoader for HtmlLoader {
+  fn load(&self, bytes: &[u8]) -> anyhow::Result<Document> {
+    let (html, _) = decode_text(bytes)?;
+
+    let document = Html::parse_document(&html);
+
+    let mut metadata = BTreeMap::new();
+
+    if let Some(title) = select_first(&document, "title") {
+      let title = title.text().collect::<String>();
+      let title = title.trim();
+
+      if !title.is_empty() {
+        metadata.insert("title".to_string(), title.to_string());
+      }
+    }
+
+    if let Some(description) =
+      select_first(&document, r#"meta[name="description"]"#).and_then(|e| e.value().attr("content"))
+    {
+      metadata.insert("description".to_string(), description.trim().to_string());
+    }
+
+    let root = select_first(&document, "main")
+      .or_else(|| select_first(&document, "article"))
+      .or_else(|| select_first(&document, "body"))
+      .unwrap_or_else(|| document.root_element());
+
+    let mut text = String::new();
+
+    collect_text(root, &mut text);
+
+    Ok(Document {
+      text: normalize_whitespace(&text),
+      format: DocumentFormat::Html,
+      metadata,
+    })
+  }
+}
+
+fn select_first<'a>(document: &'a Html, selector: &str) -> Option<ElementRef<'a>> {
+  let selector = Selector::parse(selector).ok()?;
+
+  document.select(&selector).next()
+}
+
+fn collect_text(element: ElementRef, text: &mut String) {
+  for child in element.children() {
+    match child.value() {
+      Node::Text(t) => {
+        if element.value().name() == "pre" {
+          text.push_str(t);
+          continue;
+        }
+
+        let mut last_space = text.is_empty() || text.ends_with(char::is_whitespace);
+
+        for c in t.chars() {
+          if !c.is_whitespace() {
+            text.push(c);
+            last_space = false;
+          } else if !last_space {
+            text.push(' ');
+            last_space = true;
+          }
+        }
+      }
+      Node::Element(e) => {
+        let name = e.name();
+
+        if BOILERPLATE_TAGS.contains(&name) {
+          continue;
+        }
+
+        let Some(child) = ElementRef::wrap(child) else {
+          continue;
+        };
+
+        match name {
+          "br" => text.push('\n'),
+          "td" | "th" => {
+            collect_text(child, text);
+            text.push_str(" | ");
+          }
+          "tr" => {
+            collect_text(child, text);
+            text.truncate(text.trim_end_matches(" | ").len());
+            text.push('\n');
+          }
+          _ if BLOCK_TAGS.contains(&name) => {
+            text.push_str("\n\n");
+            collect_text(child, text);
+            text.push_str("\n\n");
+          }
+          _ => collect_text(child, text),
+        }
+      }
+      _ => {}
+    }
+  }
+}
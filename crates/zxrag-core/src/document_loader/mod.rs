@@ -0,0 +1,140 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use strum::{Display, EnumString};
+
+pub mod delimited;
+pub mod docx;
+pub mod html;
+pub mod pdf;
+pub mod text;
+
+pub use delimited::DelimitedLoader;
+pub use docx::DocxLoader;
+pub use html::HtmlLoader;
+pub use pdf::PdfLoader;
+pub use text::TextLoader;
+
+#[derive(Debug, Clone, Default)]
+pub struct Document {
+  pub text: String,
+  pub format: DocumentFormat,
+  pub metadata: BTreeMap<String, String>,
+}
+
+pub trait DocumentLoader: Send + Sync {
+  fn load(&self, bytes: &[u8]) -> anyhow::Result<Document>;
+}
+
+#[derive(
+  Clone, Default, Debug, Copy, PartialEq, Eq, Deserialize, Serialize, EnumString, Display,
+)]
+pub enum DocumentFormat {
+  #[default]
+  #[serde(rename = "text")]
+  #[strum(serialize = "text")]
+  PlainText,
+  #[serde(rename = "markdown")]
+  #[strum(serialize = "markdown")]
+  Markdown,
+  #[serde(rename = "pdf")]
+  #[strum(serialize = "pdf")]
+  Pdf,
+  #[serde(rename = "docx")]
+  #[strum(serialize = "docx")]
+  Docx,
+  #[serde(rename = "html")]
+  #[strum(serialize = "html")]
+  Html,
+  #[serde(rename = "csv")]
+  #[strum(serialize = "csv")]
+  Csv,
+  #[serde(rename = "tsv")]
+  #[strum(serialize = "tsv")]
+  Tsv,
+}
+
+impl DocumentFormat {
+  /// Picks a format from the MIME type guessed for `filename`, falling back to its extension for
+  /// types `mime_guess` doesn't know about. Anything unrecognised is treated as plain text.
+  pub fn from_filename(filename: &str) -> Self {
+    let mime = mime_guess::from_path(filename).first_raw();
+
+    match mime {
+      Some("application/pdf") => return Self::Pdf,
+      Some("application/vnd.openxmlformats-officedocument.wordprocessingml.document") => {
+        return Self::Docx
+      }
+      Some("text/html" | "application/xhtml+xml") => return Self::Html,
+      Some("text/csv") => return Self::Csv,
+      Some("text/tab-separated-values") => return Self::Tsv,
+      Some("text/markdown" | "text/x-markdown") => return Self::Markdown,
+      _ => {}
+    }
+
+    let extension = Path::new(filename)
+      .extension()
+      .and_then(|v| v.to_str())
+      .map(|v| v.to_ascii_lowercase());
+
+    match extension.as_deref() {
+      Some("pdf") => Self::Pdf,
+      Some("docx") => Self::Docx,
+      Some("html" | "htm" | "xhtml") => Self::Html,
+      Some("csv") => Self::Csv,
+      Some("tsv" | "tab") => Self::Tsv,
+      Some("md" | "markdown" | "mdx") => Self::Markdown,
+      _ => Self::PlainText,
+    }
+  }
+}
+
+pub fn new_document_loader(format: DocumentFormat) -> Box<dyn DocumentLoader> {
+  match format {
+    DocumentFormat::PlainText => Box::new(TextLoader::new(DocumentFormat::PlainText)),
+    DocumentFormat::Markdown => Box::new(TextLoader::new(DocumentFormat::Markdown)),
+    DocumentFormat::Pdf => Box::new(PdfLoader),
+    DocumentFormat::Docx => Box::new(DocxLoader),
+    DocumentFormat::Html => Box::new(HtmlLoader),
+    DocumentFormat::Csv => Box::new(DelimitedLoader::new(b',')),
+    DocumentFormat::Tsv => Box::new(DelimitedLoader::new(b'\t')),
+  }
+}
+
+pub fn load_document(filename: &str, bytes: &[u8]) -> anyhow::Result<Document> {
+  let format = DocumentFormat::from_filename(filename);
+
+  let mut document = new_document_loader(format)
+    .load(bytes)
+    .map_err(|e| anyhow::anyhow!("failed to load {} as {}: {}", filename, format, e))?;
+
+  document
+    .metadata
+    .insert("filename".to_string(), filename.to_string());
+
+  Ok(document)
+}
+
+/// Collapses runs of blank lines and trailing whitespace left behind by extractors.
+pub(crate) fn normalize_whitespace(text: &str) -> String {
+  let mut output = String::with_capacity(text.len());
+  let mut blank_lines = 0;
+
+  for line in text.lines() {
+    let line = line.trim_end();
+
+    if line.trim().is_empty() {
+      blank_lines += 1;
+      continue;
+    }
+
+    if !output.is_empty() {
+      output.push_str(if blank_lines > 0 { "\n\n" } else { "\n" });
+    }
+
+    output.push_str(line);
+    blank_lines = 0;
+  }
+
+  output
+}
@@ -0,0 +1,32 @@
+use std::collections::BTreeMap;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::document_loader::{normalize_whitespace, Document, DocumentFormat, DocumentLoader};
+use crate::util::panic_message;
+
+#[derive(Debug, Clone)]
+pub struct PdfLoader;
+
+impl DocumentLoader for PdfLoader {
+  fn load(&self, bytes: &[u8]) -> anyhow::Result<Document> {
+    // `pdf_extract` panics on some malformed files instead of returning an error
+    let pages = catch_unwind(AssertUnwindSafe(|| {
+      pdf_extract::extract_text_from_mem_by_pages(bytes)
+    }))
+    .map_err(|payload| anyhow::anyhow!("malformed pdf: {}", panic_message(&*payload)))?
+    .map_err(anyhow::Error::msg)?;
+
+    let text = pages
+      .iter()
+      .map(|page| normalize_whitespace(page))
+      .filter(|page| !page.is_empty())
+      .collect::<Vec<String>>()
+      .join("\n\n");
+
+    Ok(Document {
+      text,
+      format: DocumentFormat::Pdf,
+      metadata: BTreeMap::from([("pages".to_string(), pages.len().to_string())]),
+    })
+  }
+}
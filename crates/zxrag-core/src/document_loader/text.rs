@@ -0,0 +1,57 @@
+use chardetng::EncodingDetector;
+use encoding_rs::Encoding;
+use std::collections::BTreeMap;
+
+use crate::document_loader::{Document, DocumentFormat, DocumentLoader};
+
+/// Loads plain text and Markdown, detecting the encoding from a BOM or, failing that, from the
+/// content itself when the bytes aren't valid UTF-8.
+#[derive(Debug, Clone)]
+pub struct TextLoader {
+  format: DocumentFormat,
+}
+
+impl TextLoader {
+  pub fn new(format: DocumentFormat) -> Self {
+    Self { format }
+  }
+}
+
+impl DocumentLoader for TextLoader {
+  fn load(&self, bytes: &[u8]) -> anyhow::Result<Document> {
+    let (text, encoding) = decode_text(bytes)?;
+
+    Ok(Document {
+      text,
+      format: self.format,
+      metadata: BTreeMap::from([("encoding".to_string(), encoding.to_string())]),
+    })
+  }
+}
+
+pub fn decode_text(bytes: &[u8]) -> anyhow::Result<(String, &'static str)> {
+  let encoding = if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+    encoding
+  } else if std::str::from_utf8(bytes).is_ok() {
+    encoding_rs::UTF_8
+  } else {
+    let mut detector = EncodingDetector::new();
+    detector.feed(bytes, true);
+    detector.guess(None, true)
+  };
+
+  let (text, encoding, had_errors) = encoding.decode(bytes);
+
+  if had_errors {
+    tracing::warn!(
+      "malformed sequences replaced while decoding as {}",
+      encoding.name()
+    );
+  }
+
+  if text.contains('\0') {
+    anyhow::bail!("binary content can't be loaded as text");
+  }
+
+  Ok((text.into_owned(), encoding.name()))
+}
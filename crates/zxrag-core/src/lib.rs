@@ -1,4 +1,7 @@
+pub mod document_loader;
 pub mod models;
+pub mod retriever;
 pub mod text_splitter;
 pub mod types;
 pub mod util;
+pub mod vector_store;
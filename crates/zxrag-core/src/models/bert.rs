@@ -1,12 +1,12 @@
-use candle_core::{Device, Tensor};
+use candle_core::{Device, IndexOp, Tensor};
 use candle_nn::VarBuilder;
 use candle_transformers::models::bert::{BertModel, Config, DTYPE};
 use std::path::PathBuf;
-use tokenizers::{PaddingParams, Tokenizer};
+use tokenizers::{PaddingParams, PaddingStrategy, Tokenizer, TruncationParams};
 
 use crate::types::{
   conf::EmbeddingConf,
-  model::{ModelEngine, ModelId},
+  model::{ModelEngine, ModelId, PoolingStrategy},
 };
 use crate::util::candle_device;
 
@@ -16,6 +16,8 @@ pub struct Model {
   device: Device,
   bert_model: BertModel,
   tokenizer: Tokenizer,
+  pooling: PoolingStrategy,
+  dimension: usize,
 }
 
 impl Model {
@@ -39,7 +41,26 @@ impl Model {
     let config = std::fs::read_to_string(config_filename)?;
     let config: Config = serde_json::from_str(&config)?;
 
-    let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(anyhow::Error::msg)?;
+    let mut tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(anyhow::Error::msg)?;
+
+    let pad_id = config.pad_token_id as u32;
+
+    let pad_token = tokenizer
+      .id_to_token(pad_id)
+      .unwrap_or_else(|| "[PAD]".to_string());
+
+    tokenizer
+      .with_padding(Some(PaddingParams {
+        strategy: PaddingStrategy::BatchLongest,
+        pad_id,
+        pad_token,
+        ..Default::default()
+      }))
+      .with_truncation(Some(TruncationParams {
+        max_length: config.max_position_embeddings,
+        ..Default::default()
+      }))
+      .map_err(anyhow::Error::msg)?;
 
     let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[weights_filename], DTYPE, &device)? };
 
@@ -51,56 +72,99 @@ impl Model {
       device,
       bert_model: model,
       tokenizer,
+      pooling: conf.pooling,
+      dimension: config.hidden_size,
     })
   }
 
+  pub fn id(&self) -> ModelId {
+    self.id
+  }
+
+  pub fn dimension(&self) -> usize {
+    self.dimension
+  }
+
+  pub fn tokenizer(&self) -> &Tokenizer {
+    &self.tokenizer
+  }
+
+  /// Counts the tokens the model sees for `prompts`, after truncation and without padding.
   pub fn embedding_batch(&self, prompts: &[&str]) -> anyhow::Result<Vec<Vec<f32>>> {
+    Ok(self.embedding_batch_with_usage(prompts)?.0)
+  }
+
+  /// Embeds the prompts, along with the number of tokens they were encoded into, padding excepted.
+  pub fn embedding_batch_with_usage(
+    &self,
+    prompts: &[&str],
+  ) -> anyhow::Result<(Vec<Vec<f32>>, usize)> {
     tracing::info!("id={}", self.id);
     tracing::info!("engine={}", self.engine);
 
     let start = std::time::Instant::now();
 
-    let mut tokenizer = self.tokenizer.clone();
-
-    tokenizer
-      .with_padding(Some(PaddingParams {
-        strategy: tokenizers::PaddingStrategy::Fixed(16),
-        ..Default::default()
-      }))
-      .with_truncation(None)
-      .map_err(anyhow::Error::msg)?;
-
-    let tokens = tokenizer
+    let tokens = self
+      .tokenizer
       .encode_batch(prompts.to_vec(), true)
       .map_err(anyhow::Error::msg)?;
 
+    let prompt_tokens = tokens
+      .iter()
+      .map(|tokens| tokens.get_attention_mask().iter().sum::<u32>() as usize)
+      .sum();
+
     let token_ids = tokens
       .iter()
-      .map(|tokens| {
-        let tokens = tokens.get_ids().to_vec();
-        Ok(Tensor::new(tokens.as_slice(), &self.device)?)
-      })
+      .map(|tokens| Ok(Tensor::new(tokens.get_ids(), &self.device)?))
+      .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let attention_mask = tokens
+      .iter()
+      .map(|tokens| Ok(Tensor::new(tokens.get_attention_mask(), &self.device)?))
       .collect::<anyhow::Result<Vec<_>>>()?;
 
     let token_ids = Tensor::stack(&token_ids, 0)?;
+    let attention_mask = Tensor::stack(&attention_mask, 0)?;
     let token_type_ids = token_ids.zeros_like()?;
 
     tracing::info!("running inference on batch {:?}", token_ids.shape());
 
-    let embeddings = self.bert_model.forward(&token_ids, &token_type_ids)?;
+    let embeddings = self
+      .bert_model
+      .forward(&token_ids, &token_type_ids, Some(&attention_mask))?;
 
     tracing::info!("generated embeddings {:?}", embeddings.shape());
 
-    // Apply some avg-pooling by taking the mean embedding value for all tokens (including padding)
-    let (_n_sentence, n_tokens, _hidden_size) = embeddings.dims3()?;
-    let embeddings = (embeddings.sum(1)? / (n_tokens as f64))?;
+    let embeddings = match self.pooling {
+      PoolingStrategy::Cls => embeddings.i((.., 0))?,
+      PoolingStrategy::Mean => {
+        // Average over real tokens only, padding positions are zeroed out by the mask
+        let mask = attention_mask.to_dtype(DTYPE)?.unsqueeze(2)?;
+        let sum = embeddings.broadcast_mul(&mask)?.sum(1)?;
+        sum.broadcast_div(&mask.sum(1)?)?
+      }
+      PoolingStrategy::LastToken => {
+        // Padding is on the right, so the last real token sits at `len - 1`
+        let last_tokens = tokens
+          .iter()
+          .enumerate()
+          .map(|(i, tokens)| {
+            let len = tokens.get_attention_mask().iter().sum::<u32>() as usize;
+            Ok(embeddings.i((i, len.saturating_sub(1)))?)
+          })
+          .collect::<anyhow::Result<Vec<_>>>()?;
+        Tensor::stack(&last_tokens, 0)?
+      }
+    };
+
     let embeddings = normalize_l2(&embeddings)?;
 
     tracing::info!("pooled embeddings {:?}", embeddings.shape());
 
     tracing::info!("Took {:?}", start.elapsed());
 
-    Ok(embeddings.to_vec2()?)
+    Ok((embeddings.to_vec2()?, prompt_tokens))
   }
 }
 
@@ -1,3 +1,4 @@
+use candle_core::quantized::gguf_file;
 use candle_core::DType;
 use candle_core::{Device, Tensor};
 use candle_nn::VarBuilder;
@@ -5,19 +6,26 @@ use candle_transformers::models::gemma::{Config, Model as GemmaModel};
 use std::path::PathBuf;
 use tokenizers::Tokenizer;
 
+use crate::models::quantized_gemma::ModelWeights as QGemmaModel;
 use crate::types::{
   conf::LlmConf,
   llm::LlmModel,
   model::{ModelEngine, ModelId},
 };
-use crate::util::{candle_device, local_load_safetensors};
+use crate::util::{candle_device, format_size, local_load_safetensors};
+
+#[derive(Debug, Clone)]
+enum GemmaWeights {
+  Full(GemmaModel),
+  Quantized(QGemmaModel),
+}
 
 #[derive(Debug, Clone)]
 pub struct Model {
   id: ModelId,
   engine: ModelEngine,
   device: Device,
-  model: GemmaModel,
+  gemma_model: GemmaWeights,
   tokenizer: Tokenizer,
 }
 
@@ -39,7 +47,10 @@ impl LlmModel for Model {
   }
 
   fn forward(&mut self, x: &Tensor, index_pos: usize) -> anyhow::Result<Tensor> {
-    let logits = self.model.forward(x, index_pos)?;
+    let logits = match &mut self.gemma_model {
+      GemmaWeights::Full(m) => m.forward(x, index_pos)?,
+      GemmaWeights::Quantized(m) => m.forward(x, index_pos)?,
+    };
 
     Ok(logits)
   }
@@ -59,6 +70,10 @@ impl Model {
 
     let device = candle_device(&conf.device);
 
+    if conf.model_engine == ModelEngine::Gguf {
+      return Self::new_gguf(conf, device);
+    }
+
     let model_path = PathBuf::from(&conf.model_path);
 
     let tokenizer_filename = model_path.join("tokenizer.json");
@@ -89,7 +104,47 @@ impl Model {
       id: conf.model_id,
       engine: conf.model_engine,
       device,
-      model,
+      gemma_model: GemmaWeights::Full(model),
+      tokenizer,
+    })
+  }
+
+  /// GGUF checkpoints keep their quantized weights, see `models::quantized_gemma`.
+  fn new_gguf(conf: &LlmConf, device: Device) -> anyhow::Result<Self> {
+    let start = std::time::Instant::now();
+
+    let model_path = PathBuf::from(&conf.model_path);
+    let mut model_file = std::fs::File::open(&model_path)?;
+
+    let content =
+      gguf_file::Content::read(&mut model_file).map_err(|e| e.with_path(&model_path))?;
+
+    let mut total_size_in_bytes = 0;
+    for (_, tensor) in content.tensor_infos.iter() {
+      let elem_count = tensor.shape.elem_count();
+      total_size_in_bytes +=
+        elem_count * tensor.ggml_dtype.type_size() / tensor.ggml_dtype.block_size();
+    }
+
+    tracing::info!(
+      "loaded {:?} tensors ({}) in {:.2}s",
+      content.tensor_infos.len(),
+      &format_size(total_size_in_bytes),
+      start.elapsed().as_secs_f32(),
+    );
+
+    let model = QGemmaModel::from_gguf(content, &mut model_file, &device)?;
+
+    let tokenizer =
+      Tokenizer::from_file(PathBuf::from(&conf.tokenizer_path)).map_err(anyhow::Error::msg)?;
+
+    tracing::info!("model built");
+
+    Ok(Self {
+      id: conf.model_id,
+      engine: conf.model_engine,
+      device,
+      gemma_model: GemmaWeights::Quantized(model),
       tokenizer,
     })
   }
@@ -1,9 +1,9 @@
 use candle_core::quantized::{ggml_file, gguf_file};
 use candle_core::{Device, Tensor};
-use candle_transformers::models::quantized_llama::ModelWeights;
 use std::path::PathBuf;
 use tokenizers::Tokenizer;
 
+use crate::models::quantized_llama::{KvCache, ModelWeights};
 use crate::types::{
   conf::LlmConf,
   llm::LlmModel,
@@ -17,6 +17,7 @@ pub struct Model {
   engine: ModelEngine,
   device: Device,
   model_weights: ModelWeights,
+  kv_cache: KvCache,
   tokenizer: Tokenizer,
 }
 
@@ -38,7 +39,21 @@ impl LlmModel for Model {
   }
 
   fn forward(&mut self, x: &Tensor, index_pos: usize) -> anyhow::Result<Tensor> {
-    let logits = self.model_weights.forward(x, index_pos)?;
+    if index_pos == 0 {
+      self.kv_cache.clear();
+    }
+
+    let logits = self.model_weights.forward(x, &mut self.kv_cache)?;
+
+    Ok(logits)
+  }
+
+  fn kv_cache(&mut self) -> Option<&mut KvCache> {
+    Some(&mut self.kv_cache)
+  }
+
+  fn forward_batch(&self, tokens: &[u32], caches: &mut [&mut KvCache]) -> anyhow::Result<Tensor> {
+    let logits = self.model_weights.forward_batch(tokens, caches)?;
 
     Ok(logits)
   }
@@ -117,6 +132,7 @@ impl Model {
       engine: conf.model_engine,
       device,
       model_weights,
+      kv_cache: KvCache::default(),
       tokenizer,
     })
   }
@@ -2,3 +2,6 @@ pub mod bert;
 pub mod llama_cpp;
 pub mod phi;
 pub mod gemma;
+pub mod quantized_gemma;
+pub mod quantized_llama;
+pub mod reranker;
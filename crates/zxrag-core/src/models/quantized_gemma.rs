@@ -0,0 +1,255 @@
+//! Quantized Gemma adapted from `candle_transformers::models::gemma`, with the linear layers kept
+//! as the quantized tensors of a llama.cpp GGUF checkpoint. llama.cpp stores the RMSNorm weights
+//! with Gemma's `1 +` offset already applied, so the norms are the plain ones.
+
+use candle_core::quantized::{gguf_file, QMatMul};
+use candle_core::{DType, Device, IndexOp, Module, Result, Tensor};
+use candle_nn::Embedding;
+use candle_transformers::quantized_nn::RmsNorm;
+use candle_transformers::utils::repeat_kv;
+
+#[derive(Debug, Clone)]
+struct Mlp {
+  gate_proj: QMatMul,
+  up_proj: QMatMul,
+  down_proj: QMatMul,
+}
+
+impl Module for Mlp {
+  fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+    // `gelu` is the tanh approximation Gemma's `gelu_pytorch_tanh` stands for
+    let gate = self.gate_proj.forward(xs)?.gelu()?;
+    let up = self.up_proj.forward(xs)?;
+    self.down_proj.forward(&(gate * up)?)
+  }
+}
+
+#[derive(Debug, Clone)]
+struct LayerWeights {
+  q_proj: QMatMul,
+  k_proj: QMatMul,
+  v_proj: QMatMul,
+  o_proj: QMatMul,
+  input_layernorm: RmsNorm,
+  post_attention_layernorm: RmsNorm,
+  mlp: Mlp,
+  n_head: usize,
+  n_kv_head: usize,
+  head_dim: usize,
+  cos: Tensor,
+  sin: Tensor,
+  neg_inf: Tensor,
+  kv_cache: Option<(Tensor, Tensor)>,
+}
+
+impl LayerWeights {
+  fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+    let (_b_sz, _n_head, seq_len, _head_dim) = x.dims4()?;
+    let cos = self.cos.narrow(0, index_pos, seq_len)?;
+    let sin = self.sin.narrow(0, index_pos, seq_len)?;
+    // Gemma rotates halves, llama.cpp leaves its q and k weights unpermuted
+    candle_nn::rotary_emb::rope(&x.contiguous()?, &cos, &sin)
+  }
+
+  fn attention(&mut self, x: &Tensor, index_pos: usize, mask: Option<&Tensor>) -> Result<Tensor> {
+    let (b_sz, seq_len, _) = x.dims3()?;
+
+    let q = self
+      .q_proj
+      .forward(x)?
+      .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+      .transpose(1, 2)?;
+    let k = self
+      .k_proj
+      .forward(x)?
+      .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+      .transpose(1, 2)?;
+    let v = self
+      .v_proj
+      .forward(x)?
+      .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+      .transpose(1, 2)?
+      .contiguous()?;
+
+    let q = self.apply_rotary_emb(&q, index_pos)?;
+    let k = self.apply_rotary_emb(&k, index_pos)?;
+
+    let (k, v) = match self.kv_cache.as_ref() {
+      Some((k_cache, v_cache)) if index_pos > 0 => (
+        Tensor::cat(&[k_cache, &k], 2)?,
+        Tensor::cat(&[v_cache, &v], 2)?,
+      ),
+      _ => (k, v),
+    };
+    self.kv_cache = Some((k.clone(), v.clone()));
+
+    let k = repeat_kv(k, self.n_head / self.n_kv_head)?;
+    let v = repeat_kv(v, self.n_head / self.n_kv_head)?;
+
+    let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
+    let att = match mask {
+      None => att,
+      Some(mask) => {
+        let mask = mask.broadcast_as(att.shape())?;
+        mask.where_cond(&self.neg_inf.broadcast_as(att.shape().dims())?, &att)?
+      }
+    };
+    let att = candle_nn::ops::softmax_last_dim(&att)?;
+    let y = att.matmul(&v.contiguous()?)?;
+
+    let y = y
+      .transpose(1, 2)?
+      .reshape((b_sz, seq_len, self.n_head * self.head_dim))?;
+    self.o_proj.forward(&y)
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct ModelWeights {
+  embed_tokens: Embedding,
+  hidden_size: usize,
+  layers: Vec<LayerWeights>,
+  norm: RmsNorm,
+  lm_head: QMatMul,
+}
+
+fn precompute_freqs_cis(
+  head_dim: usize,
+  freq_base: f32,
+  max_seq_len: usize,
+  device: &Device,
+) -> Result<(Tensor, Tensor)> {
+  let theta: Vec<_> = (0..head_dim)
+    .step_by(2)
+    .map(|i| 1f32 / freq_base.powf(i as f32 / head_dim as f32))
+    .collect();
+  let theta = Tensor::new(theta.as_slice(), device)?;
+  let idx_theta = Tensor::arange(0, max_seq_len as u32, device)?
+    .to_dtype(DType::F32)?
+    .reshape((max_seq_len, 1))?
+    .matmul(&theta.reshape((1, theta.elem_count()))?)?;
+  Ok((idx_theta.cos()?, idx_theta.sin()?))
+}
+
+impl ModelWeights {
+  pub fn from_gguf<R: std::io::Seek + std::io::Read>(
+    ct: gguf_file::Content,
+    reader: &mut R,
+    device: &Device,
+  ) -> Result<Self> {
+    let md_get = |s: &str| match ct.metadata.get(s) {
+      None => candle_core::bail!("cannot find {s} in metadata"),
+      Some(v) => Ok(v),
+    };
+
+    let head_count = md_get("gemma.attention.head_count")?.to_u32()? as usize;
+    let head_count_kv = md_get("gemma.attention.head_count_kv")?.to_u32()? as usize;
+    let head_dim = md_get("gemma.attention.key_length")?.to_u32()? as usize;
+    let block_count = md_get("gemma.block_count")?.to_u32()? as usize;
+    let embedding_length = md_get("gemma.embedding_length")?.to_u32()? as usize;
+    let context_length = md_get("gemma.context_length")?.to_u32()? as usize;
+    let rms_norm_eps = md_get("gemma.attention.layer_norm_rms_epsilon")?.to_f32()? as f64;
+
+    let rope_freq_base = md_get("gemma.rope.freq_base")
+      .and_then(|m| m.to_f32())
+      .unwrap_or(10000f32);
+    let (cos, sin) = precompute_freqs_cis(head_dim, rope_freq_base, context_length, device)?;
+    let neg_inf = Tensor::new(f32::NEG_INFINITY, device)?;
+
+    // The 256k vocabulary makes the embeddings large, they are looked up in F16 and the output
+    // projection tied to them stays quantized
+    let embed_tokens_q = ct.tensor(reader, "token_embd.weight", device)?;
+    let embed_tokens = embed_tokens_q.dequantize(device)?.to_dtype(DType::F16)?;
+    let norm = RmsNorm::from_qtensor(
+      ct.tensor(reader, "output_norm.weight", device)?,
+      rms_norm_eps,
+    )?;
+    let lm_head = match ct.tensor(reader, "output.weight", device) {
+      Ok(tensor) => tensor,
+      Err(_) => embed_tokens_q,
+    };
+
+    let mut layers = Vec::with_capacity(block_count);
+    for layer_idx in 0..block_count {
+      let prefix = format!("blk.{layer_idx}");
+      let q_proj = ct.tensor(reader, &format!("{prefix}.attn_q.weight"), device)?;
+      let k_proj = ct.tensor(reader, &format!("{prefix}.attn_k.weight"), device)?;
+      let v_proj = ct.tensor(reader, &format!("{prefix}.attn_v.weight"), device)?;
+      let o_proj = ct.tensor(reader, &format!("{prefix}.attn_output.weight"), device)?;
+      let gate_proj = ct.tensor(reader, &format!("{prefix}.ffn_gate.weight"), device)?;
+      let up_proj = ct.tensor(reader, &format!("{prefix}.ffn_up.weight"), device)?;
+      let down_proj = ct.tensor(reader, &format!("{prefix}.ffn_down.weight"), device)?;
+      let input_layernorm = ct.tensor(reader, &format!("{prefix}.attn_norm.weight"), device)?;
+      let post_attention_layernorm =
+        ct.tensor(reader, &format!("{prefix}.ffn_norm.weight"), device)?;
+      layers.push(LayerWeights {
+        q_proj: QMatMul::from_qtensor(q_proj)?,
+        k_proj: QMatMul::from_qtensor(k_proj)?,
+        v_proj: QMatMul::from_qtensor(v_proj)?,
+        o_proj: QMatMul::from_qtensor(o_proj)?,
+        input_layernorm: RmsNorm::from_qtensor(input_layernorm, rms_norm_eps)?,
+        post_attention_layernorm: RmsNorm::from_qtensor(post_attention_layernorm, rms_norm_eps)?,
+        mlp: Mlp {
+          gate_proj: QMatMul::from_qtensor(gate_proj)?,
+          up_proj: QMatMul::from_qtensor(up_proj)?,
+          down_proj: QMatMul::from_qtensor(down_proj)?,
+        },
+        n_head: head_count,
+        n_kv_head: head_count_kv,
+        head_dim,
+        cos: cos.clone(),
+        sin: sin.clone(),
+        neg_inf: neg_inf.clone(),
+        kv_cache: None,
+      })
+    }
+
+    Ok(Self {
+      embed_tokens: Embedding::new(embed_tokens, embedding_length),
+      hidden_size: embedding_length,
+      layers,
+      norm,
+      lm_head: QMatMul::from_qtensor(lm_head)?,
+    })
+  }
+
+  /// Causal mask for `seq_len` new tokens following `index_pos` cached ones.
+  fn mask(&self, seq_len: usize, index_pos: usize, device: &Device) -> Result<Tensor> {
+    let mask: Vec<_> = (0..seq_len)
+      .flat_map(|i| (0..index_pos + seq_len).map(move |j| u8::from(j > index_pos + i)))
+      .collect();
+    Tensor::from_slice(&mask, (seq_len, index_pos + seq_len), device)
+  }
+
+  /// Runs `x` of shape `(batch, seq_len)` following `index_pos` cached positions, and returns the
+  /// logits of the last position. A sequence starting at position 0 replaces the cache.
+  pub fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+    let (_b_sz, seq_len) = x.dims2()?;
+    let mask = if seq_len == 1 {
+      None
+    } else {
+      Some(self.mask(seq_len, index_pos, x.device())?)
+    };
+
+    let mut xs = self
+      .embed_tokens
+      .forward(x)?
+      .to_dtype(DType::F32)?
+      .affine((self.hidden_size as f64).sqrt(), 0.)?;
+    for layer in self.layers.iter_mut() {
+      let residual = &xs;
+      let h = layer.input_layernorm.forward(&xs)?;
+      let h = layer.attention(&h, index_pos, mask.as_ref())?;
+      let h = (h + residual)?;
+
+      let residual = &h;
+      let out = layer.post_attention_layernorm.forward(&h)?;
+      let out = layer.mlp.forward(&out)?;
+      xs = (out + residual)?;
+    }
+
+    let xs = self.norm.forward(&xs)?;
+    let xs = xs.i((.., seq_len - 1, ..))?;
+    self.lm_head.forward(&xs)
+  }
+}
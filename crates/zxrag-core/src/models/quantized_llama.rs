@@ -0,0 +1,397 @@
+//! Quantized llama adapted from `candle_transformers::models::quantized_llama` so that the KV cache
+//! lives outside the weights. Several sequences can then share one set of weights and be decoded
+//! together in a single batched forward pass, each at its own position.
+
+use candle_core::quantized::{ggml_file, gguf_file, QMatMul, QTensor};
+use candle_core::{DType, Device, IndexOp, Module, Result, Tensor};
+use candle_nn::Embedding;
+use candle_transformers::quantized_nn::RmsNorm;
+use candle_transformers::utils::repeat_kv;
+
+use crate::types::llm::MAX_SEQ_LEN;
+
+#[derive(Debug, Clone)]
+struct Mlp {
+  feed_forward_w1: QMatMul,
+  feed_forward_w2: QMatMul,
+  feed_forward_w3: QMatMul,
+}
+
+impl Module for Mlp {
+  fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+    let w1 = self.feed_forward_w1.forward(xs)?;
+    let w3 = self.feed_forward_w3.forward(xs)?;
+    self
+      .feed_forward_w2
+      .forward(&(candle_nn::ops::silu(&w1)? * w3)?)
+  }
+}
+
+#[derive(Debug, Clone)]
+struct LayerWeights {
+  attention_wq: QMatMul,
+  attention_wk: QMatMul,
+  attention_wv: QMatMul,
+  attention_wo: QMatMul,
+  attention_norm: RmsNorm,
+  mlp: Mlp,
+  ffn_norm: RmsNorm,
+  n_head: usize,
+  n_kv_head: usize,
+  head_dim: usize,
+  cos: Tensor,
+  sin: Tensor,
+  neg_inf: Tensor,
+}
+
+/// Keys and values of every layer for one sequence.
+#[derive(Debug, Clone, Default)]
+pub struct KvCache {
+  layers: Vec<Option<(Tensor, Tensor)>>,
+  len: usize,
+}
+
+impl KvCache {
+  /// Number of positions already in the cache, which is also the position of the next token.
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  pub fn clear(&mut self) {
+    self.layers.clear();
+    self.len = 0;
+  }
+}
+
+fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: &Tensor) -> Result<Tensor> {
+  let shape = mask.shape();
+  let m = mask.where_cond(&on_true.broadcast_as(shape.dims())?, on_false)?;
+  Ok(m)
+}
+
+impl LayerWeights {
+  fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+    let (_b_sz, _n_head, seq_len, _n_embd) = x.dims4()?;
+    let cos = self.cos.narrow(0, index_pos, seq_len)?;
+    let sin = self.sin.narrow(0, index_pos, seq_len)?;
+    candle_nn::rotary_emb::rope_i(&x.contiguous()?, &cos, &sin)
+  }
+
+  /// Attention for a single sequence. `q`, `k` and `v` are the projections of shape
+  /// `(1, seq_len, _)` and `kv_cache` is this layer's cache for that sequence.
+  fn attention(
+    &self,
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    index_pos: usize,
+    kv_cache: &mut Option<(Tensor, Tensor)>,
+    mask: Option<&Tensor>,
+  ) -> Result<Tensor> {
+    let (b_sz, seq_len, _) = q.dims3()?;
+
+    let q = q
+      .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+      .transpose(1, 2)?;
+    let k = k
+      .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+      .transpose(1, 2)?;
+    let v = v
+      .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+      .transpose(1, 2)?
+      .contiguous()?;
+
+    let q = self.apply_rotary_emb(&q, index_pos)?;
+    let k = self.apply_rotary_emb(&k, index_pos)?;
+
+    let (k, v) = match kv_cache.as_ref() {
+      Some((k_cache, v_cache)) if index_pos > 0 => (
+        Tensor::cat(&[k_cache, &k], 2)?,
+        Tensor::cat(&[v_cache, &v], 2)?,
+      ),
+      _ => (k, v),
+    };
+    *kv_cache = Some((k.clone(), v.clone()));
+
+    // Support for MQA, useful for 70B models and mistral.
+    let k = repeat_kv(k, self.n_head / self.n_kv_head)?;
+    let v = repeat_kv(v, self.n_head / self.n_kv_head)?;
+
+    let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
+    let att = match mask {
+      None => att,
+      Some(mask) => {
+        let mask = mask.broadcast_as(att.shape())?;
+        masked_fill(&att, &mask, &self.neg_inf)?
+      }
+    };
+    let att = candle_nn::ops::softmax_last_dim(&att)?;
+    let y = att.matmul(&v.contiguous()?)?;
+
+    y.transpose(1, 2)?
+      .reshape((b_sz, seq_len, self.n_head * self.head_dim))
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct ModelWeights {
+  tok_embeddings: Embedding,
+  layers: Vec<LayerWeights>,
+  norm: RmsNorm,
+  output: QMatMul,
+}
+
+fn precomput_freqs_cis(
+  head_dim: usize,
+  freq_base: f32,
+  device: &Device,
+) -> Result<(Tensor, Tensor)> {
+  let theta: Vec<_> = (0..head_dim)
+    .step_by(2)
+    .map(|i| 1f32 / freq_base.powf(i as f32 / head_dim as f32))
+    .collect();
+  let theta = Tensor::new(theta.as_slice(), device)?;
+  let idx_theta = Tensor::arange(0, MAX_SEQ_LEN as u32, device)?
+    .to_dtype(DType::F32)?
+    .reshape((MAX_SEQ_LEN, 1))?
+    .matmul(&theta.reshape((1, theta.elem_count()))?)?;
+  let cos = idx_theta.cos()?;
+  let sin = idx_theta.sin()?;
+  Ok((cos, sin))
+}
+
+impl ModelWeights {
+  pub fn from_ggml(mut ct: ggml_file::Content, gqa: usize) -> Result<Self> {
+    let head_dim = (ct.hparams.n_embd / ct.hparams.n_head) as usize;
+    let (cos, sin) = precomput_freqs_cis(head_dim, 10000., &ct.device)?;
+    let neg_inf = Tensor::new(f32::NEG_INFINITY, &ct.device)?;
+    let tok_embeddings = ct.remove("tok_embeddings.weight")?;
+    let tok_embeddings = tok_embeddings.dequantize(&ct.device)?;
+    let norm = RmsNorm::from_qtensor(ct.remove("norm.weight")?, 1e-5)?;
+    let output = ct.remove("output.weight")?;
+    let mut layers = Vec::with_capacity(ct.hparams.n_layer as usize);
+    for layer_idx in 0..ct.hparams.n_layer {
+      let prefix = format!("layers.{layer_idx}");
+      let attention_wq = ct.remove(&format!("{prefix}.attention.wq.weight"))?;
+      let attention_wk = ct.remove(&format!("{prefix}.attention.wk.weight"))?;
+      let attention_wv = ct.remove(&format!("{prefix}.attention.wv.weight"))?;
+      let attention_wo = ct.remove(&format!("{prefix}.attention.wo.weight"))?;
+      let feed_forward_w1 = ct.remove(&format!("{prefix}.feed_forward.w1.weight"))?;
+      let feed_forward_w2 = ct.remove(&format!("{prefix}.feed_forward.w2.weight"))?;
+      let feed_forward_w3 = ct.remove(&format!("{prefix}.feed_forward.w3.weight"))?;
+      let attention_norm = ct.remove(&format!("{prefix}.attention_norm.weight"))?;
+      let ffn_norm = ct.remove(&format!("{prefix}.ffn_norm.weight"))?;
+      layers.push(LayerWeights {
+        attention_wq: QMatMul::from_qtensor(attention_wq)?,
+        attention_wk: QMatMul::from_qtensor(attention_wk)?,
+        attention_wv: QMatMul::from_qtensor(attention_wv)?,
+        attention_wo: QMatMul::from_qtensor(attention_wo)?,
+        attention_norm: RmsNorm::from_qtensor(attention_norm, 1e-5)?,
+        mlp: Mlp {
+          feed_forward_w1: QMatMul::from_qtensor(feed_forward_w1)?,
+          feed_forward_w2: QMatMul::from_qtensor(feed_forward_w2)?,
+          feed_forward_w3: QMatMul::from_qtensor(feed_forward_w3)?,
+        },
+        ffn_norm: RmsNorm::from_qtensor(ffn_norm, 1e-5)?,
+        n_head: ct.hparams.n_head as usize,
+        n_kv_head: ct.hparams.n_head as usize / gqa,
+        head_dim,
+        cos: cos.clone(),
+        sin: sin.clone(),
+        neg_inf: neg_inf.clone(),
+      })
+    }
+    Ok(Self {
+      tok_embeddings: Embedding::new(tok_embeddings, ct.hparams.n_embd as usize),
+      layers,
+      norm,
+      output: QMatMul::from_qtensor(output)?,
+    })
+  }
+
+  pub fn from_gguf<R: std::io::Seek + std::io::Read>(
+    ct: gguf_file::Content,
+    reader: &mut R,
+    device: &Device,
+  ) -> Result<Self> {
+    let md_get = |s: &str| match ct.metadata.get(s) {
+      None => candle_core::bail!("cannot find {s} in metadata"),
+      Some(v) => Ok(v),
+    };
+
+    let n_expert = md_get("llama.expert_count")
+      .and_then(|v| v.to_u32())
+      .unwrap_or(0) as usize;
+    if n_expert > 1 {
+      candle_core::bail!("mixture of experts models are not supported");
+    }
+
+    let head_count = md_get("llama.attention.head_count")?.to_u32()? as usize;
+    let head_count_kv = md_get("llama.attention.head_count_kv")?.to_u32()? as usize;
+    let block_count = md_get("llama.block_count")?.to_u32()? as usize;
+    let embedding_length = md_get("llama.embedding_length")?.to_u32()? as usize;
+    let rope_dim = md_get("llama.rope.dimension_count")?.to_u32()? as usize;
+    let rms_norm_eps = md_get("llama.attention.layer_norm_rms_epsilon")?.to_f32()? as f64;
+
+    let rope_freq_base = md_get("llama.rope.freq_base")
+      .and_then(|m| m.to_f32())
+      .unwrap_or(10000f32);
+    let (cos, sin) = precomput_freqs_cis(rope_dim, rope_freq_base, device)?;
+    let neg_inf = Tensor::new(f32::NEG_INFINITY, device)?;
+
+    let tok_embeddings_q = ct.tensor(reader, "token_embd.weight", device)?;
+    let tok_embeddings = tok_embeddings_q.dequantize(device)?;
+    let norm = RmsNorm::from_qtensor(
+      ct.tensor(reader, "output_norm.weight", device)?,
+      rms_norm_eps,
+    )?;
+    let output: QTensor = match ct.tensor(reader, "output.weight", device) {
+      Ok(tensor) => tensor,
+      Err(_) => tok_embeddings_q,
+    };
+    let mut layers = Vec::with_capacity(block_count);
+    for layer_idx in 0..block_count {
+      let prefix = format!("blk.{layer_idx}");
+      let attention_wq = ct.tensor(reader, &format!("{prefix}.attn_q.weight"), device)?;
+      let attention_wk = ct.tensor(reader, &format!("{prefix}.attn_k.weight"), device)?;
+      let attention_wv = ct.tensor(reader, &format!("{prefix}.attn_v.weight"), device)?;
+      let attention_wo = ct.tensor(reader, &format!("{prefix}.attn_output.weight"), device)?;
+      let feed_forward_w1 = ct.tensor(reader, &format!("{prefix}.ffn_gate.weight"), device)?;
+      let feed_forward_w2 = ct.tensor(reader, &format!("{prefix}.ffn_down.weight"), device)?;
+      let feed_forward_w3 = ct.tensor(reader, &format!("{prefix}.ffn_up.weight"), device)?;
+      let attention_norm = ct.tensor(reader, &format!("{prefix}.attn_norm.weight"), device)?;
+      let ffn_norm = ct.tensor(reader, &format!("{prefix}.ffn_norm.weight"), device)?;
+      layers.push(LayerWeights {
+        attention_wq: QMatMul::from_qtensor(attention_wq)?,
+        attention_wk: QMatMul::from_qtensor(attention_wk)?,
+        attention_wv: QMatMul::from_qtensor(attention_wv)?,
+        attention_wo: QMatMul::from_qtensor(attention_wo)?,
+        attention_norm: RmsNorm::from_qtensor(attention_norm, rms_norm_eps)?,
+        mlp: Mlp {
+          feed_forward_w1: QMatMul::from_qtensor(feed_forward_w1)?,
+          feed_forward_w2: QMatMul::from_qtensor(feed_forward_w2)?,
+          feed_forward_w3: QMatMul::from_qtensor(feed_forward_w3)?,
+        },
+        ffn_norm: RmsNorm::from_qtensor(ffn_norm, rms_norm_eps)?,
+        n_head: head_count,
+        n_kv_head: head_count_kv,
+        head_dim: embedding_length / head_count,
+        cos: cos.clone(),
+        sin: sin.clone(),
+        neg_inf: neg_inf.clone(),
+      })
+    }
+    Ok(Self {
+      tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+      layers,
+      norm,
+      output: QMatMul::from_qtensor(output)?,
+    })
+  }
+
+  /// Causal mask for `seq_len` new tokens following `index_pos` cached ones.
+  fn mask(&self, seq_len: usize, index_pos: usize, device: &Device) -> Result<Tensor> {
+    let mask: Vec<_> = (0..seq_len)
+      .flat_map(|i| (0..index_pos + seq_len).map(move |j| u8::from(j > index_pos + i)))
+      .collect();
+    Tensor::from_slice(&mask, (seq_len, index_pos + seq_len), device)
+  }
+
+  /// Runs `x` of shape `(1, seq_len)` for one sequence, continuing from `cache`, and returns the
+  /// logits of the last position.
+  pub fn forward(&self, x: &Tensor, cache: &mut KvCache) -> Result<Tensor> {
+    let (_b_sz, seq_len) = x.dims2()?;
+    let index_pos = cache.len;
+    let mask = if seq_len == 1 {
+      None
+    } else {
+      Some(self.mask(seq_len, index_pos, x.device())?)
+    };
+    cache.layers.resize(self.layers.len(), None);
+
+    let mut layer_in = self.tok_embeddings.forward(x)?;
+    for (layer, kv_cache) in self.layers.iter().zip(cache.layers.iter_mut()) {
+      let x = layer_in;
+      let residual = &x;
+      let x = layer.attention_norm.forward(&x)?;
+      let q = layer.attention_wq.forward(&x)?;
+      let k = layer.attention_wk.forward(&x)?;
+      let v = layer.attention_wv.forward(&x)?;
+      let attn = layer.attention(&q, &k, &v, index_pos, kv_cache, mask.as_ref())?;
+      let attn = layer.attention_wo.forward(&attn)?;
+      let x = (attn + residual)?;
+
+      let residual = &x;
+      let x = layer.ffn_norm.forward(&x)?;
+      let x = layer.mlp.forward(&x)?;
+      layer_in = (x + residual)?;
+    }
+    cache.len += seq_len;
+
+    let x = self.norm.forward(&layer_in)?;
+    let x = x.i((.., seq_len - 1, ..))?;
+    self.output.forward(&x)
+  }
+
+  /// Decodes one token per sequence in a single pass, `tokens[i]` continuing from `caches[i]`.
+  /// The projections and the MLP run on the whole batch, only attention is computed per sequence
+  /// since every sequence sits at a different position. Returns logits of shape `(batch, vocab)`.
+  pub fn forward_batch(&self, tokens: &[u32], caches: &mut [&mut KvCache]) -> Result<Tensor> {
+    if tokens.len() != caches.len() {
+      candle_core::bail!(
+        "{} tokens for {} kv caches in batched forward",
+        tokens.len(),
+        caches.len()
+      );
+    }
+
+    let positions: Vec<usize> = caches.iter().map(|cache| cache.len).collect();
+    for cache in caches.iter_mut() {
+      cache.layers.resize(self.layers.len(), None);
+    }
+
+    let x = Tensor::new(tokens, self.tok_embeddings.embeddings().device())?.unsqueeze(1)?;
+
+    let mut layer_in = self.tok_embeddings.forward(&x)?;
+    for (layer_idx, layer) in self.layers.iter().enumerate() {
+      let x = layer_in;
+      let residual = &x;
+      let x = layer.attention_norm.forward(&x)?;
+      let q = layer.attention_wq.forward(&x)?;
+      let k = layer.attention_wk.forward(&x)?;
+      let v = layer.attention_wv.forward(&x)?;
+      let attn = caches
+        .iter_mut()
+        .enumerate()
+        .map(|(i, cache)| {
+          layer.attention(
+            &q.i(i..i + 1)?,
+            &k.i(i..i + 1)?,
+            &v.i(i..i + 1)?,
+            positions[i],
+            &mut cache.layers[layer_idx],
+            None,
+          )
+        })
+        .collect::<Result<Vec<_>>>()?;
+      let attn = layer.attention_wo.forward(&Tensor::cat(&attn, 0)?)?;
+      let x = (attn + residual)?;
+
+      let residual = &x;
+      let x = layer.ffn_norm.forward(&x)?;
+      let x = layer.mlp.forward(&x)?;
+      layer_in = (x + residual)?;
+    }
+    for cache in caches.iter_mut() {
+      cache.len += 1;
+    }
+
+    let x = self.norm.forward(&layer_in)?;
+    let x = x.i((.., 0, ..))?;
+    self.output.forward(&x)
+  }
+}
@@ -0,0 +1,158 @@
+use candle_core::{DType, Device, IndexOp, Tensor};
+use candle_nn::{ops::sigmoid, VarBuilder};
+use candle_transformers::models::xlm_roberta::{Config, XLMRobertaForSequenceClassification};
+use std::path::PathBuf;
+use tokenizers::{PaddingParams, PaddingStrategy, Tokenizer, TruncationParams};
+
+use crate::types::{
+  conf::RerankerConf,
+  model::{ModelEngine, ModelId},
+};
+use crate::util::candle_device;
+
+const RERANK_BATCH_SIZE: usize = 16;
+
+/// Cross-encoder scoring query/document pairs, such as the bge-reranker family.
+pub struct Model {
+  id: ModelId,
+  engine: ModelEngine,
+  device: Device,
+  model: XLMRobertaForSequenceClassification,
+  tokenizer: Tokenizer,
+}
+
+impl Model {
+  pub fn new(conf: &RerankerConf) -> anyhow::Result<Self> {
+    let device = candle_device(&conf.device);
+
+    let model_path = PathBuf::from(&conf.model_path);
+
+    let config_filename = model_path.join("config.json");
+    let tokenizer_filename = if conf.tokenizer_path.is_empty() {
+      model_path.join("tokenizer.json")
+    } else {
+      PathBuf::from(&conf.tokenizer_path)
+    };
+    let weights_filename = model_path.join("model.safetensors");
+
+    let config = std::fs::read_to_string(config_filename)?;
+    let config: Config = serde_json::from_str(&config)?;
+
+    let mut tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(anyhow::Error::msg)?;
+
+    let pad_id = config.pad_token_id;
+
+    let pad_token = tokenizer
+      .id_to_token(pad_id)
+      .unwrap_or_else(|| "<pad>".to_string());
+
+    // Positions start after the padding index, which takes two of the position embeddings
+    let max_length = config
+      .max_position_embeddings
+      .saturating_sub(pad_id as usize + 1);
+
+    tokenizer
+      .with_padding(Some(PaddingParams {
+        strategy: PaddingStrategy::BatchLongest,
+        pad_id,
+        pad_token,
+        ..Default::default()
+      }))
+      .with_truncation(Some(TruncationParams {
+        max_length,
+        ..Default::default()
+      }))
+      .map_err(anyhow::Error::msg)?;
+
+    let vb =
+      unsafe { VarBuilder::from_mmaped_safetensors(&[weights_filename], DType::F32, &device)? };
+
+    let model = XLMRobertaForSequenceClassification::new(1, &config, vb)?;
+
+    Ok(Self {
+      id: conf.model_id,
+      engine: conf.model_engine,
+      device,
+      model,
+      tokenizer,
+    })
+  }
+
+  pub fn id(&self) -> ModelId {
+    self.id
+  }
+
+  /// Counts the tokens the model sees for every `(query, document)` pair, without padding.
+  pub fn count_tokens(&self, query: &str, documents: &[&str]) -> anyhow::Result<usize> {
+    let pairs: Vec<(&str, &str)> = documents
+      .iter()
+      .map(|document| (query, *document))
+      .collect();
+
+    let tokens = self
+      .tokenizer
+      .encode_batch(pairs, true)
+      .map_err(anyhow::Error::msg)?;
+
+    Ok(
+      tokens
+        .iter()
+        .map(|tokens| tokens.get_attention_mask().iter().sum::<u32>() as usize)
+        .sum(),
+    )
+  }
+
+  /// Relevance of each document to `query` in `[0, 1]`, in the order of `documents`.
+  pub fn rerank(&self, query: &str, documents: &[&str]) -> anyhow::Result<Vec<f32>> {
+    tracing::info!("id={}", self.id);
+    tracing::info!("engine={}", self.engine);
+
+    let start = std::time::Instant::now();
+
+    let mut scores = Vec::with_capacity(documents.len());
+
+    for batch in documents.chunks(RERANK_BATCH_SIZE) {
+      scores.extend(self.rerank_batch(query, batch)?);
+    }
+
+    tracing::info!(
+      "reranked {} documents, took {:?}",
+      documents.len(),
+      start.elapsed()
+    );
+
+    Ok(scores)
+  }
+
+  fn rerank_batch(&self, query: &str, documents: &[&str]) -> anyhow::Result<Vec<f32>> {
+    let pairs: Vec<(&str, &str)> = documents
+      .iter()
+      .map(|document| (query, *document))
+      .collect();
+
+    let tokens = self
+      .tokenizer
+      .encode_batch(pairs, true)
+      .map_err(anyhow::Error::msg)?;
+
+    let token_ids = tokens
+      .iter()
+      .map(|tokens| Ok(Tensor::new(tokens.get_ids(), &self.device)?))
+      .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let attention_mask = tokens
+      .iter()
+      .map(|tokens| Ok(Tensor::new(tokens.get_attention_mask(), &self.device)?))
+      .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let token_ids = Tensor::stack(&token_ids, 0)?;
+    let attention_mask = Tensor::stack(&attention_mask, 0)?;
+    let token_type_ids = token_ids.zeros_like()?;
+
+    let logits = self
+      .model
+      .forward(&token_ids, &attention_mask, &token_type_ids)?;
+
+    Ok(sigmoid(&logits.i((.., 0))?)?.to_vec1()?)
+  }
+}
@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+
+use crate::retriever::{RetrievalFilter, RetrievedChunk};
+
+const K1: f32 = 1.2;
+const B: f32 = 0.75;
+
+/// Characters kept inside a term when they join alphanumerics, so identifiers such as `ERR-404`,
+/// `v1.2.3` or `snake_case` can be matched exactly.
+pub(crate) const JOINERS: [char; 5] = ['_', '-', '.', ':', '/'];
+
+/// In-memory BM25 inverted index over the `text` column of a knowledge base table.
+pub struct Bm25Index {
+  chunks: Vec<RetrievedChunk>,
+  doc_lens: Vec<u32>,
+  postings: HashMap<String, Vec<(u32, u32)>>,
+  avg_doc_len: f32,
+}
+
+impl Bm25Index {
+  pub fn new(chunks: Vec<RetrievedChunk>) -> Self {
+    let mut postings: HashMap<String, Vec<(u32, u32)>> = HashMap::new();
+    let mut doc_lens = Vec::with_capacity(chunks.len());
+
+    for (doc, chunk) in chunks.iter().enumerate() {
+      let terms = tokenize(&chunk.text);
+
+      doc_lens.push(terms.len() as u32);
+
+      let mut freqs: HashMap<String, u32> = HashMap::new();
+
+      for term in terms {
+        *freqs.entry(term).or_default() += 1;
+      }
+
+      for (term, freq) in freqs {
+        postings.entry(term).or_default().push((doc as u32, freq));
+      }
+    }
+
+    let avg_doc_len = if doc_lens.is_empty() {
+      0.0
+    } else {
+      doc_lens.iter().sum::<u32>() as f32 / doc_lens.len() as f32
+    };
+
+    Self {
+      chunks,
+      doc_lens,
+      postings,
+      avg_doc_len,
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.chunks.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.chunks.is_empty()
+  }
+
+  /// Returns up to `limit` chunks matching `filter` and sharing at least one term with `query`,
+  /// best match first.
+  pub fn search(&self, query: &str, limit: usize, filter: &RetrievalFilter) -> Vec<RetrievedChunk> {
+    let doc_count = self.chunks.len() as f32;
+
+    let mut terms = tokenize(query);
+    terms.sort_unstable();
+    terms.dedup();
+
+    let mut scores: HashMap<u32, f32> = HashMap::new();
+
+    for term in &terms {
+      let Some(postings) = self.postings.get(term) else {
+        continue;
+      };
+
+      let doc_freq = postings.len() as f32;
+      let idf = ((doc_count - doc_freq + 0.5) / (doc_freq + 0.5) + 1.0).ln();
+
+      for &(doc, freq) in postings {
+        if !filter.matches(&self.chunks[doc as usize]) {
+          continue;
+        }
+
+        let freq = freq as f32;
+        let doc_len = self.doc_lens[doc as usize] as f32;
+        let norm = K1 * (1.0 - B + B * doc_len / self.avg_doc_len.max(1.0));
+
+        *scores.entry(doc).or_default() += idf * freq * (K1 + 1.0) / (freq + norm);
+      }
+    }
+
+    let mut ranked: Vec<(u32, f32)> = scores.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    ranked.truncate(limit);
+
+    ranked
+      .into_iter()
+      .map(|(doc, score)| RetrievedChunk {
+        score,
+        ..self.chunks[doc as usize].clone()
+      })
+      .collect()
+  }
+}
+
+/// Lowercased terms of `text`. Words joined by [`JOINERS`] are kept whole and also split into
+/// their parts. CJK text has no word boundaries, so it is indexed as characters and bigrams.
+pub fn tokenize(text: &str) -> Vec<String> {
+  let mut terms = vec![];
+
+  for word in text.split(|c: char| !(c.is_alphanumeric() || JOINERS.contains(&c))) {
+    let word = word.trim_matches(|c: char| JOINERS.contains(&c));
+
+    if word.is_empty() {
+      continue;
+    }
+
+    let parts: Vec<&str> = word
+      .split(|c: char| JOINERS.contains(&c))
+      .filter(|part| !part.is_empty())
+      .collect();
+
+    if parts.len() > 1 {
+      terms.push(word.to_lowercase());
+    }
+
+    for part in parts {
+      push_part_terms(part, &mut terms);
+    }
+  }
+
+  terms
+}
+
+fn push_part_terms(part: &str, terms: &mut Vec<String>) {
+  let mut word = String::new();
+  let mut previous_cjk: Option<char> = None;
+
+  for c in part.chars() {
+    if is_cjk(c) {
+      if !word.is_empty() {
+        terms.push(std::mem::take(&mut word).to_lowercase());
+      }
+
+      terms.push(c.to_string());
+
+      if let Some(previous) = previous_cjk {
+        terms.push(format!("{previous}{c}"));
+      }
+
+      previous_cjk = Some(c);
+    } else {
+      word.push(c);
+      previous_cjk = None;
+    }
+  }
+
+  if !word.is_empty() {
+    terms.push(word.to_lowercase());
+  }
+}
+
+pub(crate) fn is_cjk(c: char) -> bool {
+  matches!(c,
+    '\u{3040}'..='\u{30ff}'
+    | '\u{3400}'..='\u{4dbf}'
+    | '\u{4e00}'..='\u{9fff}'
+    | '\u{ac00}'..='\u{d7af}'
+    | '\u{f900}'..='\u{faff}'
+    | '\u{20000}'..='\u{2fa1f}'
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn chunk(id: &str, file_id: i64, text: &str) -> RetrievedChunk {
+    RetrievedChunk {
+      id: id.to_string(),
+      file_id,
+      text: text.to_string(),
+      ..Default::default()
+    }
+  }
+
+  fn ids(chunks: &[RetrievedChunk]) -> Vec<&str> {
+    chunks.iter().map(|chunk| chunk.id.as_str()).collect()
+  }
+
+  #[test]
+  fn identifiers_are_kept_whole_and_split() {
+    assert_eq!(
+      tokenize("Got ERR-404 from v1.2, see snake_case."),
+      vec![
+        "got",
+        "err-404",
+        "err",
+        "404",
+        "from",
+        "v1.2",
+        "v1",
+        "2",
+        "see",
+        "snake_case",
+        "snake",
+        "case"
+      ]
+    );
+  }
+
+  #[test]
+  fn cjk_text_is_indexed_as_characters_and_bigrams() {
+    assert_eq!(tokenize("检索abc"), vec!["检", "索", "检索", "abc"]);
+  }
+
+  #[test]
+  fn exact_identifiers_rank_first() {
+    let index = Bm25Index::new(vec![
+      chunk("a", 1, "the server returned an error"),
+      chunk("b", 1, "the server returned ERR-404 for the request"),
+      chunk("c", 1, "nothing in common"),
+    ]);
+
+    let hits = index.search("ERR-404 error", 10, &RetrievalFilter::default());
+
+    assert_eq!(ids(&hits), vec!["b", "a"]);
+    assert!(hits[0].score > hits[1].score);
+  }
+
+  #[test]
+  fn search_is_limited_and_filtered() {
+    let index = Bm25Index::new(vec![
+      chunk("a", 1, "install the package"),
+      chunk("b", 2, "install the package again"),
+      chunk("c", 2, "install it"),
+    ]);
+
+    let filter = RetrievalFilter {
+      file_ids: vec![2],
+      ..Default::default()
+    };
+
+    assert_eq!(index.search("install", 1, &filter).len(), 1);
+    assert!(index
+      .search("install", 10, &filter)
+      .iter()
+      .all(|hit| hit.file_id == 2));
+    assert!(index
+      .search("missing", 10, &RetrievalFilter::default())
+      .is_empty());
+  }
+}
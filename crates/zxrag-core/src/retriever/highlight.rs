@@ -0,0 +1,177 @@
+use std::collections::HashSet;
+
+use crate::retriever::bm25::{is_cjk, tokenize, JOINERS};
+
+pub const HIGHLIGHT_PRE_TAG: &str = "<em>";
+pub const HIGHLIGHT_POST_TAG: &str = "</em>";
+
+/// Context kept before the first match of a snippet, as a share of its length.
+const LEADING_CONTEXT: usize = 4;
+
+/// A snippet of at most `max_chars` characters of `text` around its densest run of query terms,
+/// with every term wrapped in [`HIGHLIGHT_PRE_TAG`] and [`HIGHLIGHT_POST_TAG`]. Terms are matched
+/// the way [`tokenize`] splits them, `None` when no term of `query` is in `text`.
+pub fn highlight(text: &str, query: &str, max_chars: usize) -> Option<String> {
+  let terms: HashSet<String> = tokenize(query).into_iter().collect();
+
+  let chars: Vec<(usize, char)> = text.char_indices().collect();
+
+  let matches = term_matches(&chars, &terms);
+
+  if matches.is_empty() || max_chars == 0 {
+    return None;
+  }
+
+  // The window starting at the match that has the most matches after it within `max_chars`
+  let (first, _) = matches
+    .iter()
+    .enumerate()
+    .map(|(i, &(start, _))| {
+      let covered = matches[i..]
+        .iter()
+        .take_while(|&&(_, end)| end <= start + max_chars)
+        .count();
+
+      (i, covered)
+    })
+    .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))?;
+
+  let mut start = matches[first].0.saturating_sub(max_chars / LEADING_CONTEXT);
+  let end = (start + max_chars).min(chars.len());
+  start = end.saturating_sub(max_chars).min(start);
+
+  let offset = |index: usize| chars.get(index).map_or(text.len(), |&(offset, _)| offset);
+
+  let mut snippet = String::new();
+
+  if start > 0 {
+    snippet.push('…');
+  }
+
+  let mut position = start;
+
+  for &(match_start, match_end) in matches
+    .iter()
+    .filter(|&&(match_start, match_end)| match_start >= start && match_end <= end)
+  {
+    snippet.push_str(&text[offset(position)..offset(match_start)]);
+    snippet.push_str(HIGHLIGHT_PRE_TAG);
+    snippet.push_str(&text[offset(match_start)..offset(match_end)]);
+    snippet.push_str(HIGHLIGHT_POST_TAG);
+
+    position = match_end;
+  }
+
+  snippet.push_str(&text[offset(position)..offset(end)]);
+
+  if end < chars.len() {
+    snippet.push('…');
+  }
+
+  Some(snippet)
+}
+
+/// Character ranges of the words of `chars` that are in `terms`. CJK characters are words of their
+/// own, as they are indexed one by one. Matches next to each other, or only apart by [`JOINERS`],
+/// are merged into one.
+fn term_matches(chars: &[(usize, char)], terms: &HashSet<String>) -> Vec<(usize, usize)> {
+  let mut matches = vec![];
+  let mut word = String::new();
+  let mut word_start = 0;
+
+  for (index, &(_, c)) in chars.iter().enumerate() {
+    if c.is_alphanumeric() && !is_cjk(c) {
+      if word.is_empty() {
+        word_start = index;
+      }
+
+      word.extend(c.to_lowercase());
+
+      continue;
+    }
+
+    if terms.contains(&word) {
+      push_match(chars, &mut matches, (word_start, index));
+    }
+
+    word.clear();
+
+    if is_cjk(c) && terms.contains(&c.to_string()) {
+      push_match(chars, &mut matches, (index, index + 1));
+    }
+  }
+
+  if terms.contains(&word) {
+    push_match(chars, &mut matches, (word_start, chars.len()));
+  }
+
+  matches
+}
+
+fn push_match(
+  chars: &[(usize, char)],
+  matches: &mut Vec<(usize, usize)>,
+  (start, end): (usize, usize),
+) {
+  if let Some(last) = matches.last_mut() {
+    if chars[last.1..start]
+      .iter()
+      .all(|(_, c)| JOINERS.contains(c))
+    {
+      last.1 = end;
+      return;
+    }
+  }
+
+  matches.push((start, end));
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn identifiers_are_highlighted_whole() {
+    assert_eq!(
+      highlight("The server returned ERR-404.", "err-404", 100).unwrap(),
+      "The server returned <em>ERR-404</em>."
+    );
+  }
+
+  #[test]
+  fn cjk_terms_are_highlighted() {
+    assert_eq!(
+      highlight("我们的检索系统", "检索", 100).unwrap(),
+      "我们的<em>检索</em>系统"
+    );
+  }
+
+  #[test]
+  fn texts_without_a_query_term_have_no_snippet() {
+    assert_eq!(highlight("nothing to see", "needle", 100), None);
+    assert_eq!(highlight("a needle", "needle", 0), None);
+  }
+
+  #[test]
+  fn snippets_are_cut_around_the_densest_matches() {
+    let text = format!(
+      "needle {} needle and needle {}",
+      "hay ".repeat(20),
+      "hay ".repeat(20)
+    );
+
+    let snippet = highlight(&text, "needle", 40).unwrap();
+
+    assert!(snippet.starts_with('…') && snippet.ends_with('…'));
+    assert_eq!(snippet.matches("<em>needle</em>").count(), 2);
+    assert_eq!(
+      snippet
+        .replace(HIGHLIGHT_PRE_TAG, "")
+        .replace(HIGHLIGHT_POST_TAG, "")
+        .trim_matches('…')
+        .chars()
+        .count(),
+      40
+    );
+  }
+}
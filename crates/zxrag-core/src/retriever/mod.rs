@@ -0,0 +1,330 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use strum::{Display, EnumString};
+use tokenizers::Tokenizer;
+
+pub mod bm25;
+pub mod highlight;
+pub mod rewrite;
+
+pub use bm25::Bm25Index;
+pub use highlight::highlight;
+pub use rewrite::{rewrite_query, QueryRewrite, RewrittenQuery};
+
+/// A chunk of a knowledge base table as returned by retrieval. `score` is the vector distance,
+/// the BM25 score, the fused RRF score or the reranker relevance depending on the last stage that
+/// ranked it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RetrievedChunk {
+  pub id: String,
+  pub kb_id: i64,
+  pub file_id: i64,
+  pub file_name: String,
+  pub text: String,
+  pub heading_path: String,
+  pub score: f32,
+}
+
+#[derive(
+  Clone, Default, Debug, Copy, PartialEq, Eq, Deserialize, Serialize, EnumString, Display,
+)]
+pub enum RetrievalMode {
+  #[serde(rename = "vector")]
+  #[strum(serialize = "vector")]
+  Vector,
+  #[serde(rename = "lexical")]
+  #[strum(serialize = "lexical")]
+  Lexical,
+  #[default]
+  #[serde(rename = "hybrid")]
+  #[strum(serialize = "hybrid")]
+  Hybrid,
+}
+
+#[derive(
+  Clone, Default, Debug, Copy, PartialEq, Eq, Deserialize, Serialize, EnumString, Display,
+)]
+pub enum DistanceMetric {
+  #[default]
+  #[serde(rename = "l2")]
+  #[strum(serialize = "l2")]
+  L2,
+  #[serde(rename = "cosine")]
+  #[strum(serialize = "cosine")]
+  Cosine,
+  #[serde(rename = "dot")]
+  #[strum(serialize = "dot")]
+  Dot,
+}
+
+impl DistanceMetric {
+  /// Squared euclidean distance for L2, one minus the cosine similarity or the dot product
+  /// otherwise, as LanceDB reports them.
+  pub fn distance(self, a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+
+    match self {
+      Self::L2 => a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum(),
+      Self::Cosine => {
+        let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+
+        let norms = norm(a) * norm(b);
+
+        if norms == 0.0 {
+          1.0
+        } else {
+          1.0 - dot / norms
+        }
+      }
+      Self::Dot => 1.0 - dot,
+    }
+  }
+
+  /// A distance of this metric as a cosine distance, from 0 for the same direction to 2 for the
+  /// opposite one. Embeddings are L2 normalized, so the squared euclidean distance is twice the
+  /// cosine distance and the dot product is the cosine similarity.
+  pub fn normalize(self, distance: f32) -> f32 {
+    match self {
+      Self::L2 => distance / 2.0,
+      Self::Cosine | Self::Dot => distance,
+    }
+  }
+}
+
+/// Retrieval settings of a knowledge base chat. Unset fields fall back to the settings stored for
+/// the knowledge base, then to `retrieval_conf`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RetrievalSetting {
+  pub mode: Option<RetrievalMode>,
+  /// Chunks put into the prompt, fewer when they don't fit the context window.
+  pub top_k: Option<usize>,
+  pub metric: Option<DistanceMetric>,
+  /// Chunks farther than this from the query are dropped, lexical hits included. It is a cosine
+  /// distance from 0 to 2 whatever the metric, see [`DistanceMetric::normalize`].
+  pub max_distance: Option<f32>,
+  /// Chunks the reranker scores below this are dropped, ignored without a reranker.
+  pub min_relevance: Option<f32>,
+  /// Rewriting of the last message before it is searched, see [`QueryRewrite`].
+  pub rewrite: Option<QueryRewrite>,
+  /// Index partitions probed by vector searches, see `retrieval_conf.nprobes`.
+  pub nprobes: Option<usize>,
+  /// Rescoring of indexed vector searches, see `retrieval_conf.refine_factor`.
+  pub refine_factor: Option<u32>,
+  #[serde(default)]
+  pub filter: RetrievalFilter,
+}
+
+impl RetrievalSetting {
+  pub fn or(self, fallback: RetrievalSetting) -> Self {
+    Self {
+      mode: self.mode.or(fallback.mode),
+      top_k: self.top_k.or(fallback.top_k),
+      metric: self.metric.or(fallback.metric),
+      max_distance: self.max_distance.or(fallback.max_distance),
+      min_relevance: self.min_relevance.or(fallback.min_relevance),
+      rewrite: self.rewrite.or(fallback.rewrite),
+      nprobes: self.nprobes.or(fallback.nprobes),
+      refine_factor: self.refine_factor.or(fallback.refine_factor),
+      filter: if self.filter.is_empty() {
+        fallback.filter
+      } else {
+        self.filter
+      },
+    }
+  }
+}
+
+/// Restricts retrieval to chunks of the given files. Both lists empty means no restriction.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RetrievalFilter {
+  #[serde(default)]
+  pub file_ids: Vec<i64>,
+  #[serde(default)]
+  pub filenames: Vec<String>,
+}
+
+impl RetrievalFilter {
+  pub fn is_empty(&self) -> bool {
+    self.file_ids.is_empty() && self.filenames.is_empty()
+  }
+
+  pub fn matches(&self, chunk: &RetrievedChunk) -> bool {
+    (self.file_ids.is_empty() || self.file_ids.contains(&chunk.file_id))
+      && (self.filenames.is_empty() || self.filenames.contains(&chunk.file_name))
+  }
+}
+
+/// Keeps the leading chunks whose [`format_source`] blocks fit in `budget` tokens together.
+pub fn fit_token_budget(
+  chunks: Vec<RetrievedChunk>,
+  budget: usize,
+  tokenizer: &Tokenizer,
+) -> anyhow::Result<Vec<RetrievedChunk>> {
+  let mut used = 0;
+  let mut fitted = vec![];
+
+  for chunk in chunks {
+    let tokens = tokenizer
+      .encode(format_source(fitted.len() + 1, &chunk), false)
+      .map_err(anyhow::Error::msg)?
+      .len();
+
+    if used + tokens > budget {
+      break;
+    }
+
+    used += tokens;
+    fitted.push(chunk);
+  }
+
+  Ok(fitted)
+}
+
+/// Renders a chunk for the prompt under its citation marker `[index]`.
+pub fn format_source(index: usize, chunk: &RetrievedChunk) -> String {
+  if chunk.heading_path.is_empty() {
+    format!("[{}] {}", index, chunk.text)
+  } else {
+    format!(
+      "[{}] (section: {})\n{}",
+      index, chunk.heading_path, chunk.text
+    )
+  }
+}
+
+/// Merges rankings with reciprocal rank fusion: a chunk scores `1 / (k + rank)` in every ranking
+/// it appears in, so agreement between rankings matters more than any single raw score.
+pub fn reciprocal_rank_fusion(rankings: &[Vec<RetrievedChunk>], k: f32) -> Vec<RetrievedChunk> {
+  let mut fused: Vec<RetrievedChunk> = vec![];
+  let mut positions: HashMap<String, usize> = HashMap::new();
+
+  for ranking in rankings {
+    for (rank, chunk) in ranking.iter().enumerate() {
+      let score = 1.0 / (k + rank as f32 + 1.0);
+
+      match positions.get(&chunk.id) {
+        Some(&position) => fused[position].score += score,
+        None => {
+          positions.insert(chunk.id.clone(), fused.len());
+          fused.push(RetrievedChunk {
+            score,
+            ..chunk.clone()
+          });
+        }
+      }
+    }
+  }
+
+  fused.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+  fused
+}
+
+/// Lexical indexes of knowledge base tables, built on first use and dropped whenever the rows of
+/// the table change.
+#[derive(Default, Clone)]
+pub struct LexicalIndexCache {
+  indexes: Arc<RwLock<HashMap<i64, Arc<Bm25Index>>>>,
+  generation: Arc<AtomicU64>,
+}
+
+impl LexicalIndexCache {
+  pub fn get(&self, kb_id: i64) -> Option<Arc<Bm25Index>> {
+    self.indexes.read().ok()?.get(&kb_id).cloned()
+  }
+
+  /// Taken before reading the table an index is built from, see [`Self::insert`].
+  pub fn generation(&self) -> u64 {
+    self.generation.load(Ordering::Acquire)
+  }
+
+  /// Caches `index` unless some table was invalidated since `generation`, in which case the index
+  /// may miss rows and is only used by the caller.
+  pub fn insert(&self, kb_id: i64, generation: u64, index: Bm25Index) -> Arc<Bm25Index> {
+    let index = Arc::new(index);
+
+    if let Ok(mut indexes) = self.indexes.write() {
+      if self.generation() == generation {
+        indexes.insert(kb_id, index.clone());
+      }
+    }
+
+    index
+  }
+
+  pub fn invalidate(&self, kb_id: i64) {
+    if let Ok(mut indexes) = self.indexes.write() {
+      self.generation.fetch_add(1, Ordering::AcqRel);
+      indexes.remove(&kb_id);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn normalized_distances_agree_across_metrics() {
+    let query = [1.0, 0.0];
+    let chunk = [0.6, 0.8];
+
+    for metric in [
+      DistanceMetric::L2,
+      DistanceMetric::Cosine,
+      DistanceMetric::Dot,
+    ] {
+      let distance = metric.normalize(metric.distance(&query, &chunk));
+
+      assert!((distance - 0.4).abs() < 1e-6, "{}: {}", metric, distance);
+    }
+  }
+
+  fn ranking(ids: &[&str]) -> Vec<RetrievedChunk> {
+    ids
+      .iter()
+      .map(|id| RetrievedChunk {
+        id: id.to_string(),
+        score: 100.0,
+        ..Default::default()
+      })
+      .collect()
+  }
+
+  #[test]
+  fn fusion_favors_chunks_both_rankings_agree_on() {
+    let fused = reciprocal_rank_fusion(
+      &[ranking(&["a", "b", "c"]), ranking(&["c", "b", "d"])],
+      60.0,
+    );
+
+    let ids: Vec<&str> = fused.iter().map(|chunk| chunk.id.as_str()).collect();
+
+    // `c` ranks first and third, which beats second in both
+    assert_eq!(ids, vec!["c", "b", "a", "d"]);
+    assert!((fused[1].score - 2.0 / 62.0).abs() < 1e-6);
+    assert!((fused[3].score - 1.0 / 63.0).abs() < 1e-6);
+  }
+
+  #[test]
+  fn indexes_built_before_an_invalidation_are_not_cached() {
+    let cache = LexicalIndexCache::default();
+
+    let generation = cache.generation();
+    cache.insert(1, generation, Bm25Index::new(vec![]));
+
+    assert!(cache.get(1).is_some());
+
+    let generation = cache.generation();
+    cache.invalidate(1);
+    cache.insert(1, generation, Bm25Index::new(vec![]));
+
+    assert!(cache.get(1).is_none());
+
+    cache.insert(1, cache.generation(), Bm25Index::new(vec![]));
+
+    assert!(cache.get(1).is_some());
+  }
+}
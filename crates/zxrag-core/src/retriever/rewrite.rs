@@ -0,0 +1,296 @@
+use either::Either;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use strum::{Display, EnumString};
+
+use crate::types::inference::get_inference_worker;
+use crate::types::llm::TextGenerationSetting;
+use crate::types::model::ModelId;
+use crate::types::openai::{ChatMessage, ChatMessages};
+
+const CONDENSE_PROMPT: &str = "Rewrite the last question of the conversation below as a single \
+  standalone question that can be understood without the conversation. Reply with the question \
+  only.";
+
+const HYDE_PROMPT: &str = "Write a short passage that answers the last question of the \
+  conversation below, as it could appear in a reference document. Reply with the passage only.";
+
+const MULTI_QUERY_PROMPT: &str = "Write {n} different search queries, one per line, that would \
+  find documents answering the last question of the conversation below. Each query must be \
+  understandable without the conversation. Reply with the queries only.";
+
+/// How the last message of a knowledge base chat is turned into search queries.
+#[derive(
+  Clone, Default, Debug, Copy, PartialEq, Eq, Deserialize, Serialize, EnumString, Display,
+)]
+pub enum QueryRewrite {
+  /// The last message is searched as is.
+  #[default]
+  #[serde(rename = "none")]
+  #[strum(serialize = "none")]
+  None,
+  /// The LLM rewrites the last message into a standalone question using the conversation.
+  #[serde(rename = "condense")]
+  #[strum(serialize = "condense")]
+  Condense,
+  /// The LLM writes a hypothetical answer, which is embedded instead of the question.
+  #[serde(rename = "hyde")]
+  #[strum(serialize = "hyde")]
+  Hyde,
+  /// The LLM writes several standalone queries, each searched on its own, and the rankings are
+  /// fused.
+  #[serde(rename = "multi_query")]
+  #[strum(serialize = "multi_query")]
+  MultiQuery,
+}
+
+/// Searches to run for a knowledge base chat.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RewrittenQuery {
+  /// What the reranker scores chunks against.
+  pub query: String,
+  /// Texts embedded for vector search, each giving one ranking.
+  pub embedding_texts: Vec<String>,
+  /// Queries of lexical search, each giving one ranking.
+  pub lexical_queries: Vec<String>,
+}
+
+impl RewrittenQuery {
+  pub fn new(query: String) -> Self {
+    Self {
+      embedding_texts: vec![query.clone()],
+      lexical_queries: vec![query.clone()],
+      query,
+    }
+  }
+}
+
+/// Rewrites the last message of `messages` with the LLM. `multi_queries` is the number of queries
+/// [`QueryRewrite::MultiQuery`] asks for, on top of the last message itself.
+pub async fn rewrite_query(
+  rewrite: QueryRewrite,
+  messages: &ChatMessages<'_>,
+  model_id: ModelId,
+  multi_queries: usize,
+) -> anyhow::Result<RewrittenQuery> {
+  let question = messages
+    .last()
+    .ok_or(anyhow::anyhow!("messages is empty"))?
+    .to_string();
+
+  let conversation = conversation_text(messages);
+
+  let has_history = messages
+    .iter()
+    .filter(|message| !matches!(message, ChatMessage::System { .. }))
+    .count()
+    > 1;
+
+  match rewrite {
+    QueryRewrite::None => Ok(RewrittenQuery::new(question)),
+    // A first question has nothing to resolve against
+    QueryRewrite::Condense if !has_history => Ok(RewrittenQuery::new(question)),
+    QueryRewrite::Condense => {
+      let standalone = generate(CONDENSE_PROMPT, &conversation, model_id, 64, true).await?;
+
+      if standalone.is_empty() {
+        return Ok(RewrittenQuery::new(question));
+      }
+
+      tracing::info!("condensed query: {}", standalone);
+
+      Ok(RewrittenQuery::new(standalone))
+    }
+    QueryRewrite::Hyde => {
+      let passage = generate(HYDE_PROMPT, &conversation, model_id, 256, false).await?;
+
+      if passage.is_empty() {
+        return Ok(RewrittenQuery::new(question));
+      }
+
+      tracing::info!("hypothetical answer: {}", passage);
+
+      Ok(RewrittenQuery {
+        embedding_texts: vec![passage],
+        lexical_queries: vec![question.clone()],
+        query: question,
+      })
+    }
+    QueryRewrite::MultiQuery => {
+      let prompt = MULTI_QUERY_PROMPT.replace("{n}", &multi_queries.to_string());
+
+      let output = generate(&prompt, &conversation, model_id, 128, false).await?;
+
+      let queries = parse_queries(&output, multi_queries);
+
+      tracing::info!("expanded queries: {:?}", queries);
+
+      Ok(expanded_query(question, queries, has_history))
+    }
+  }
+}
+
+/// The distinct queries listed one per line in `output`, at most `limit` of them.
+fn parse_queries(output: &str, limit: usize) -> Vec<String> {
+  let mut queries = vec![];
+
+  for line in output.lines() {
+    // Models like to number their lists
+    let query = line
+      .trim()
+      .trim_start_matches(|c: char| c.is_ascii_digit() || matches!(c, '.' | ')' | '-' | '*'))
+      .trim()
+      .to_string();
+
+    if !query.is_empty() && !queries.contains(&query) && queries.len() < limit {
+      queries.push(query);
+    }
+  }
+
+  queries
+}
+
+/// Searches for the expanded `queries` along with the `question` itself.
+fn expanded_query(question: String, mut queries: Vec<String>, has_history: bool) -> RewrittenQuery {
+  // The reranker still judges by the standalone meaning of the question, the first query
+  let query = if has_history {
+    queries.first().cloned().unwrap_or_else(|| question.clone())
+  } else {
+    question.clone()
+  };
+
+  if !queries.contains(&question) {
+    queries.insert(0, question);
+  }
+
+  RewrittenQuery {
+    query,
+    embedding_texts: queries.clone(),
+    lexical_queries: queries,
+  }
+}
+
+/// The user and assistant turns of `messages` as plain text.
+fn conversation_text(messages: &ChatMessages<'_>) -> String {
+  messages
+    .iter()
+    .filter_map(|message| match message {
+      ChatMessage::User { .. } => Some(format!("User: {}", message)),
+      ChatMessage::Assistant { .. } => Some(format!("Assistant: {}", message)),
+      _ => None,
+    })
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
+async fn generate(
+  instruction: &str,
+  conversation: &str,
+  model_id: ModelId,
+  sample_len: usize,
+  single_line: bool,
+) -> anyhow::Result<String> {
+  let messages: ChatMessages = vec![ChatMessage::User {
+    content: Either::Left(Cow::Owned(format!(
+      "{}\n\nConversation:\n{}",
+      instruction, conversation
+    ))),
+    name: None,
+  }]
+  .into();
+
+  let output = get_inference_worker()?
+    .generate(TextGenerationSetting {
+      temperature: 0.0,
+      top_p: None,
+      seed: 299792458,
+      repeat_penalty: 1.1,
+      repeat_last_n: 64,
+      sample_len,
+      prompt: messages.to_prompt(model_id)?,
+      stop: if single_line {
+        vec!["\n".to_string()]
+      } else {
+        vec![]
+      },
+    })
+    .await?;
+
+  Ok(output.text.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn messages(json: &str) -> ChatMessages<'_> {
+    serde_json::from_str::<Vec<ChatMessage>>(json)
+      .unwrap()
+      .into()
+  }
+
+  #[test]
+  fn numbered_queries_are_parsed_once_each() {
+    let output = "1. install on linux\n2) install on linux\n\n- build from source\n* docker image";
+
+    assert_eq!(
+      parse_queries(output, 2),
+      vec!["install on linux", "build from source"]
+    );
+  }
+
+  #[test]
+  fn expanded_queries_include_the_question() {
+    let queries = vec!["how to install zxrag".to_string()];
+
+    let rewritten = expanded_query("and on linux?".to_string(), queries.clone(), true);
+
+    assert_eq!(rewritten.query, "how to install zxrag");
+    assert_eq!(
+      rewritten.embedding_texts,
+      vec!["and on linux?", "how to install zxrag"]
+    );
+    assert_eq!(rewritten.lexical_queries, rewritten.embedding_texts);
+
+    // Without history the question already stands on its own
+    let rewritten = expanded_query("how to install?".to_string(), queries, false);
+
+    assert_eq!(rewritten.query, "how to install?");
+  }
+
+  #[test]
+  fn conversation_leaves_out_system_messages() {
+    let messages = messages(
+      r#"[
+        {"role": "system", "content": "Be brief."},
+        {"role": "user", "content": "What is zxrag?"},
+        {"role": "assistant", "content": "A RAG server."},
+        {"role": "user", "content": "How do I install it?"}
+      ]"#,
+    );
+
+    assert_eq!(
+      conversation_text(&messages),
+      "User: What is zxrag?\nAssistant: A RAG server.\nUser: How do I install it?"
+    );
+  }
+
+  #[tokio::test]
+  async fn first_questions_are_not_condensed() {
+    let messages = messages(
+      r#"[
+        {"role": "system", "content": "Be brief."},
+        {"role": "user", "content": "What is zxrag?"}
+      ]"#,
+    );
+
+    // No inference worker is running, so any generation would fail
+    for rewrite in [QueryRewrite::None, QueryRewrite::Condense] {
+      let rewritten = rewrite_query(rewrite, &messages, ModelId::default(), 3)
+        .await
+        .unwrap();
+
+      assert_eq!(rewritten, RewrittenQuery::new("What is zxrag?".to_string()));
+    }
+  }
+}
@@ -0,0 +1,346 @@
+use crate::text_splitter::{
+  char_len, check_chunk_params, merge_splits, RecursiveCharacterTextSplitter, TextChunk,
+  TextSplitter,
+};
+
+pub const HEADING_PATH_SEPARATOR: &str = " > ";
+
+/// Splits Markdown on heading boundaries and records the heading path of every chunk. Fenced code
+/// blocks and tables are kept whole even when they exceed `chunk_size` characters; oversized prose
+/// paragraphs fall back to [`RecursiveCharacterTextSplitter`].
+#[derive(Debug, Clone)]
+pub struct MarkdownTextSplitter {
+  chunk_size: usize,
+  chunk_overlap: usize,
+  fallback: RecursiveCharacterTextSplitter,
+}
+
+struct Block {
+  text: String,
+  atomic: bool,
+}
+
+struct Section {
+  heading_path: String,
+  blocks: Vec<Block>,
+}
+
+impl MarkdownTextSplitter {
+  pub fn new(chunk_size: usize, chunk_overlap: usize) -> anyhow::Result<Self> {
+    check_chunk_params(chunk_size, chunk_overlap)?;
+
+    Ok(Self {
+      chunk_size,
+      chunk_overlap,
+      fallback: RecursiveCharacterTextSplitter::new(chunk_size, chunk_overlap)?,
+    })
+  }
+
+  fn split_section(&self, section: &Section) -> anyhow::Result<Vec<String>> {
+    let mut pieces = vec![];
+
+    for block in &section.blocks {
+      if block.atomic || char_len(&block.text) <= self.chunk_size {
+        pieces.push(block.text.clone());
+      } else {
+        pieces.extend(self.fallback.split_text(&block.text)?);
+      }
+    }
+
+    let pieces: Vec<&str> = pieces.iter().map(|s| s.as_str()).collect();
+
+    Ok(merge_splits(
+      &pieces,
+      "\n\n",
+      self.chunk_size,
+      self.chunk_overlap,
+    ))
+  }
+}
+
+impl TextSplitter for MarkdownTextSplitter {
+  fn split_text(&self, text: &str) -> anyhow::Result<Vec<String>> {
+    Ok(
+      self
+        .split_chunks(text)?
+        .into_iter()
+        .map(|chunk| chunk.text)
+        .collect(),
+    )
+  }
+
+  fn split_chunks(&self, text: &str) -> anyhow::Result<Vec<TextChunk>> {
+    let mut chunks = vec![];
+
+    for section in parse_sections(text) {
+      for chunk in self.split_section(&section)? {
+        chunks.push(TextChunk {
+          text: chunk,
+          heading_path: section.heading_path.clone(),
+        });
+      }
+    }
+
+    Ok(chunks)
+  }
+}
+
+fn parse_sections(text: &str) -> Vec<Section> {
+  let lines: Vec<&str> = text.lines().collect();
+
+  let mut sections = vec![];
+  let mut headings: Vec<(usize, String)> = vec![];
+  let mut section = Section {
+    heading_path: String::new(),
+    blocks: vec![],
+  };
+  let mut paragraph: Vec<&str> = vec![];
+
+  let mut i = 0;
+
+  while i < lines.len() {
+    let line = lines[i];
+
+    if let Some(fence) = fence_marker(line) {
+      flush_paragraph(&mut section, &mut paragraph);
+
+      let start = i;
+      i += 1;
+
+      while i < lines.len() && !lines[i].trim_start().starts_with(fence) {
+        i += 1;
+      }
+
+      let end = (i + 1).min(lines.len());
+
+      section.blocks.push(Block {
+        text: lines[start..end].join("\n"),
+        atomic: true,
+      });
+
+      i = end;
+      continue;
+    }
+
+    if is_table_start(&lines, i) {
+      flush_paragraph(&mut section, &mut paragraph);
+
+      let start = i;
+
+      while i < lines.len() && !lines[i].trim().is_empty() && lines[i].contains('|') {
+        i += 1;
+      }
+
+      section.blocks.push(Block {
+        text: lines[start..i].join("\n"),
+        atomic: true,
+      });
+
+      continue;
+    }
+
+    if let Some((level, title)) = parse_heading(line) {
+      flush_paragraph(&mut section, &mut paragraph);
+
+      if !section.blocks.is_empty() {
+        sections.push(section);
+      }
+
+      while headings.last().is_some_and(|(l, _)| *l >= level) {
+        headings.pop();
+      }
+
+      headings.push((level, title));
+
+      section = Section {
+        heading_path: headings
+          .iter()
+          .map(|(_, title)| title.as_str())
+          .collect::<Vec<&str>>()
+          .join(HEADING_PATH_SEPARATOR),
+        blocks: vec![],
+      };
+
+      i += 1;
+      continue;
+    }
+
+    if line.trim().is_empty() {
+      flush_paragraph(&mut section, &mut paragraph);
+    } else {
+      paragraph.push(line);
+    }
+
+    i += 1;
+  }
+
+  flush_paragraph(&mut section, &mut paragraph);
+
+  if !section.blocks.is_empty() {
+    sections.push(section);
+  }
+
+  sections
+}
+
+fn flush_paragraph(section: &mut Section, paragraph: &mut Vec<&str>) {
+  if paragraph.is_empty() {
+    return;
+  }
+
+  section.blocks.push(Block {
+    text: paragraph.join("\n"),
+    atomic: false,
+  });
+
+  paragraph.clear();
+}
+
+fn fence_marker(line: &str) -> Option<&'static str> {
+  let line = line.trim_start();
+
+  if line.starts_with("```") {
+    Some("```")
+  } else if line.starts_with("~~~") {
+    Some("~~~")
+  } else {
+    None
+  }
+}
+
+fn parse_heading(line: &str) -> Option<(usize, String)> {
+  let line = line.trim_start();
+
+  let level = line.chars().take_while(|c| *c == '#').count();
+
+  if level == 0 || level > 6 {
+    return None;
+  }
+
+  let rest = &line[level..];
+
+  if !rest.is_empty() && !rest.starts_with([' ', '\t']) {
+    return None;
+  }
+
+  let title = rest.trim().trim_end_matches('#').trim();
+
+  Some((level, title.to_string()))
+}
+
+fn is_table_start(lines: &[&str], i: usize) -> bool {
+  let line = lines[i].trim();
+
+  if line.starts_with('|') {
+    return true;
+  }
+
+  line.contains('|')
+    && lines
+      .get(i + 1)
+      .is_some_and(|next| is_table_delimiter(next))
+}
+
+fn is_table_delimiter(line: &str) -> bool {
+  let line = line.trim().trim_matches('|');
+
+  !line.is_empty()
+    && line.split('|').all(|cell| {
+      let cell = cell.trim().trim_start_matches(':').trim_end_matches(':');
+
+      !cell.is_empty() && cell.chars().all(|c| c == '-')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn split(text: &str, chunk_size: usize, chunk_overlap: usize) -> Vec<TextChunk> {
+    MarkdownTextSplitter::new(chunk_size, chunk_overlap)
+      .unwrap()
+      .split_chunks(text)
+      .unwrap()
+  }
+
+  fn heading_paths(chunks: &[TextChunk]) -> Vec<&str> {
+    chunks
+      .iter()
+      .map(|chunk| chunk.heading_path.as_str())
+      .collect()
+  }
+
+  #[test]
+  fn chunks_carry_the_headings_enclosing_them() {
+    let text = "intro\n\n# Guide\n\nguide text\n\n## Install\n\ninstall text\n\n### Linux\n\n\
+                linux text\n\n## Usage ##\n\nusage text\n\n# FAQ\n\nfaq text";
+
+    let chunks = split(text, 1000, 0);
+
+    assert_eq!(
+      heading_paths(&chunks),
+      vec![
+        "",
+        "Guide",
+        "Guide > Install",
+        "Guide > Install > Linux",
+        "Guide > Usage",
+        "FAQ",
+      ]
+    );
+    assert_eq!(chunks[3].text, "linux text");
+  }
+
+  #[test]
+  fn sections_without_content_are_skipped() {
+    let chunks = split("# Empty\n\n## Child\n\ntext", 1000, 0);
+
+    assert_eq!(heading_paths(&chunks), vec!["Empty > Child"]);
+  }
+
+  #[test]
+  fn headings_inside_code_blocks_are_ignored() {
+    let text = "# Shell\n\n```sh\n# not a heading\necho hi\n```";
+
+    let chunks = split(text, 1000, 0);
+
+    assert_eq!(chunks.len(), 1);
+    assert_eq!(chunks[0].heading_path, "Shell");
+    assert_eq!(chunks[0].text, "```sh\n# not a heading\necho hi\n```");
+  }
+
+  #[test]
+  fn code_blocks_and_tables_are_kept_whole() {
+    let code = format!("```\n{}\n```", "let x = 1;\n".repeat(10).trim_end());
+    let table = "| a | b |\n| - | - |\n| 1 | 2 |\n| 3 | 4 |";
+
+    let chunks = split(&format!("# Code\n\n{}\n\n{}", code, table), 20, 0);
+
+    assert_eq!(chunks.len(), 2);
+    assert_eq!(chunks[0].text, code);
+    assert_eq!(chunks[1].text, table);
+  }
+
+  #[test]
+  fn long_paragraphs_are_split_to_the_chunk_size() {
+    let paragraph = "word ".repeat(60);
+
+    let chunks = split(&format!("# Long\n\n{}", paragraph), 50, 10);
+
+    assert!(chunks.len() > 1);
+    assert!(chunks
+      .iter()
+      .all(|chunk| char_len(&chunk.text) <= 50 && chunk.heading_path == "Long"));
+  }
+
+  #[test]
+  fn paragraphs_are_merged_with_overlap() {
+    let text = "# Notes\n\naaaa\n\nbbbb\n\ncccc\n\ndddd";
+
+    let chunks = split(text, 10, 4);
+
+    let texts: Vec<&str> = chunks.iter().map(|chunk| chunk.text.as_str()).collect();
+
+    assert_eq!(texts, vec!["aaaa\n\nbbbb", "bbbb\n\ncccc", "cccc\n\ndddd"]);
+  }
+}
@@ -1 +1,200 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use strum::{Display, EnumString};
+use tokenizers::Tokenizer;
 
+use crate::types::conf::TextSplitterConf;
+
+pub mod markdown;
+pub mod recursive_character;
+pub mod sentence;
+pub mod token;
+
+pub use markdown::MarkdownTextSplitter;
+pub use recursive_character::RecursiveCharacterTextSplitter;
+pub use sentence::SentenceTextSplitter;
+pub use token::TokenTextSplitter;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TextChunk {
+  pub text: String,
+  /// Headings enclosing the chunk joined by `" > "`, empty for splitters without document structure.
+  pub heading_path: String,
+}
+
+pub trait TextSplitter: Send + Sync {
+  fn split_text(&self, text: &str) -> anyhow::Result<Vec<String>>;
+
+  fn split_chunks(&self, text: &str) -> anyhow::Result<Vec<TextChunk>> {
+    Ok(
+      self
+        .split_text(text)?
+        .into_iter()
+        .map(|text| TextChunk {
+          text,
+          heading_path: String::new(),
+        })
+        .collect(),
+    )
+  }
+}
+
+#[derive(
+  Clone, Default, Debug, Copy, PartialEq, Eq, Deserialize, Serialize, EnumString, Display,
+)]
+pub enum TextSplitterKind {
+  #[serde(rename = "recursive_character")]
+  #[strum(serialize = "recursive_character")]
+  RecursiveCharacter,
+  #[serde(rename = "sentence")]
+  #[strum(serialize = "sentence")]
+  Sentence,
+  #[default]
+  #[serde(rename = "token")]
+  #[strum(serialize = "token")]
+  Token,
+  #[serde(rename = "markdown")]
+  #[strum(serialize = "markdown")]
+  Markdown,
+}
+
+pub fn new_text_splitter(
+  kind: TextSplitterKind,
+  conf: &TextSplitterConf,
+  tokenizer: &Tokenizer,
+) -> anyhow::Result<Box<dyn TextSplitter>> {
+  let splitter: Box<dyn TextSplitter> = match kind {
+    TextSplitterKind::RecursiveCharacter => Box::new(RecursiveCharacterTextSplitter::new(
+      conf.chunk_size,
+      conf.chunk_overlap,
+    )?),
+    TextSplitterKind::Sentence => Box::new(SentenceTextSplitter::new(
+      conf.chunk_size,
+      conf.chunk_overlap,
+    )?),
+    TextSplitterKind::Token => Box::new(TokenTextSplitter::new(
+      tokenizer.clone(),
+      conf.chunk_size,
+      conf.chunk_overlap,
+    )?),
+    TextSplitterKind::Markdown => Box::new(MarkdownTextSplitter::new(
+      conf.chunk_size,
+      conf.chunk_overlap,
+    )?),
+  };
+
+  Ok(splitter)
+}
+
+pub(crate) fn check_chunk_params(chunk_size: usize, chunk_overlap: usize) -> anyhow::Result<()> {
+  if chunk_size == 0 {
+    anyhow::bail!("chunk_size must be greater than 0");
+  }
+
+  if chunk_overlap >= chunk_size {
+    anyhow::bail!(
+      "chunk_overlap ({}) must be smaller than chunk_size ({})",
+      chunk_overlap,
+      chunk_size
+    );
+  }
+
+  Ok(())
+}
+
+pub(crate) fn char_len(text: &str) -> usize {
+  text.chars().count()
+}
+
+/// Greedily merges small splits into chunks of at most `chunk_size` characters, carrying up to
+/// `chunk_overlap` characters of trailing splits over into the next chunk.
+pub(crate) fn merge_splits(
+  splits: &[&str],
+  separator: &str,
+  chunk_size: usize,
+  chunk_overlap: usize,
+) -> Vec<String> {
+  let separator_len = char_len(separator);
+
+  let mut chunks = vec![];
+  let mut current: VecDeque<&str> = VecDeque::new();
+  let mut total = 0usize;
+
+  for split in splits {
+    let len = char_len(split);
+    let joined_len = |current: &VecDeque<&str>| {
+      if current.is_empty() {
+        0
+      } else {
+        separator_len
+      }
+    };
+
+    if total + len + joined_len(&current) > chunk_size && !current.is_empty() {
+      push_chunk(&mut chunks, &current, separator);
+
+      while total > chunk_overlap || (total > 0 && total + len + joined_len(&current) > chunk_size)
+      {
+        let Some(first) = current.pop_front() else {
+          break;
+        };
+
+        total -= char_len(first) + if current.is_empty() { 0 } else { separator_len };
+      }
+    }
+
+    total += len + joined_len(&current);
+    current.push_back(split);
+  }
+
+  push_chunk(&mut chunks, &current, separator);
+
+  chunks
+}
+
+fn push_chunk(chunks: &mut Vec<String>, current: &VecDeque<&str>, separator: &str) {
+  let chunk = current
+    .iter()
+    .copied()
+    .collect::<Vec<&str>>()
+    .join(separator);
+
+  let chunk = chunk.trim();
+
+  if !chunk.is_empty() {
+    chunks.push(chunk.to_string());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn chunk_params_are_checked() {
+    assert!(check_chunk_params(0, 0).is_err());
+    assert!(check_chunk_params(10, 10).is_err());
+    assert!(check_chunk_params(10, 9).is_ok());
+  }
+
+  #[test]
+  fn splits_are_merged_up_to_the_chunk_size() {
+    let chunks = merge_splits(&["aa", "bb", "cc", "dd"], " ", 5, 0);
+
+    assert_eq!(chunks, vec!["aa bb", "cc dd"]);
+  }
+
+  #[test]
+  fn trailing_splits_overlap_into_the_next_chunk() {
+    let chunks = merge_splits(&["aa", "bb", "cc", "dd"], " ", 8, 2);
+
+    assert_eq!(chunks, vec!["aa bb cc", "cc dd"]);
+  }
+
+  #[test]
+  fn oversized_splits_become_their_own_chunk() {
+    let chunks = merge_splits(&["a", "bbbbbbbb", "c"], " ", 4, 1);
+
+    assert_eq!(chunks, vec!["a", "bbbbbbbb", "c"]);
+  }
+}
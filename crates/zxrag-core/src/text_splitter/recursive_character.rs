@@ -0,0 +1,170 @@
+use crate::text_splitter::{char_len, check_chunk_params, merge_splits, TextSplitter};
+
+const DEFAULT_SEPARATORS: [&str; 7] = ["\n\n", "\n", "。", ". ", "，", " ", ""];
+
+/// Splits text on the first separator that occurs in it, recursing with the remaining separators
+/// for pieces that are still longer than `chunk_size` characters. Separators stay attached to the
+/// end of the piece they terminate.
+#[derive(Debug, Clone)]
+pub struct RecursiveCharacterTextSplitter {
+  separators: Vec<String>,
+  chunk_size: usize,
+  chunk_overlap: usize,
+}
+
+impl RecursiveCharacterTextSplitter {
+  pub fn new(chunk_size: usize, chunk_overlap: usize) -> anyhow::Result<Self> {
+    Self::with_separators(
+      DEFAULT_SEPARATORS.iter().map(|s| s.to_string()).collect(),
+      chunk_size,
+      chunk_overlap,
+    )
+  }
+
+  pub fn with_separators(
+    separators: Vec<String>,
+    chunk_size: usize,
+    chunk_overlap: usize,
+  ) -> anyhow::Result<Self> {
+    check_chunk_params(chunk_size, chunk_overlap)?;
+
+    Ok(Self {
+      separators,
+      chunk_size,
+      chunk_overlap,
+    })
+  }
+
+  fn split_recursive(&self, text: &str, separators: &[String]) -> Vec<String> {
+    let mut chunks = vec![];
+
+    let (separator, rest) = match separators
+      .iter()
+      .position(|s| s.is_empty() || text.contains(s.as_str()))
+    {
+      Some(i) => (separators[i].as_str(), &separators[i + 1..]),
+      None => ("", &[][..]),
+    };
+
+    let splits: Vec<&str> = if separator.is_empty() {
+      text
+        .char_indices()
+        .map(|(i, c)| &text[i..i + c.len_utf8()])
+        .collect()
+    } else {
+      text.split_inclusive(separator).collect()
+    };
+
+    let mut good_splits = vec![];
+
+    for split in splits {
+      if char_len(split) <= self.chunk_size {
+        good_splits.push(split);
+        continue;
+      }
+
+      if !good_splits.is_empty() {
+        chunks.extend(merge_splits(
+          &good_splits,
+          "",
+          self.chunk_size,
+          self.chunk_overlap,
+        ));
+        good_splits.clear();
+      }
+
+      if rest.is_empty() {
+        chunks.push(split.to_string());
+      } else {
+        chunks.extend(self.split_recursive(split, rest));
+      }
+    }
+
+    if !good_splits.is_empty() {
+      chunks.extend(merge_splits(
+        &good_splits,
+        "",
+        self.chunk_size,
+        self.chunk_overlap,
+      ));
+    }
+
+    chunks
+  }
+}
+
+impl TextSplitter for RecursiveCharacterTextSplitter {
+  fn split_text(&self, text: &str) -> anyhow::Result<Vec<String>> {
+    Ok(self.split_recursive(text, &self.separators))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn short_text_is_a_single_chunk() {
+    let splitter = RecursiveCharacterTextSplitter::new(100, 10).unwrap();
+
+    assert_eq!(
+      splitter
+        .split_text("one paragraph.\n\nanother one.")
+        .unwrap(),
+      vec!["one paragraph.\n\nanother one."]
+    );
+  }
+
+  #[test]
+  fn paragraphs_are_split_before_lines_and_words() {
+    let splitter = RecursiveCharacterTextSplitter::new(20, 0).unwrap();
+
+    let chunks = splitter
+      .split_text("first paragraph\n\nsecond paragraph\nwith two lines")
+      .unwrap();
+
+    assert_eq!(
+      chunks,
+      vec!["first paragraph", "second paragraph", "with two lines"]
+    );
+  }
+
+  #[test]
+  fn chunks_respect_the_chunk_size_and_overlap() {
+    let splitter = RecursiveCharacterTextSplitter::new(30, 10).unwrap();
+
+    let text = (0..40)
+      .map(|i| format!("w{:02}", i))
+      .collect::<Vec<_>>()
+      .join(" ");
+
+    let chunks = splitter.split_text(&text).unwrap();
+
+    assert!(chunks.len() > 1);
+    assert!(chunks.iter().all(|chunk| char_len(chunk) <= 30));
+
+    // Every chunk after the first starts with words the previous one ended with
+    for pair in chunks.windows(2) {
+      let first_word = pair[1].split(' ').next().unwrap();
+
+      assert!(pair[0].contains(first_word));
+    }
+
+    // Nothing is lost
+    for i in 0..40 {
+      assert!(chunks
+        .iter()
+        .any(|chunk| chunk.contains(&format!("w{:02}", i))));
+    }
+  }
+
+  #[test]
+  fn text_without_separators_is_split_by_character() {
+    let splitter = RecursiveCharacterTextSplitter::new(4, 0).unwrap();
+
+    assert_eq!(
+      splitter.split_text("abcdefghij").unwrap(),
+      vec!["abcd", "efgh", "ij"]
+    );
+  }
+}
@@ -0,0 +1,169 @@
+use crate::text_splitter::{
+  char_len, check_chunk_params, merge_splits, RecursiveCharacterTextSplitter, TextSplitter,
+};
+
+const CJK_TERMINATORS: [char; 5] = ['。', '！', '？', '；', '…'];
+const LATIN_TERMINATORS: [char; 3] = ['.', '!', '?'];
+const CLOSING_PUNCTUATION: [char; 7] = ['"', '\'', ')', ']', '”', '’', '」'];
+
+/// Packs whole sentences into chunks of at most `chunk_size` characters. Sentences that are longer
+/// than a chunk on their own fall back to [`RecursiveCharacterTextSplitter`].
+#[derive(Debug, Clone)]
+pub struct SentenceTextSplitter {
+  chunk_size: usize,
+  chunk_overlap: usize,
+  fallback: RecursiveCharacterTextSplitter,
+}
+
+impl SentenceTextSplitter {
+  pub fn new(chunk_size: usize, chunk_overlap: usize) -> anyhow::Result<Self> {
+    check_chunk_params(chunk_size, chunk_overlap)?;
+
+    Ok(Self {
+      chunk_size,
+      chunk_overlap,
+      fallback: RecursiveCharacterTextSplitter::new(chunk_size, chunk_overlap)?,
+    })
+  }
+}
+
+impl TextSplitter for SentenceTextSplitter {
+  fn split_text(&self, text: &str) -> anyhow::Result<Vec<String>> {
+    let mut chunks = vec![];
+    let mut sentences = vec![];
+
+    for sentence in split_sentences(text) {
+      if char_len(sentence) <= self.chunk_size {
+        sentences.push(sentence);
+        continue;
+      }
+
+      chunks.extend(merge_splits(
+        &sentences,
+        "",
+        self.chunk_size,
+        self.chunk_overlap,
+      ));
+      sentences.clear();
+
+      chunks.extend(self.fallback.split_text(sentence)?);
+    }
+
+    chunks.extend(merge_splits(
+      &sentences,
+      "",
+      self.chunk_size,
+      self.chunk_overlap,
+    ));
+
+    Ok(chunks)
+  }
+}
+
+/// Splits text after sentence terminators, keeping the terminator, any closing quotes or brackets
+/// and the trailing whitespace with the sentence they end.
+pub fn split_sentences(text: &str) -> Vec<&str> {
+  let mut sentences = vec![];
+  let mut start = 0;
+  let mut chars = text.char_indices().peekable();
+
+  while let Some((_, c)) = chars.next() {
+    let is_hard_break = c == '\n' || CJK_TERMINATORS.contains(&c);
+
+    if !is_hard_break && !LATIN_TERMINATORS.contains(&c) {
+      continue;
+    }
+
+    while let Some((_, next)) = chars.peek() {
+      if CLOSING_PUNCTUATION.contains(next)
+        || CJK_TERMINATORS.contains(next)
+        || LATIN_TERMINATORS.contains(next)
+      {
+        chars.next();
+      } else {
+        break;
+      }
+    }
+
+    match chars.peek() {
+      Some((_, next)) if next.is_whitespace() => {
+        while let Some((_, next)) = chars.peek() {
+          if next.is_whitespace() {
+            chars.next();
+          } else {
+            break;
+          }
+        }
+      }
+      Some(_) if !is_hard_break => continue,
+      _ => {}
+    }
+
+    let end = chars.peek().map_or(text.len(), |(i, _)| *i);
+
+    if end > start {
+      sentences.push(&text[start..end]);
+    }
+
+    start = end;
+  }
+
+  if start < text.len() {
+    sentences.push(&text[start..]);
+  }
+
+  sentences
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn sentences_keep_their_terminators() {
+    assert_eq!(
+      split_sentences("Hello there. \"Quoted!\" Next?\nLine 3.14 stays"),
+      vec![
+        "Hello there. ",
+        "\"Quoted!\" ",
+        "Next?\n",
+        "Line 3.14 stays"
+      ]
+    );
+  }
+
+  #[test]
+  fn cjk_terminators_break_without_whitespace() {
+    assert_eq!(
+      split_sentences("你好。世界！再见"),
+      vec!["你好。", "世界！", "再见"]
+    );
+  }
+
+  #[test]
+  fn whole_sentences_are_packed_into_chunks() {
+    let splitter = SentenceTextSplitter::new(40, 0).unwrap();
+
+    let chunks = splitter
+      .split_text("First sentence. Second sentence. Third one here.")
+      .unwrap();
+
+    assert_eq!(
+      chunks,
+      vec!["First sentence. Second sentence.", "Third one here."]
+    );
+  }
+
+  #[test]
+  fn long_sentences_fall_back_to_the_recursive_splitter() {
+    let splitter = SentenceTextSplitter::new(20, 0).unwrap();
+
+    let chunks = splitter
+      .split_text("Short. This sentence is much longer than twenty characters.")
+      .unwrap();
+
+    assert_eq!(chunks[0], "Short.");
+    assert!(chunks.len() > 2);
+    assert!(chunks.iter().all(|chunk| char_len(chunk) <= 20));
+  }
+}
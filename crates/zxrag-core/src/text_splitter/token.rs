@@ -0,0 +1,147 @@
+use tokenizers::Tokenizer;
+
+use crate::text_splitter::{check_chunk_params, TextSplitter};
+
+/// Splits text into windows of at most `chunk_size` tokens as counted by the embedding model's
+/// tokenizer, with `chunk_overlap` tokens shared between consecutive windows.
+#[derive(Clone)]
+pub struct TokenTextSplitter {
+  tokenizer: Tokenizer,
+  chunk_size: usize,
+  chunk_overlap: usize,
+}
+
+impl TokenTextSplitter {
+  pub fn new(
+    mut tokenizer: Tokenizer,
+    chunk_size: usize,
+    chunk_overlap: usize,
+  ) -> anyhow::Result<Self> {
+    check_chunk_params(chunk_size, chunk_overlap)?;
+
+    tokenizer
+      .with_padding(None)
+      .with_truncation(None)
+      .map_err(anyhow::Error::msg)?;
+
+    Ok(Self {
+      tokenizer,
+      chunk_size,
+      chunk_overlap,
+    })
+  }
+
+  pub fn count_tokens(&self, text: &str) -> anyhow::Result<usize> {
+    let encoding = self
+      .tokenizer
+      .encode(text, false)
+      .map_err(anyhow::Error::msg)?;
+
+    Ok(encoding.len())
+  }
+}
+
+impl TextSplitter for TokenTextSplitter {
+  fn split_text(&self, text: &str) -> anyhow::Result<Vec<String>> {
+    let encoding = self
+      .tokenizer
+      .encode(text, false)
+      .map_err(anyhow::Error::msg)?;
+
+    let offsets = encoding.get_offsets();
+
+    let mut chunks = vec![];
+    let mut start = 0;
+
+    while start < offsets.len() {
+      let end = (start + self.chunk_size).min(offsets.len());
+
+      let byte_start = floor_char_boundary(text, offsets[start].0);
+      let byte_end = ceil_char_boundary(text, offsets[end - 1].1);
+
+      let chunk = text[byte_start..byte_end].trim();
+
+      if !chunk.is_empty() {
+        chunks.push(chunk.to_string());
+      }
+
+      if end == offsets.len() {
+        break;
+      }
+
+      start += self.chunk_size - self.chunk_overlap;
+    }
+
+    Ok(chunks)
+  }
+}
+
+fn floor_char_boundary(text: &str, mut index: usize) -> usize {
+  index = index.min(text.len());
+
+  while !text.is_char_boundary(index) {
+    index -= 1;
+  }
+
+  index
+}
+
+fn ceil_char_boundary(text: &str, mut index: usize) -> usize {
+  index = index.min(text.len());
+
+  while !text.is_char_boundary(index) {
+    index += 1;
+  }
+
+  index
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+  use tokenizers::models::wordlevel::WordLevel;
+  use tokenizers::pre_tokenizers::whitespace::Whitespace;
+
+  use super::*;
+
+  fn splitter(chunk_size: usize, chunk_overlap: usize) -> TokenTextSplitter {
+    let model = WordLevel::builder()
+      .vocab(HashMap::from([("<unk>".to_string(), 0)]))
+      .unk_token("<unk>".to_string())
+      .build()
+      .unwrap();
+
+    let mut tokenizer = Tokenizer::new(model);
+    tokenizer.with_pre_tokenizer(Whitespace {});
+
+    TokenTextSplitter::new(tokenizer, chunk_size, chunk_overlap).unwrap()
+  }
+
+  #[test]
+  fn windows_overlap_by_chunk_overlap_tokens() {
+    let chunks = splitter(4, 1).split_text("a b c d e f g h i j").unwrap();
+
+    assert_eq!(chunks, vec!["a b c d", "d e f g", "g h i j"]);
+  }
+
+  #[test]
+  fn chunks_stay_within_the_chunk_size() {
+    let splitter = splitter(3, 0);
+
+    let chunks = splitter
+      .split_text("one two three four five six seven")
+      .unwrap();
+
+    assert_eq!(chunks, vec!["one two three", "four five six", "seven"]);
+    assert!(chunks
+      .iter()
+      .all(|chunk| splitter.count_tokens(chunk).unwrap() <= 3));
+  }
+
+  #[test]
+  fn chunks_keep_the_original_text() {
+    let chunks = splitter(2, 0).split_text("héllo,  wörld\nagain").unwrap();
+
+    assert_eq!(chunks, vec!["héllo,", "wörld\nagain"]);
+  }
+}
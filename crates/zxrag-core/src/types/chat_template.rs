@@ -0,0 +1,290 @@
+use either::Either;
+use minijinja::{context, Environment, Error, ErrorKind};
+use serde::Serialize;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::types::conf::LlmConf;
+use crate::types::model::ModelId;
+use crate::types::openai::{ChatMessage, ChatMessages};
+
+pub static CHAT_TEMPLATE: OnceLock<ChatTemplate> = OnceLock::new();
+
+const MISTRAL_TEMPLATE: &str = r#"{{ bos_token }}
+{%- set system = namespace(text='') %}
+{%- for message in messages %}
+  {%- if message['role'] == 'system' %}
+    {%- set system.text = system.text + message['content'] + '\n\n' %}
+  {%- elif message['role'] == 'user' %}
+    {{- '[INST] ' + system.text + message['content'] + ' [/INST]' }}
+    {%- set system.text = '' %}
+  {%- elif message['role'] == 'assistant' %}
+    {{- message['content'] + eos_token }}
+  {%- endif %}
+{%- endfor %}"#;
+
+const ZEPHYR_TEMPLATE: &str = r#"{%- for message in messages %}
+  {{- '<|' + message['role'] + '|>\n' + message['content'] + eos_token + '\n' }}
+{%- endfor %}
+{%- if add_generation_prompt %}
+  {{- '<|assistant|>\n' }}
+{%- endif %}"#;
+
+const PHI_TEMPLATE: &str = r#"{%- for message in messages %}
+  {%- if message['role'] == 'system' %}
+    {{- message['content'] + '\n' }}
+  {%- elif message['role'] == 'assistant' %}
+    {{- 'Output: ' + message['content'] + '\n' }}
+  {%- else %}
+    {{- 'Instruct: ' + message['content'] + '\n' }}
+  {%- endif %}
+{%- endfor %}
+{%- if add_generation_prompt %}
+  {{- 'Output:' }}
+{%- endif %}"#;
+
+const GEMMA_TEMPLATE: &str = r#"{{ bos_token }}
+{%- set system = namespace(text='') %}
+{%- for message in messages %}
+  {%- if message['role'] == 'system' %}
+    {%- set system.text = system.text + message['content'] + '\n' %}
+  {%- elif message['role'] == 'assistant' %}
+    {{- '<start_of_turn>model\n' + message['content'] + '<end_of_turn>\n' }}
+  {%- else %}
+    {{- '<start_of_turn>user\n' + system.text + message['content'] + '<end_of_turn>\n' }}
+    {%- set system.text = '' %}
+  {%- endif %}
+{%- endfor %}
+{%- if add_generation_prompt %}
+  {{- '<start_of_turn>model\n' }}
+{%- endif %}"#;
+
+const CHATML_TEMPLATE: &str = r#"{%- for message in messages %}
+  {{- '<|im_start|>' + message['role'] + '\n' + message['content'] + '<|im_end|>\n' }}
+{%- endfor %}
+{%- if add_generation_prompt %}
+  {{- '<|im_start|>assistant\n' }}
+{%- endif %}"#;
+
+/// Name the template is registered under in its environment.
+const TEMPLATE_NAME: &str = "chat";
+
+/// A Jinja chat template in the format used by the `chat_template` field of Hugging Face
+/// `tokenizer_config.json` files, compiled once when it is created.
+pub struct ChatTemplate {
+  env: Environment<'static>,
+  bos_token: String,
+  eos_token: String,
+}
+
+#[derive(Serialize)]
+struct TemplateMessage {
+  role: &'static str,
+  content: String,
+}
+
+impl ChatTemplate {
+  pub fn new(source: String, bos_token: String, eos_token: String) -> anyhow::Result<Self> {
+    let mut env = Environment::new();
+
+    env.set_trim_blocks(true);
+    env.set_lstrip_blocks(true);
+    env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+    env.add_function("raise_exception", |msg: String| -> Result<String, Error> {
+      Err(Error::new(ErrorKind::InvalidOperation, msg))
+    });
+    env.add_template_owned(TEMPLATE_NAME, source)?;
+
+    Ok(Self {
+      env,
+      bos_token,
+      eos_token,
+    })
+  }
+
+  pub fn builtin(model_id: ModelId) -> anyhow::Result<Self> {
+    let (source, bos_token, eos_token) = match model_id {
+      ModelId::Zephyr7bAlpha | ModelId::Zephyr7bBeta => (ZEPHYR_TEMPLATE, "<s>", "</s>"),
+      ModelId::Mistral7bInstructV0_1 | ModelId::Mistral7bInstructV0_2 => {
+        (MISTRAL_TEMPLATE, "<s>", "</s>")
+      }
+      ModelId::PhiV2 => (PHI_TEMPLATE, "", "<|endoftext|>"),
+      ModelId::CodeGemma7bIt => (GEMMA_TEMPLATE, "<bos>", "<eos>"),
+      _ => (CHATML_TEMPLATE, "", ""),
+    };
+
+    Self::new(
+      source.to_string(),
+      bos_token.to_string(),
+      eos_token.to_string(),
+    )
+  }
+
+  /// Resolves the template for the configured model: `chat_template_path` wins, then the
+  /// `tokenizer_config.json` shipped with the model, then the built-in template for the model id.
+  pub fn load(conf: &LlmConf) -> anyhow::Result<Self> {
+    let builtin = Self::builtin(conf.model_id)?;
+
+    let tokenizer_config = tokenizer_config_candidates(conf)
+      .into_iter()
+      .find(|path| path.is_file())
+      .map(|path| read_tokenizer_config(&path))
+      .transpose()?;
+
+    let (bos_token, eos_token) = match &tokenizer_config {
+      Some(config) => (
+        special_token(config, "bos_token").unwrap_or(builtin.bos_token.clone()),
+        special_token(config, "eos_token").unwrap_or(builtin.eos_token.clone()),
+      ),
+      None => (builtin.bos_token.clone(), builtin.eos_token.clone()),
+    };
+
+    if !conf.chat_template_path.is_empty() {
+      let path = Path::new(&conf.chat_template_path);
+
+      let source = if path.extension().is_some_and(|ext| ext == "json") {
+        chat_template_source(&read_tokenizer_config(path)?).ok_or(anyhow::anyhow!(
+          "chat_template not found in {}",
+          path.display()
+        ))?
+      } else {
+        std::fs::read_to_string(path)?
+      };
+
+      tracing::info!("chat template loaded from {}", path.display());
+
+      return Self::new(source, bos_token, eos_token);
+    }
+
+    if let Some(source) = tokenizer_config.as_ref().and_then(chat_template_source) {
+      tracing::info!("chat template loaded from tokenizer_config.json");
+
+      return Self::new(source, bos_token, eos_token);
+    }
+
+    tracing::info!("using built-in chat template for {}", conf.model_id);
+
+    Ok(builtin)
+  }
+
+  pub fn render(
+    &self,
+    messages: &ChatMessages,
+    add_generation_prompt: bool,
+  ) -> anyhow::Result<String> {
+    let messages = messages
+      .iter()
+      .map(template_message)
+      .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let prompt = self.env.get_template(TEMPLATE_NAME)?.render(context! {
+        messages => messages,
+        add_generation_prompt => add_generation_prompt,
+        bos_token => &self.bos_token,
+        eos_token => &self.eos_token,
+    })?;
+
+    Ok(prompt)
+  }
+}
+
+pub fn set_chat_template(template: ChatTemplate) -> anyhow::Result<()> {
+  CHAT_TEMPLATE
+    .set(template)
+    .map_err(|_| anyhow::anyhow!("set_chat_template failed"))?;
+
+  Ok(())
+}
+
+pub fn get_chat_template() -> Option<&'static ChatTemplate> {
+  CHAT_TEMPLATE.get()
+}
+
+fn template_message(message: &ChatMessage) -> anyhow::Result<TemplateMessage> {
+  let (role, content) = match message {
+    ChatMessage::System { content, .. } => ("system", content.as_deref().unwrap_or_default()),
+    ChatMessage::Assistant { content, .. } => ("assistant", content.as_deref().unwrap_or_default()),
+    ChatMessage::Tool { content, .. } => ("tool", content.as_deref().unwrap_or_default()),
+    ChatMessage::User {
+      content: Either::Left(data),
+      ..
+    } => ("user", data.as_ref()),
+    ChatMessage::User {
+      content: Either::Right(data),
+      ..
+    } => {
+      let mut content = String::new();
+
+      for part in data {
+        write!(content, "{part}")?;
+      }
+
+      return Ok(TemplateMessage {
+        role: "user",
+        content,
+      });
+    }
+  };
+
+  Ok(TemplateMessage {
+    role,
+    content: content.to_string(),
+  })
+}
+
+fn tokenizer_config_candidates(conf: &LlmConf) -> Vec<PathBuf> {
+  let mut dirs = vec![];
+
+  for path in [&conf.tokenizer_path, &conf.model_path] {
+    if path.is_empty() {
+      continue;
+    }
+
+    let path = Path::new(path);
+
+    if path.is_dir() {
+      dirs.push(path.to_path_buf());
+    } else if let Some(parent) = path.parent() {
+      dirs.push(parent.to_path_buf());
+    }
+  }
+
+  dirs
+    .into_iter()
+    .map(|dir| dir.join("tokenizer_config.json"))
+    .collect()
+}
+
+fn read_tokenizer_config(path: &Path) -> anyhow::Result<serde_json::Value> {
+  let config = std::fs::read_to_string(path)?;
+
+  Ok(serde_json::from_str(&config)?)
+}
+
+/// `chat_template` is either a single template or a list of named templates, in which case the
+/// one named `default` is used.
+fn chat_template_source(config: &serde_json::Value) -> Option<String> {
+  match config.get("chat_template")? {
+    serde_json::Value::String(source) => Some(source.clone()),
+    serde_json::Value::Array(templates) => templates
+      .iter()
+      .find(|t| t.get("name").and_then(|n| n.as_str()) == Some("default"))
+      .and_then(|t| t.get("template"))
+      .and_then(|t| t.as_str())
+      .map(|s| s.to_string()),
+    _ => None,
+  }
+}
+
+/// Special tokens are either plain strings or `AddedToken` objects with a `content` field.
+fn special_token(config: &serde_json::Value, key: &str) -> Option<String> {
+  match config.get(key)? {
+    serde_json::Value::String(token) => Some(token.clone()),
+    serde_json::Value::Object(token) => token
+      .get("content")
+      .and_then(|c| c.as_str())
+      .map(|s| s.to_string()),
+    _ => None,
+  }
+}
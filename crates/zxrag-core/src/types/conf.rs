@@ -1,6 +1,9 @@
 use serde::{Deserialize, Serialize};
 
-use crate::types::model::{ModelEngine, ModelId};
+use crate::retriever::{DistanceMetric, QueryRewrite, RetrievalMode};
+use crate::text_splitter::TextSplitterKind;
+use crate::types::model::{ModelEngine, ModelId, PoolingStrategy};
+use crate::vector_store::{VectorIndexKind, VectorStoreKind};
 
 #[derive(Debug, Default, Deserialize, Serialize)]
 pub struct BackendConf {
@@ -9,6 +12,12 @@ pub struct BackendConf {
   pub bind_addr: String,
   pub llm_conf: LlmConf,
   pub embedding_conf: EmbeddingConf,
+  pub reranker_conf: RerankerConf,
+  pub text_splitter_conf: TextSplitterConf,
+  pub retrieval_conf: RetrievalConf,
+  pub ingestion_conf: IngestionConf,
+  pub vector_index_conf: VectorIndexConf,
+  pub vector_store: VectorStoreKind,
   pub lancedb_path: String,
   pub database_url: String,
   pub opendal_path: String,
@@ -23,6 +32,12 @@ pub struct LlmConf {
   pub repo_id: String,
   pub tokenizer_path: String,
   pub device: String,
+  /// Jinja template, or a `tokenizer_config.json` holding one, used instead of the model's own.
+  pub chat_template_path: String,
+  /// Threads running generation jobs, each batching the sequences it has admitted.
+  pub worker_threads: usize,
+  /// Sequences a worker thread decodes together before new requests have to wait in the queue.
+  pub max_batch_size: usize,
 }
 
 #[derive(Debug, Default, Deserialize, Serialize)]
@@ -34,6 +49,70 @@ pub struct EmbeddingConf {
   pub repo_id: String,
   pub tokenizer_path: String,
   pub device: String,
+  pub pooling: PoolingStrategy,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct RerankerConf {
+  pub enabled: bool,
+  pub model_id: ModelId,
+  pub model_engine: ModelEngine,
+  pub model_path: String,
+  pub repo_id: String,
+  pub tokenizer_path: String,
+  pub device: String,
+  /// Retrieved chunks scored by the reranker in knowledge base chat, the best ones make the prompt.
+  pub candidates: usize,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct TextSplitterConf {
+  pub splitter: TextSplitterKind,
+  /// Measured in tokens for the token splitter and in characters otherwise.
+  pub chunk_size: usize,
+  pub chunk_overlap: usize,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct RetrievalConf {
+  /// Settings below are used when neither the request nor the knowledge base sets them.
+  pub mode: RetrievalMode,
+  pub top_k: usize,
+  pub metric: DistanceMetric,
+  /// Chunks taken from each ranking before fusion.
+  pub candidates: usize,
+  /// Damping constant of reciprocal rank fusion, higher values flatten the rank weights.
+  pub rrf_k: f32,
+  pub rewrite: QueryRewrite,
+  /// Queries the LLM writes for the `multi_query` rewrite.
+  pub multi_queries: usize,
+  /// Index partitions a vector search probes, ignored for collections without an index.
+  pub nprobes: usize,
+  /// Candidates of an indexed search rescored with their full vectors, as a multiple of the
+  /// candidates kept. 0 keeps the approximate distances of the index.
+  pub refine_factor: u32,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct IngestionConf {
+  /// Jobs extracting, splitting and embedding files at the same time.
+  pub workers: usize,
+  /// Whether uploading a file to a knowledge base queues its embedding unless the request says
+  /// otherwise.
+  pub auto_embed: bool,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct VectorIndexConf {
+  /// Settings below are used when the build request doesn't set them.
+  pub kind: VectorIndexKind,
+  /// IVF partitions, 0 lets LanceDB pick from the number of rows.
+  pub num_partitions: usize,
+  /// PQ sub-vectors, which must divide the embedding dimension. 0 lets LanceDB pick.
+  pub num_sub_vectors: usize,
+  /// Rows a collection holds outside of its index, or at all when it has none, before ingestion
+  /// rebuilds the index. 0 leaves indexes to be built by hand.
+  pub rebuild_rows: usize,
 }
 
 pub fn init_backend_conf(cli_conf_path: &str) -> Result<BackendConf, anyhow::Error> {
@@ -46,11 +125,42 @@ pub fn init_backend_conf(cli_conf_path: &str) -> Result<BackendConf, anyhow::Err
     .set_default("llm_conf.repo_id", "")?
     .set_default("llm_conf.model_path", "")?
     .set_default("llm_conf.tokenizer_path", "")?
+    .set_default("llm_conf.chat_template_path", "")?
+    .set_default("llm_conf.worker_threads", 1)?
+    .set_default("llm_conf.max_batch_size", 8)?
     .set_default("embedding_conf.model_id", "none")?
     .set_default("embedding_conf.model_engine", "huggingface")?
     .set_default("embedding_conf.repo_id", "")?
     .set_default("embedding_conf.model_path", "")?
     .set_default("embedding_conf.tokenizer_path", "")?
+    .set_default("embedding_conf.pooling", "cls")?
+    .set_default("reranker_conf.enabled", false)?
+    .set_default("reranker_conf.model_id", "none")?
+    .set_default("reranker_conf.model_engine", "huggingface")?
+    .set_default("reranker_conf.repo_id", "")?
+    .set_default("reranker_conf.model_path", "")?
+    .set_default("reranker_conf.tokenizer_path", "")?
+    .set_default("reranker_conf.device", "cpu")?
+    .set_default("reranker_conf.candidates", 20)?
+    .set_default("text_splitter_conf.splitter", "token")?
+    .set_default("text_splitter_conf.chunk_size", 256)?
+    .set_default("text_splitter_conf.chunk_overlap", 32)?
+    .set_default("retrieval_conf.mode", "hybrid")?
+    .set_default("retrieval_conf.top_k", 3)?
+    .set_default("retrieval_conf.metric", "l2")?
+    .set_default("retrieval_conf.candidates", 20)?
+    .set_default("retrieval_conf.rrf_k", 60.0)?
+    .set_default("retrieval_conf.rewrite", "none")?
+    .set_default("retrieval_conf.multi_queries", 3)?
+    .set_default("retrieval_conf.nprobes", 20)?
+    .set_default("retrieval_conf.refine_factor", 0)?
+    .set_default("ingestion_conf.workers", 1)?
+    .set_default("ingestion_conf.auto_embed", false)?
+    .set_default("vector_index_conf.kind", "ivf_pq")?
+    .set_default("vector_index_conf.num_partitions", 0)?
+    .set_default("vector_index_conf.num_sub_vectors", 0)?
+    .set_default("vector_index_conf.rebuild_rows", 100000)?
+    .set_default("vector_store", "lancedb")?
     .set_default("lancedb_path", "lancedb")?
     .set_default("database_url", "sqlite:./sqlite.db")?
     .set_default("opendal_path", "opendal")?
@@ -1,23 +1,32 @@
 use std::sync::Arc;
 use std::sync::OnceLock;
+use tokenizers::Tokenizer;
 
 use crate::models::bert::Model as BertModel;
+use crate::models::gemma::Model as GemmaModel;
 use crate::models::llama_cpp::Model as LlamaCppModel;
 use crate::models::phi::Model as PhiModel;
-use crate::types::conf::{EmbeddingConf, LlmConf};
-use crate::types::llm::{TextGeneration, TextGenerationSetting};
+use crate::models::reranker::Model as RerankerModel;
+use crate::types::chat_template::{set_chat_template, ChatTemplate};
+use crate::types::conf::{EmbeddingConf, LlmConf, RerankerConf};
+use crate::types::llm::{LlmModel, TextGeneration, TextGenerationSetting};
 use crate::types::model::ModelId;
 
 pub enum LlmModelHandle {
   LlamaCpp(LlamaCppModel),
   Phi(PhiModel),
+  Gemma(GemmaModel),
 }
 
 pub static LLM_MODEL_HANDLE: OnceLock<LlmModelHandle> = OnceLock::new();
 
 pub static EMBEDDING_MODEL_HANDLE: OnceLock<Arc<BertModel>> = OnceLock::new();
 
+pub static RERANKER_MODEL_HANDLE: OnceLock<Arc<RerankerModel>> = OnceLock::new();
+
 pub fn set_llm_model_handle(model_id: ModelId, conf: &LlmConf) -> anyhow::Result<()> {
+  set_chat_template(ChatTemplate::load(conf)?)?;
+
   match model_id {
     ModelId::Mistral7bInstructV0_2 | ModelId::Mistral7bInstructV0_1 | ModelId::Zephyr7bBeta => {
       let model = LlamaCppModel::new(conf)?;
@@ -37,20 +46,48 @@ pub fn set_llm_model_handle(model_id: ModelId, conf: &LlmConf) -> anyhow::Result
 
       Ok(())
     }
+    ModelId::CodeGemma7bIt => {
+      let model = GemmaModel::new(conf)?;
+
+      LLM_MODEL_HANDLE
+        .set(LlmModelHandle::Gemma(model))
+        .map_err(|_| anyhow::anyhow!("set_llm_model_handle failed"))?;
+
+      Ok(())
+    }
     _ => Err(anyhow::anyhow!("{} not unimplemented", model_id)),
   }
 }
 
-pub fn get_text_gen(setting: TextGenerationSetting) -> anyhow::Result<TextGeneration> {
-  let text_gen = match LLM_MODEL_HANDLE
+/// Returns a copy of the loaded model with its own KV cache. Weights are shared between copies.
+pub fn get_llm_model() -> anyhow::Result<Box<dyn LlmModel + Send + Sync>> {
+  let model: Box<dyn LlmModel + Send + Sync> = match LLM_MODEL_HANDLE
+    .get()
+    .ok_or(anyhow::anyhow!("get_llm_model failed"))?
+  {
+    LlmModelHandle::LlamaCpp(model) => Box::new(model.clone()),
+    LlmModelHandle::Phi(model) => Box::new(model.clone()),
+    LlmModelHandle::Gemma(model) => Box::new(model.clone()),
+  };
+
+  Ok(model)
+}
+
+pub fn get_llm_tokenizer() -> anyhow::Result<&'static Tokenizer> {
+  let tokenizer = match LLM_MODEL_HANDLE
     .get()
-    .ok_or(anyhow::anyhow!("get_text_gen failed"))?
+    .ok_or(anyhow::anyhow!("get_llm_tokenizer failed"))?
   {
-    LlmModelHandle::LlamaCpp(model) => TextGeneration::new(Box::new(model.clone()), setting)?,
-    LlmModelHandle::Phi(model) => TextGeneration::new(Box::new(model.clone()), setting)?,
+    LlmModelHandle::LlamaCpp(model) => model.tokenizer(),
+    LlmModelHandle::Phi(model) => model.tokenizer(),
+    LlmModelHandle::Gemma(model) => model.tokenizer(),
   };
 
-  Ok(text_gen)
+  Ok(tokenizer)
+}
+
+pub fn get_text_gen(setting: TextGenerationSetting) -> anyhow::Result<TextGeneration> {
+  TextGeneration::new(get_llm_model()?, setting)
 }
 
 pub fn set_embedding_model_handle(model_id: ModelId, conf: &EmbeddingConf) -> anyhow::Result<()> {
@@ -72,3 +109,23 @@ pub fn get_embedding_model(model_id: ModelId) -> anyhow::Result<Arc<BertModel>>
 
   Ok(model.clone())
 }
+
+pub fn set_reranker_model_handle(model_id: ModelId, conf: &RerankerConf) -> anyhow::Result<()> {
+  tracing::info!("{}", model_id);
+
+  RERANKER_MODEL_HANDLE
+    .set(Arc::new(RerankerModel::new(conf)?))
+    .map_err(|_| anyhow::anyhow!("set_reranker_model_handle failed"))?;
+
+  Ok(())
+}
+
+pub fn get_reranker_model(model_id: ModelId) -> anyhow::Result<Arc<RerankerModel>> {
+  tracing::info!("{}", model_id);
+
+  let model = RERANKER_MODEL_HANDLE
+    .get()
+    .ok_or(anyhow::anyhow!("get_reranker_model failed"))?;
+
+  Ok(model.clone())
+}
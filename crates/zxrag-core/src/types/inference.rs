@@ -0,0 +1,321 @@
+use serde::{Deserialize, Serialize};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError, TryLockError};
+use tokio::sync::mpsc;
+
+use crate::types::conf::LlmConf;
+use crate::types::llm::{TextGenerationChunk, TextGenerationOutput, TextGenerationSetting};
+use crate::types::scheduler::{ChunkSender, Scheduler};
+use crate::util::panic_message;
+
+const JOB_QUEUE_CAPACITY: usize = 64;
+
+pub static INFERENCE_WORKER: OnceLock<InferenceWorker> = OnceLock::new();
+
+pub type ChunkReceiver = mpsc::UnboundedReceiver<anyhow::Result<TextGenerationChunk>>;
+
+struct InferenceJob {
+  setting: TextGenerationSetting,
+  sender: ChunkSender,
+}
+
+#[derive(Default)]
+struct InferenceCounters {
+  queued: AtomicUsize,
+  running: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+pub struct InferenceStatus {
+  /// Jobs waiting to be admitted by a worker thread.
+  pub queued: usize,
+  /// Sequences currently being generated, across all worker threads.
+  pub running: usize,
+  pub worker_threads: usize,
+  pub max_batch_size: usize,
+}
+
+/// Runs text generation on dedicated threads so forward passes never block the async runtime.
+/// Jobs are queued over a channel and each thread batches the sequences it has admitted, see
+/// [`Scheduler`]. Chunks are sent back as soon as they are decoded.
+pub struct InferenceWorker {
+  jobs: SyncSender<InferenceJob>,
+  counters: Arc<InferenceCounters>,
+  worker_threads: usize,
+  max_batch_size: usize,
+}
+
+impl InferenceWorker {
+  pub fn new(conf: &LlmConf) -> anyhow::Result<Self> {
+    let worker_threads = conf.worker_threads.max(1);
+    let max_batch_size = conf.max_batch_size.max(1);
+
+    let (jobs, receiver) = sync_channel(JOB_QUEUE_CAPACITY);
+
+    let receiver = Arc::new(Mutex::new(receiver));
+
+    let counters = Arc::new(InferenceCounters::default());
+
+    for index in 0..worker_threads {
+      let receiver = receiver.clone();
+      let counters = counters.clone();
+      let scheduler = Scheduler::new(max_batch_size)?;
+
+      std::thread::Builder::new()
+        .name(format!("zxrag-inference-{index}"))
+        .spawn(move || run_worker(scheduler, &receiver, &counters))?;
+    }
+
+    tracing::info!(
+      "{} inference worker threads started, up to {} sequences each",
+      worker_threads,
+      max_batch_size
+    );
+
+    Ok(Self {
+      jobs,
+      counters,
+      worker_threads,
+      max_batch_size,
+    })
+  }
+
+  /// Queues a generation job. Dropping the returned receiver cancels the job before its next
+  /// forward pass, which is how client disconnects stop generation.
+  pub fn submit(&self, setting: TextGenerationSetting) -> anyhow::Result<ChunkReceiver> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+
+    self.counters.queued.fetch_add(1, Ordering::Relaxed);
+
+    self
+      .jobs
+      .try_send(InferenceJob { setting, sender })
+      .map_err(|e| {
+        self.counters.queued.fetch_sub(1, Ordering::Relaxed);
+
+        match e {
+          TrySendError::Full(_) => anyhow::anyhow!("inference queue is full"),
+          TrySendError::Disconnected(_) => anyhow::anyhow!("inference worker stopped"),
+        }
+      })?;
+
+    Ok(receiver)
+  }
+
+  pub async fn generate(
+    &self,
+    setting: TextGenerationSetting,
+  ) -> anyhow::Result<TextGenerationOutput> {
+    let mut receiver = self.submit(setting)?;
+
+    let mut output = TextGenerationOutput::default();
+
+    while let Some(chunk) = receiver.recv().await {
+      let chunk = chunk?;
+
+      output.text.push_str(&chunk.text);
+
+      if let Some(finish_reason) = chunk.finish_reason {
+        output.finish_reason = finish_reason;
+      }
+
+      if let Some(usage) = chunk.usage {
+        output.usage = usage;
+      }
+    }
+
+    Ok(output)
+  }
+
+  pub fn status(&self) -> InferenceStatus {
+    InferenceStatus {
+      queued: self.counters.queued.load(Ordering::Relaxed),
+      running: self.counters.running.load(Ordering::Relaxed),
+      worker_threads: self.worker_threads,
+      max_batch_size: self.max_batch_size,
+    }
+  }
+}
+
+pub fn set_inference_worker(conf: &LlmConf) -> anyhow::Result<()> {
+  INFERENCE_WORKER
+    .set(InferenceWorker::new(conf)?)
+    .map_err(|_| anyhow::anyhow!("set_inference_worker failed"))?;
+
+  Ok(())
+}
+
+pub fn get_inference_worker() -> anyhow::Result<&'static InferenceWorker> {
+  INFERENCE_WORKER
+    .get()
+    .ok_or(anyhow::anyhow!("get_inference_worker failed"))
+}
+
+fn run_worker(
+  mut scheduler: Scheduler,
+  receiver: &Mutex<Receiver<InferenceJob>>,
+  counters: &InferenceCounters,
+) {
+  // Sequences this thread has reported in `counters.running`
+  let mut running = 0;
+
+  loop {
+    while scheduler.has_capacity() {
+      let job = if scheduler.is_empty() {
+        // Nothing to decode, so wait for work while holding the lock. The receiver stays usable
+        // when another thread panicked holding it, steps are unwound below and never hold it.
+        receiver
+          .lock()
+          .unwrap_or_else(PoisonError::into_inner)
+          .recv()
+          .ok()
+      } else {
+        // Another idle thread may be waiting on the lock, in which case it takes the next job
+        let receiver = match receiver.try_lock() {
+          Ok(receiver) => receiver,
+          Err(TryLockError::Poisoned(e)) => e.into_inner(),
+          Err(TryLockError::WouldBlock) => break,
+        };
+
+        match receiver.try_recv() {
+          Ok(job) => Some(job),
+          Err(TryRecvError::Empty) => break,
+          Err(TryRecvError::Disconnected) => None,
+        }
+      };
+
+      let Some(InferenceJob { setting, sender }) = job else {
+        if scheduler.is_empty() {
+          return;
+        }
+
+        break;
+      };
+
+      counters.queued.fetch_sub(1, Ordering::Relaxed);
+
+      if let Err(payload) = catch_unwind(AssertUnwindSafe(|| scheduler.admit(setting, sender))) {
+        tracing::error!(
+          "admitting a sequence panicked: {}",
+          panic_message(&*payload)
+        );
+      }
+    }
+
+    running = report_running(counters, running, scheduler.len());
+
+    // A panic in a forward pass only fails the sequences of this thread, which keeps serving
+    if let Err(payload) = catch_unwind(AssertUnwindSafe(|| scheduler.step())) {
+      let reason = panic_message(&*payload);
+      tracing::error!("generation step panicked: {}", reason);
+
+      scheduler.fail_all(reason);
+    }
+
+    running = report_running(counters, running, scheduler.len());
+  }
+}
+
+fn report_running(counters: &InferenceCounters, reported: usize, running: usize) -> usize {
+  counters.running.fetch_add(running, Ordering::Relaxed);
+  counters.running.fetch_sub(reported, Ordering::Relaxed);
+
+  running
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::types::llm::{FinishReason, TextGenerationUsage};
+
+  fn worker(capacity: usize) -> (InferenceWorker, Receiver<InferenceJob>) {
+    let (jobs, receiver) = sync_channel(capacity);
+
+    let worker = InferenceWorker {
+      jobs,
+      counters: Arc::default(),
+      worker_threads: 1,
+      max_batch_size: 4,
+    };
+
+    (worker, receiver)
+  }
+
+  #[test]
+  fn submit_refuses_jobs_once_the_queue_is_full() {
+    let (worker, _receiver) = worker(1);
+
+    worker.submit(TextGenerationSetting::default()).unwrap();
+
+    let e = worker.submit(TextGenerationSetting::default()).unwrap_err();
+
+    assert_eq!(e.to_string(), "inference queue is full");
+    assert_eq!(worker.status().queued, 1);
+  }
+
+  #[test]
+  fn submit_fails_once_the_workers_stopped() {
+    let (worker, receiver) = worker(1);
+
+    drop(receiver);
+
+    let e = worker.submit(TextGenerationSetting::default()).unwrap_err();
+
+    assert_eq!(e.to_string(), "inference worker stopped");
+    assert_eq!(worker.status().queued, 0);
+  }
+
+  #[tokio::test]
+  async fn generate_joins_the_chunks_of_a_job() {
+    let (worker, receiver) = worker(1);
+
+    std::thread::spawn(move || {
+      let InferenceJob { sender, .. } = receiver.recv().unwrap();
+
+      for text in ["Hello", ", world"] {
+        let _ = sender.send(Ok(TextGenerationChunk {
+          text: text.to_string(),
+          ..Default::default()
+        }));
+      }
+
+      let _ = sender.send(Ok(TextGenerationChunk {
+        finish_reason: Some(FinishReason::Stop),
+        ..Default::default()
+      }));
+      let _ = sender.send(Ok(TextGenerationChunk {
+        usage: Some(TextGenerationUsage {
+          prompt_tokens: 3,
+          completion_tokens: 2,
+        }),
+        ..Default::default()
+      }));
+    });
+
+    let output = worker
+      .generate(TextGenerationSetting::default())
+      .await
+      .unwrap();
+
+    assert_eq!(output.text, "Hello, world");
+    assert_eq!(output.finish_reason, FinishReason::Stop);
+    assert_eq!(output.usage.completion_tokens, 2);
+  }
+
+  #[test]
+  fn running_sequences_are_reported_per_thread() {
+    let counters = InferenceCounters::default();
+
+    let first = report_running(&counters, 0, 3);
+    let second = report_running(&counters, 0, 2);
+
+    assert_eq!(counters.running.load(Ordering::Relaxed), 5);
+
+    report_running(&counters, first, 1);
+    report_running(&counters, second, 0);
+
+    assert_eq!(counters.running.load(Ordering::Relaxed), 1);
+  }
+}
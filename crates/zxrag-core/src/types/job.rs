@@ -0,0 +1,27 @@
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumString};
+
+#[derive(
+  Clone, Default, Debug, Copy, PartialEq, Eq, Deserialize, Serialize, EnumString, Display,
+)]
+pub enum JobStatus {
+  #[default]
+  #[serde(rename = "queued")]
+  #[strum(serialize = "queued")]
+  Queued,
+  #[serde(rename = "running")]
+  #[strum(serialize = "running")]
+  Running,
+  #[serde(rename = "succeeded")]
+  #[strum(serialize = "succeeded")]
+  Succeeded,
+  #[serde(rename = "failed")]
+  #[strum(serialize = "failed")]
+  Failed,
+}
+
+impl JobStatus {
+  pub fn is_finished(&self) -> bool {
+    matches!(self, JobStatus::Succeeded | JobStatus::Failed)
+  }
+}
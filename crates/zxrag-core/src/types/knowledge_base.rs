@@ -7,6 +7,8 @@ pub struct EmbeddingResponse<'a> {
   pub embeddings: Vec<Embedding<'a>>,
   pub model: Cow<'a, str>,
   pub usage: EmbeddingsUsage,
+  /// Offset of the next page, `None` on the last one.
+  pub next_offset: Option<usize>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -17,7 +19,11 @@ pub struct Embedding<'a> {
   pub filename: Cow<'a, str>,
   pub object: Cow<'a, str>,
   pub text: Cow<'a, str>,
-  pub embedding: Vec<f32>,
+  pub heading_path: Cow<'a, str>,
+  /// `None` when vectors were left out of the listing.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub embedding: Option<Vec<f32>>,
+  /// Position of the row among those matching the listing filters.
   pub index: usize,
 }
 
@@ -1,26 +1,50 @@
+use arrow_array::types::Float32Type;
+use arrow_array::{Array, FixedSizeListArray, Float32Array, Int64Array, RecordBatch, StringArray};
 use arrow_schema::{DataType, Field, Schema};
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::OnceLock;
 
+use crate::retriever::RetrievedChunk;
+use crate::types::model::ModelId;
+use crate::vector_store::Chunk;
+
+pub const EMBEDDING_MODEL_METADATA_KEY: &str = "zxrag.embedding_model";
+pub const EMBEDDING_DIMENSION_METADATA_KEY: &str = "zxrag.embedding_dimension";
+
 pub static EMBEDDING_SCHEMA: OnceLock<Arc<Schema>> = OnceLock::new();
 
-pub fn set_embedding_schema() -> anyhow::Result<()> {
+pub fn set_embedding_schema(model_id: ModelId, dimension: usize) -> anyhow::Result<()> {
   EMBEDDING_SCHEMA
-    .set(Arc::new(Schema::new(vec![
-      Field::new("id", DataType::Utf8, false),
-      Field::new("kb_id", DataType::Int64, false),
-      Field::new("file_id", DataType::Int64, false),
-      Field::new("file_name", DataType::Utf8, false),
-      Field::new("text", DataType::Utf8, false),
-      Field::new(
-        "vector",
-        DataType::FixedSizeList(
-          Arc::new(Field::new("item", DataType::Float32, true)),
-          1024_i32,
+    .set(Arc::new(
+      Schema::new(vec![
+        Field::new("id", DataType::Utf8, false),
+        Field::new("kb_id", DataType::Int64, false),
+        Field::new("file_id", DataType::Int64, false),
+        Field::new("file_name", DataType::Utf8, false),
+        Field::new("text", DataType::Utf8, false),
+        // Nullable and optional, tables created before it was added don't have it
+        Field::new("heading_path", DataType::Utf8, true),
+        Field::new(
+          "vector",
+          DataType::FixedSizeList(
+            Arc::new(Field::new("item", DataType::Float32, true)),
+            i32::try_from(dimension)?,
+          ),
+          false,
+        ),
+      ])
+      .with_metadata(HashMap::from([
+        (
+          EMBEDDING_MODEL_METADATA_KEY.to_string(),
+          model_id.to_string(),
         ),
-        false,
-      ),
-    ])))
+        (
+          EMBEDDING_DIMENSION_METADATA_KEY.to_string(),
+          dimension.to_string(),
+        ),
+      ])),
+    ))
     .map_err(|_| anyhow::anyhow!("init_embedding_schema failed"))?;
 
   Ok(())
@@ -33,3 +57,269 @@ pub fn get_embedding_schema() -> anyhow::Result<Arc<Schema>> {
 
   Ok(schema.clone())
 }
+
+pub fn get_embedding_dimension() -> anyhow::Result<usize> {
+  let schema = get_embedding_schema()?;
+
+  vector_dimension(&schema).ok_or(anyhow::anyhow!("get_embedding_dimension failed"))
+}
+
+pub fn vector_dimension(schema: &Schema) -> Option<usize> {
+  match schema.field_with_name("vector").ok()?.data_type() {
+    DataType::FixedSizeList(_, size) => usize::try_from(*size).ok(),
+    _ => None,
+  }
+}
+
+/// Embedding model recorded in the metadata of a knowledge base table.
+pub fn embedding_model(schema: &Schema) -> Option<&str> {
+  schema
+    .metadata()
+    .get(EMBEDDING_MODEL_METADATA_KEY)
+    .map(|s| s.as_str())
+}
+
+/// Checks that a knowledge base table was built with the embedding model that is loaded now.
+/// Tables created before the model was recorded in their metadata are only checked by dimension.
+pub fn check_embedding_schema(table_schema: &Schema) -> anyhow::Result<()> {
+  let schema = get_embedding_schema()?;
+
+  let expected_model = embedding_model(&schema).unwrap_or_default();
+  let expected_dimension = vector_dimension(&schema);
+
+  let model = embedding_model(table_schema);
+  let dimension = vector_dimension(table_schema);
+
+  if dimension != expected_dimension || model.is_some_and(|model| model != expected_model) {
+    anyhow::bail!(
+      "table was built with {} ({} dims) but the loaded embedding model is {} ({} dims)",
+      model.unwrap_or("an unknown model"),
+      dimension.unwrap_or_default(),
+      expected_model,
+      expected_dimension.unwrap_or_default(),
+    );
+  }
+
+  Ok(())
+}
+
+/// Reads every row of a knowledge base table batch, vectors excepted. Vector search results carry
+/// their distance in `_distance`, which becomes the score.
+pub fn record_batch_chunks(batch: &RecordBatch) -> anyhow::Result<Vec<RetrievedChunk>> {
+  let id = string_column(batch, "id")?;
+  let kb_id = int64_column(batch, "kb_id")?;
+  let file_id = int64_column(batch, "file_id")?;
+  let file_name = string_column(batch, "file_name")?;
+  let text = string_column(batch, "text")?;
+  let heading_path = batch
+    .column_by_name("heading_path")
+    .map(|column| {
+      column
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .ok_or(anyhow::anyhow!("column heading_path is not a string"))
+    })
+    .transpose()?;
+  let distance = batch
+    .column_by_name("_distance")
+    .and_then(|column| column.as_any().downcast_ref::<Float32Array>());
+
+  Ok(
+    (0..batch.num_rows())
+      .map(|row| RetrievedChunk {
+        id: id.value(row).to_string(),
+        kb_id: kb_id.value(row),
+        file_id: file_id.value(row),
+        file_name: file_name.value(row).to_string(),
+        text: text.value(row).to_string(),
+        heading_path: heading_path
+          .filter(|heading_path| heading_path.is_valid(row))
+          .map_or(String::new(), |heading_path| {
+            heading_path.value(row).to_string()
+          }),
+        score: distance.map_or(0.0, |distance| distance.value(row)),
+      })
+      .collect(),
+  )
+}
+
+/// Reads the `vector` column of a knowledge base table batch, in row order.
+pub fn record_batch_vectors(batch: &RecordBatch) -> anyhow::Result<Vec<Vec<f32>>> {
+  let vector = batch
+    .column_by_name("vector")
+    .and_then(|column| column.as_any().downcast_ref::<FixedSizeListArray>())
+    .ok_or(anyhow::anyhow!(
+      "column vector is missing or not a fixed size list"
+    ))?;
+
+  (0..batch.num_rows())
+    .map(|row| {
+      let values = vector.value(row);
+
+      let values = values
+        .as_any()
+        .downcast_ref::<Float32Array>()
+        .ok_or(anyhow::anyhow!("column vector does not hold float32"))?;
+
+      Ok(values.values().to_vec())
+    })
+    .collect()
+}
+
+/// Builds a batch of rows of a knowledge base table with its `schema`, which may lack the columns
+/// added after the table was created.
+pub fn chunks_record_batch(chunks: &[Chunk], schema: Arc<Schema>) -> anyhow::Result<RecordBatch> {
+  let dimension = vector_dimension(&schema).ok_or(anyhow::anyhow!("table has no vector column"))?;
+  let dimension = i32::try_from(dimension)?;
+
+  let columns = schema
+    .fields()
+    .iter()
+    .map(|field| -> anyhow::Result<Arc<dyn Array>> {
+      Ok(match field.name().as_str() {
+        "id" => Arc::new(StringArray::from_iter_values(
+          chunks.iter().map(|chunk| chunk.id.as_str()),
+        )),
+        "kb_id" => Arc::new(Int64Array::from_iter_values(
+          chunks.iter().map(|chunk| chunk.kb_id),
+        )),
+        "file_id" => Arc::new(Int64Array::from_iter_values(
+          chunks.iter().map(|chunk| chunk.file_id),
+        )),
+        "file_name" => Arc::new(StringArray::from_iter_values(
+          chunks.iter().map(|chunk| chunk.file_name.as_str()),
+        )),
+        "text" => Arc::new(StringArray::from_iter_values(
+          chunks.iter().map(|chunk| chunk.text.as_str()),
+        )),
+        "heading_path" => Arc::new(StringArray::from_iter_values(
+          chunks.iter().map(|chunk| chunk.heading_path.as_str()),
+        )),
+        "vector" => {
+          let vectors = chunks
+            .iter()
+            .map(|chunk| Some(chunk.vector.iter().copied().map(Some).collect::<Vec<_>>()));
+
+          Arc::new(FixedSizeListArray::from_iter_primitive::<Float32Type, _, _>(vectors, dimension))
+        }
+        name => anyhow::bail!("unknown column {}", name),
+      })
+    })
+    .collect::<anyhow::Result<Vec<_>>>()?;
+
+  Ok(RecordBatch::try_new(schema, columns)?)
+}
+
+fn string_column<'a>(batch: &'a RecordBatch, name: &str) -> anyhow::Result<&'a StringArray> {
+  batch
+    .column_by_name(name)
+    .and_then(|column| column.as_any().downcast_ref::<StringArray>())
+    .ok_or(anyhow::anyhow!(
+      "column {} is missing or not a string",
+      name
+    ))
+}
+
+fn int64_column<'a>(batch: &'a RecordBatch, name: &str) -> anyhow::Result<&'a Int64Array> {
+  batch
+    .column_by_name(name)
+    .and_then(|column| column.as_any().downcast_ref::<Int64Array>())
+    .ok_or(anyhow::anyhow!(
+      "column {} is missing or not an int64",
+      name
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn schema(heading_path: bool) -> Arc<Schema> {
+    let mut fields = vec![
+      Field::new("id", DataType::Utf8, false),
+      Field::new("kb_id", DataType::Int64, false),
+      Field::new("file_id", DataType::Int64, false),
+      Field::new("file_name", DataType::Utf8, false),
+      Field::new("text", DataType::Utf8, false),
+    ];
+
+    if heading_path {
+      fields.push(Field::new("heading_path", DataType::Utf8, true));
+    }
+
+    fields.push(Field::new(
+      "vector",
+      DataType::FixedSizeList(Arc::new(Field::new("item", DataType::Float32, true)), 2),
+      false,
+    ));
+
+    Arc::new(Schema::new(fields))
+  }
+
+  fn chunk(id: &str, heading_path: &str) -> Chunk {
+    Chunk {
+      id: id.to_string(),
+      kb_id: 1,
+      file_id: 2,
+      file_name: "a.md".to_string(),
+      text: format!("text of {}", id),
+      heading_path: heading_path.to_string(),
+      vector: vec![1.0, 0.0],
+    }
+  }
+
+  #[test]
+  fn chunks_round_trip_with_their_heading_path() {
+    let chunks = vec![chunk("a", "Guide > Install"), chunk("b", "")];
+
+    let batch = chunks_record_batch(&chunks, schema(true)).unwrap();
+    let read = record_batch_chunks(&batch).unwrap();
+
+    assert_eq!(read.len(), 2);
+    assert_eq!(read[0].heading_path, "Guide > Install");
+    assert_eq!(read[1].heading_path, "");
+    assert_eq!(
+      record_batch_vectors(&batch).unwrap(),
+      vec![vec![1.0, 0.0]; 2]
+    );
+  }
+
+  #[test]
+  fn tables_without_heading_path_are_written_and_read() {
+    let chunks = vec![chunk("a", "Guide > Install")];
+
+    let batch = chunks_record_batch(&chunks, schema(false)).unwrap();
+
+    assert_eq!(batch.num_columns(), 6);
+
+    let read = record_batch_chunks(&batch).unwrap();
+
+    assert_eq!(read[0].id, "a");
+    assert_eq!(read[0].text, "text of a");
+    assert_eq!(read[0].heading_path, "");
+  }
+
+  #[test]
+  fn null_heading_paths_read_as_empty() {
+    let batch = RecordBatch::try_new(
+      schema(true),
+      vec![
+        Arc::new(StringArray::from(vec!["a"])),
+        Arc::new(Int64Array::from(vec![1])),
+        Arc::new(Int64Array::from(vec![2])),
+        Arc::new(StringArray::from(vec!["a.md"])),
+        Arc::new(StringArray::from(vec!["text"])),
+        Arc::new(StringArray::from(vec![None::<&str>])),
+        Arc::new(
+          FixedSizeListArray::from_iter_primitive::<Float32Type, _, _>(
+            vec![Some(vec![Some(1.0), Some(0.0)])],
+            2,
+          ),
+        ),
+      ],
+    )
+    .unwrap();
+
+    assert_eq!(record_batch_chunks(&batch).unwrap()[0].heading_path, "");
+  }
+}
@@ -1,18 +1,25 @@
 use candle_core::{Device, Tensor};
 use candle_transformers::generation::LogitsProcessor;
-use futures::Stream;
 use serde::{Deserialize, Serialize};
-use std::pin::Pin;
-use std::task::{Context, Poll};
+use strum::{Display, EnumString};
 use tokenizers::Tokenizer;
 
+use crate::models::quantized_llama::KvCache;
 use crate::types::{
   model::{ModelEngine, ModelId},
   token_output_stream::TokenOutputStream,
 };
 use crate::util::eos_token;
 
-const MAX_SEQ_LEN: usize = 4096;
+pub const MAX_SEQ_LEN: usize = 4096;
+
+/// Tokens of the context window a prompt and its answer leave unused.
+const SEQ_LEN_MARGIN: usize = 10;
+
+/// Tokens a prompt may take for `sample_len` tokens to be generated after it.
+pub fn prompt_budget(sample_len: usize) -> usize {
+  MAX_SEQ_LEN.saturating_sub(sample_len + SEQ_LEN_MARGIN)
+}
 
 pub trait LlmModel: Send + Sync {
   fn id(&self) -> ModelId;
@@ -20,6 +27,18 @@ pub trait LlmModel: Send + Sync {
   fn tokenizer(&self) -> &Tokenizer;
   fn device(&self) -> &Device;
   fn forward(&mut self, x: &Tensor, index_pos: usize) -> anyhow::Result<Tensor>;
+
+  /// The KV cache of this copy of the model. Only models that support `forward_batch` have one
+  /// the caller can reach.
+  fn kv_cache(&mut self) -> Option<&mut KvCache> {
+    None
+  }
+
+  /// Decodes one token per sequence in a single forward pass, `tokens[i]` continuing from
+  /// `caches[i]`, and returns logits of shape `(batch, vocab)`.
+  fn forward_batch(&self, _tokens: &[u32], _caches: &mut [&mut KvCache]) -> anyhow::Result<Tensor> {
+    anyhow::bail!("{} does not support batched decoding", self.id())
+  }
 }
 
 #[derive(Debug, Default, Deserialize, Serialize)]
@@ -31,6 +50,51 @@ pub struct TextGenerationSetting {
   pub repeat_last_n: usize,
   pub sample_len: usize,
   pub prompt: String,
+  #[serde(default)]
+  pub stop: Vec<String>,
+}
+
+#[derive(
+  Clone, Default, Debug, Copy, PartialEq, Eq, Deserialize, Serialize, EnumString, Display,
+)]
+pub enum FinishReason {
+  /// The model emitted its EOS token or one of the stop sequences.
+  #[serde(rename = "stop")]
+  #[strum(serialize = "stop")]
+  Stop,
+  /// `sample_len` tokens were generated.
+  #[default]
+  #[serde(rename = "length")]
+  #[strum(serialize = "length")]
+  Length,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TextGenerationUsage {
+  pub prompt_tokens: usize,
+  pub completion_tokens: usize,
+}
+
+impl TextGenerationUsage {
+  pub fn total_tokens(&self) -> usize {
+    self.prompt_tokens + self.completion_tokens
+  }
+}
+
+#[derive(Debug, Default)]
+pub struct TextGenerationOutput {
+  pub text: String,
+  pub finish_reason: FinishReason,
+  pub usage: TextGenerationUsage,
+}
+
+#[derive(Debug, Default)]
+pub struct TextGenerationChunk {
+  pub text: String,
+  /// Only set on the last text chunk of the stream.
+  pub finish_reason: Option<FinishReason>,
+  /// Only set on the trailing chunk that follows the last text chunk, which carries no text.
+  pub usage: Option<TextGenerationUsage>,
 }
 
 pub struct TextGeneration {
@@ -55,7 +119,8 @@ impl TextGeneration {
 
     let logits_processor = LogitsProcessor::new(setting.seed, temperature, setting.top_p);
 
-    let token_output_stream = TokenOutputStream::new(model.tokenizer().clone());
+    let token_output_stream =
+      TokenOutputStream::new(model.tokenizer().clone()).with_stop_sequences(setting.stop.clone());
 
     let eos_token = eos_token(model.id());
 
@@ -75,16 +140,12 @@ impl TextGeneration {
     })
   }
 
-  pub fn generate(&mut self) -> anyhow::Result<String> {
+  pub fn generate(&mut self) -> anyhow::Result<TextGenerationOutput> {
     tracing::info!("prompt={}", self.setting.prompt);
 
-    let tokens = self
-      .token_output_stream
-      .tokenizer()
-      .encode(self.setting.prompt.clone(), true)
-      .map_err(anyhow::Error::msg)?;
+    let prompt_tokens = self.encode_prompt()?;
 
-    let prompt_tokens = tokens.get_ids().to_vec();
+    let prompt_tokens_len = prompt_tokens.len();
 
     self.all_tokens.extend(prompt_tokens);
 
@@ -94,6 +155,8 @@ impl TextGeneration {
 
     let mut output = String::new();
 
+    let mut finish_reason = FinishReason::Length;
+
     for index in 0..self.setting.sample_len {
       let context_size = if index > 0 { 1 } else { self.all_tokens.len() };
 
@@ -106,6 +169,7 @@ impl TextGeneration {
       generated_tokens += 1;
 
       if next_token == self.eos_token {
+        finish_reason = FinishReason::Stop;
         break;
       };
 
@@ -114,6 +178,11 @@ impl TextGeneration {
 
         output.push_str(&t);
       }
+
+      if self.token_output_stream.is_stopped() {
+        finish_reason = FinishReason::Stop;
+        break;
+      }
     }
 
     if let Some(rest) = self
@@ -132,27 +201,82 @@ impl TextGeneration {
       generated_tokens as f64 / dt.as_secs_f64(),
     );
 
-    Ok(output)
+    Ok(TextGenerationOutput {
+      text: output,
+      finish_reason,
+      usage: TextGenerationUsage {
+        prompt_tokens: prompt_tokens_len,
+        completion_tokens: generated_tokens,
+      },
+    })
+  }
+
+  /// Encodes the prompt and shortens `sample_len` so that both fit in the context window. A prompt
+  /// leaving no room for a single token is refused, callers leave out old turns beforehand, see
+  /// [`prompt_budget`].
+  fn encode_prompt(&mut self) -> anyhow::Result<Vec<u32>> {
+    // Chat templates already emit the special tokens the model expects
+    let tokens = self
+      .token_output_stream
+      .tokenizer()
+      .encode(self.setting.prompt.clone(), false)
+      .map_err(anyhow::Error::msg)?
+      .get_ids()
+      .to_vec();
+
+    if tokens.is_empty() {
+      anyhow::bail!("the prompt is empty");
+    }
+
+    let max_sample_len = MAX_SEQ_LEN.saturating_sub(tokens.len() + SEQ_LEN_MARGIN);
+
+    if max_sample_len == 0 {
+      anyhow::bail!(
+        "the prompt takes {} tokens, which leaves no room in the context window of {}",
+        tokens.len(),
+        MAX_SEQ_LEN
+      );
+    }
+
+    if self.setting.sample_len > max_sample_len {
+      tracing::info!(
+        "sample_len {} shortened to {} to fit the context window",
+        self.setting.sample_len,
+        max_sample_len
+      );
+
+      self.setting.sample_len = max_sample_len;
+    }
+
+    Ok(tokens)
   }
 
   pub fn forward_token(&mut self, index_pos: usize) -> anyhow::Result<u32> {
+    let logits = self.forward_logits(index_pos)?;
+
+    self.sample(&logits)
+  }
+
+  fn forward_logits(&mut self, index_pos: usize) -> anyhow::Result<Tensor> {
     let ctxt = &self.all_tokens[index_pos..];
 
     let input = Tensor::new(ctxt, self.model.device())?.unsqueeze(0)?;
 
     let logits = self.model.forward(&input, index_pos)?;
 
-    let logits = logits.squeeze(0)?;
+    Ok(logits.squeeze(0)?)
+  }
 
+  fn sample(&mut self, logits: &Tensor) -> anyhow::Result<u32> {
     let logits = if self.setting.repeat_penalty == 1. {
-      logits
+      logits.clone()
     } else {
       let start_at = self
         .all_tokens
         .len()
         .saturating_sub(self.setting.repeat_last_n);
       candle_transformers::utils::apply_repeat_penalty(
-        &logits,
+        logits,
         self.setting.repeat_penalty,
         &self.all_tokens[start_at..],
       )?
@@ -164,45 +288,158 @@ impl TextGeneration {
 
 pub struct TextGenerationStream {
   pub text_gen: TextGeneration,
+  prompt_tokens: usize,
   generated_tokens: usize,
+  finished: bool,
+  usage_sent: bool,
 }
 
 impl TextGenerationStream {
   pub fn new(mut text_gen: TextGeneration) -> anyhow::Result<Self> {
     tracing::info!("prompt_str={}", &text_gen.setting.prompt);
 
-    let tokens = text_gen
-      .token_output_stream
-      .tokenizer()
-      .encode(text_gen.setting.prompt.clone(), true)
-      .map_err(anyhow::Error::msg)?;
-
-    let mut prompt_tokens = tokens.get_ids().to_owned();
+    let prompt_tokens = text_gen.encode_prompt()?;
 
-    prompt_tokens = if prompt_tokens.len() + text_gen.setting.sample_len > MAX_SEQ_LEN - 10 {
-      let to_remove = prompt_tokens.len() + text_gen.setting.sample_len + 10 - MAX_SEQ_LEN;
-      prompt_tokens[prompt_tokens.len().saturating_sub(to_remove)..].to_vec()
-    } else {
-      prompt_tokens
-    };
+    let prompt_tokens_len = prompt_tokens.len();
 
-    text_gen.all_tokens.extend(prompt_tokens.clone());
+    text_gen.all_tokens.extend(prompt_tokens);
 
     Ok(Self {
       text_gen,
+      prompt_tokens: prompt_tokens_len,
       generated_tokens: 0,
+      finished: false,
+      usage_sent: false,
     })
   }
+
+  fn finish(&mut self, finish_reason: FinishReason) -> TextGenerationChunk {
+    self.finished = true;
+
+    let text = self
+      .text_gen
+      .token_output_stream
+      .decode_rest()
+      .ok()
+      .flatten()
+      .unwrap_or_default();
+
+    TextGenerationChunk {
+      text,
+      finish_reason: Some(finish_reason),
+      usage: None,
+    }
+  }
+
+  pub fn usage(&self) -> TextGenerationUsage {
+    TextGenerationUsage {
+      prompt_tokens: self.prompt_tokens,
+      completion_tokens: self.generated_tokens,
+    }
+  }
+
+  /// Whether every chunk, including the trailing usage chunk, has been produced.
+  pub fn is_done(&self) -> bool {
+    self.finished && self.usage_sent
+  }
+
+  /// Whether the prompt has been through the model, after which each step decodes one token.
+  pub fn is_prefilled(&self) -> bool {
+    self.generated_tokens > 0
+  }
+
+  pub fn last_token(&self) -> Option<u32> {
+    self.text_gen.all_tokens.last().copied()
+  }
+
+  pub fn model_mut(&mut self) -> &mut (dyn LlmModel + Send + Sync) {
+    self.text_gen.model.as_mut()
+  }
+
+  /// Returns the next chunk when it doesn't need a forward pass: the final chunk once
+  /// `sample_len` tokens have been generated, or the trailing usage chunk.
+  pub fn pending_chunk(&mut self) -> Option<TextGenerationChunk> {
+    if self.finished {
+      if self.usage_sent {
+        return None;
+      }
+
+      self.usage_sent = true;
+
+      return Some(TextGenerationChunk {
+        usage: Some(self.usage()),
+        ..Default::default()
+      });
+    }
+
+    // `sample_len` is fitted to the context window when the stream is created, the context length
+    // is checked as well so a sequence can never grow past the rope tables
+    if self.generated_tokens >= self.text_gen.setting.sample_len
+      || self.text_gen.all_tokens.len() >= MAX_SEQ_LEN
+    {
+      return Some(self.finish(FinishReason::Length));
+    }
+
+    None
+  }
+
+  /// Samples the next token from `logits` of shape `(vocab,)`, which may come from a forward pass
+  /// run outside the stream, and returns the text it decodes to.
+  pub fn push_logits(&mut self, logits: &Tensor) -> anyhow::Result<TextGenerationChunk> {
+    let next_token = match self.text_gen.sample(logits) {
+      Ok(next_token) => next_token,
+      Err(e) => return Err(self.fail(e)),
+    };
+
+    self.text_gen.all_tokens.push(next_token);
+    self.generated_tokens += 1;
+
+    tracing::info!("next_token={}", next_token);
+
+    if next_token == self.text_gen.eos_token {
+      return Ok(self.finish(FinishReason::Stop));
+    }
+
+    match self.text_gen.token_output_stream.next_token(next_token) {
+      Ok(t) => {
+        tracing::info!("t={:?}", t);
+
+        let stopped = self.text_gen.token_output_stream.is_stopped();
+
+        self.finished = stopped;
+
+        Ok(TextGenerationChunk {
+          text: t.unwrap_or_default(),
+          finish_reason: stopped.then_some(FinishReason::Stop),
+          usage: None,
+        })
+      }
+      Err(e) => Err(self.fail(e.into())),
+    }
+  }
+
+  /// Ends the stream after an error, without a usage chunk.
+  pub fn fail(&mut self, e: anyhow::Error) -> anyhow::Error {
+    self.finished = true;
+    self.usage_sent = true;
+
+    e
+  }
 }
 
-impl Stream for TextGenerationStream {
-  type Item = String;
+/// Each call runs one forward pass, so this must be driven from a thread that is allowed to block.
+impl Iterator for TextGenerationStream {
+  type Item = anyhow::Result<TextGenerationChunk>;
 
-  fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+  fn next(&mut self) -> Option<Self::Item> {
     tracing::info!("generated_tokens={}", self.generated_tokens);
 
-    if self.generated_tokens > self.text_gen.setting.sample_len {
-      return Poll::Ready(None);
+    if self.is_done() {
+      return None;
+    }
+
+    if let Some(chunk) = self.pending_chunk() {
+      return Some(Ok(chunk));
     }
 
     let context_size = if self.generated_tokens > 0 {
@@ -213,30 +450,9 @@ impl Stream for TextGenerationStream {
 
     let start_pos = self.text_gen.all_tokens.len().saturating_sub(context_size);
 
-    let next_token = self.text_gen.forward_token(start_pos);
-
-    match next_token {
-      Ok(next_token) => {
-        self.text_gen.all_tokens.push(next_token);
-        self.generated_tokens += 1;
-
-        tracing::info!("next_token={}", next_token);
-
-        if next_token == self.text_gen.eos_token {
-          Poll::Ready(None)
-        } else if let Ok(t) = self.text_gen.token_output_stream.next_token(next_token) {
-          tracing::info!("t={:?}", t);
-
-          if let Some(t) = t {
-            Poll::Ready(Some(t))
-          } else {
-            Poll::Ready(Some("".to_string()))
-          }
-        } else {
-          Poll::Ready(None)
-        }
-      }
-      Err(_) => Poll::Ready(None),
+    match self.text_gen.forward_logits(start_pos) {
+      Ok(logits) => Some(self.push_logits(&logits)),
+      Err(e) => Some(Err(self.fail(e))),
     }
   }
 }
@@ -1,9 +1,13 @@
+pub mod chat_template;
 pub mod conf;
 pub mod handle;
+pub mod inference;
+pub mod job;
 pub mod knowledge_base;
 pub mod lancedb;
 pub mod llm;
 pub mod model;
 pub mod openai;
+pub mod scheduler;
 pub mod sqlx;
 pub mod token_output_stream;
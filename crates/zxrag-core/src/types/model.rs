@@ -23,6 +23,15 @@ pub enum ModelId {
   #[serde(rename = "bge-large-zh-v1.5")]
   #[strum(serialize = "bge-large-zh-v1.5")]
   BgeLargeZhV1_5,
+  #[serde(rename = "bge-reranker-base")]
+  #[strum(serialize = "bge-reranker-base")]
+  BgeRerankerBase,
+  #[serde(rename = "bge-reranker-large")]
+  #[strum(serialize = "bge-reranker-large")]
+  BgeRerankerLarge,
+  #[serde(rename = "bge-reranker-v2-m3")]
+  #[strum(serialize = "bge-reranker-v2-m3")]
+  BgeRerankerV2M3,
   #[serde(rename = "codegemma-7b-it")]
   #[strum(serialize = "codegemma-7b-it")]
   CodeGemma7bIt,
@@ -63,3 +72,19 @@ pub enum ModelEngine {
   #[default]
   None,
 }
+
+#[derive(
+  Clone, Default, Debug, Copy, PartialEq, Eq, Deserialize, Serialize, EnumString, Display,
+)]
+pub enum PoolingStrategy {
+  #[serde(rename = "mean")]
+  #[strum(serialize = "mean")]
+  Mean,
+  #[default]
+  #[serde(rename = "cls")]
+  #[strum(serialize = "cls")]
+  Cls,
+  #[serde(rename = "last_token")]
+  #[strum(serialize = "last_token")]
+  LastToken,
+}
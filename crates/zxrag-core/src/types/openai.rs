@@ -3,10 +3,13 @@ use either::Either;
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::collections::HashMap;
-use std::fmt::Write;
 use std::fmt::{Display, Formatter};
 use tinyvec::TinyVec;
+use tokenizers::Tokenizer;
 
+use crate::retriever::{RetrievalSetting, RetrievedChunk};
+use crate::types::chat_template::{get_chat_template, ChatTemplate};
+use crate::types::llm::TextGenerationUsage;
 use crate::types::model::ModelId;
 
 #[derive(Serialize, Deserialize)]
@@ -23,6 +26,7 @@ pub struct ChatCompletionRequest<'a> {
   #[serde(default, with = "either::serde_untagged_optional")]
   pub stop: Option<Either<Cow<'a, str>, Vec<Cow<'a, str>>>>,
   pub stream: Option<bool>,
+  pub stream_options: Option<StreamOptions>,
   pub response_format: Option<serde_json::Value>,
   pub temperature: Option<f64>,
   pub top_p: Option<f64>,
@@ -31,6 +35,35 @@ pub struct ChatCompletionRequest<'a> {
   pub tool_choice: Option<Either<Cow<'a, str>, ToolStub<'a>>>,
   pub user: Option<Cow<'a, str>>,
   pub one_shot: Option<bool>,
+  /// Knowledge base chat only, overrides the retrieval settings of the knowledge base.
+  pub retrieval: Option<RetrievalSetting>,
+  /// Continues a stored session: its history is put before `messages`, and the new turn is
+  /// appended to it once answered.
+  pub session_id: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct StreamOptions {
+  /// Sends a trailing chunk with the token usage of the whole request and no choices.
+  pub include_usage: Option<bool>,
+}
+
+impl<'a> ChatCompletionRequest<'a> {
+  pub fn include_usage(&self) -> bool {
+    self
+      .stream_options
+      .as_ref()
+      .and_then(|options| options.include_usage)
+      .unwrap_or(false)
+  }
+
+  pub fn stop_sequences(&self) -> Vec<String> {
+    match &self.stop {
+      Some(Either::Left(stop)) => vec![stop.to_string()],
+      Some(Either::Right(stop)) => stop.iter().map(|s| s.to_string()).collect(),
+      None => vec![],
+    }
+  }
 }
 
 #[derive(Serialize, Deserialize, Default, Deref, DerefMut, From)]
@@ -42,86 +75,59 @@ pub struct ChatMessages<'a>(
 
 impl<'a> ChatMessages<'a> {
   pub fn to_prompt(&self, model_id: ModelId) -> anyhow::Result<String> {
-    let mut prompt = String::new();
-
-    for (i, message) in self.0.iter().enumerate() {
-      match message {
-        ChatMessage::System {
-          content: Some(data),
-          ..
-        } => {
-          if i == 0 {
-            write!(prompt, "<s>")?;
-          }
-
-          if model_id.is_mistral() {
-            write!(
-              prompt,
-              "[INST] {data} Hi [/INST] Hello! how can I help you</s>"
-            )?;
-          } else {
-            write!(prompt, "<|SYSTEM|>{data}")?;
-          }
-        }
-        ChatMessage::User {
-          content: Either::Left(data),
-          ..
-        } => {
-          if i == 0 {
-            write!(prompt, "<s>")?;
-          }
+    match get_chat_template() {
+      Some(template) => template.render(self, true),
+      None => ChatTemplate::builtin(model_id)?.render(self, true),
+    }
+  }
 
-          if model_id.is_mistral() {
-            write!(prompt, "[INST] {data} [/INST]")?;
-          } else {
-            write!(prompt, "<|USER|>{data}")?;
-          }
+  /// Renders the prompt, leaving out the oldest turns until it takes at most `budget` tokens.
+  /// System messages and the last question are always kept.
+  pub fn to_fitted_prompt(
+    &mut self,
+    model_id: ModelId,
+    tokenizer: &Tokenizer,
+    budget: usize,
+  ) -> anyhow::Result<String> {
+    let is_question = |message: &ChatMessage| matches!(message, ChatMessage::User { .. });
+
+    let mut dropped = 0;
+
+    loop {
+      let prompt = self.to_prompt(model_id)?;
+
+      let tokens = tokenizer
+        .encode(prompt.as_str(), false)
+        .map_err(anyhow::Error::msg)?
+        .len();
+
+      // The oldest turn runs from the first message after the system ones to the next question
+      let last_question = self.iter().rposition(is_question);
+      let start = self
+        .iter()
+        .position(|message| !matches!(message, ChatMessage::System { .. }));
+      let end = start.and_then(|start| {
+        self
+          .iter()
+          .skip(start + 1)
+          .position(is_question)
+          .map(|index| start + 1 + index)
+      });
+
+      match (start, end) {
+        (Some(start), Some(end)) if tokens > budget && Some(end) <= last_question => {
+          self.drain(start..end);
+          dropped += end - start;
         }
-        ChatMessage::User {
-          content: Either::Right(data),
-          ..
-        } => {
-          if i == 0 {
-            write!(prompt, "<s>")?;
+        _ => {
+          if dropped > 0 {
+            tracing::info!("{} oldest messages left out of the prompt", dropped);
           }
 
-          if model_id.is_mistral() {
-            write!(prompt, "[INST] ")?;
-
-            for part in data {
-              write!(prompt, "{part}")?;
-            }
-
-            write!(prompt, " [/INST]")?;
-          } else {
-            write!(prompt, "<|USER|>")?;
-
-            for part in data {
-              write!(prompt, "{part}")?;
-            }
-          }
-        }
-        ChatMessage::Assistant {
-          content: Some(data),
-          ..
-        } => {
-          if model_id.is_mistral() {
-            write!(prompt, "{data}")?;
-          } else {
-            write!(prompt, "<|ASSISTANT|>{data}")?;
-          }
-        }
-        ChatMessage::Tool {
-          content: Some(data),
-          ..
-        } => {
-          write!(prompt, "<|TOOL|>{data}")?;
+          return Ok(prompt);
         }
-        _ => {}
       }
     }
-
-    Ok(prompt)
   }
 }
 
@@ -154,6 +160,43 @@ pub enum ChatMessage<'a> {
   },
 }
 
+impl<'a> ChatMessage<'a> {
+  pub fn role(&self) -> &'static str {
+    match self {
+      ChatMessage::System { .. } => "system",
+      ChatMessage::User { .. } => "user",
+      ChatMessage::Assistant { .. } => "assistant",
+      ChatMessage::Tool { .. } => "tool",
+    }
+  }
+}
+
+impl ChatMessage<'static> {
+  /// Rebuilds a message stored as a role and its text.
+  pub fn from_text(role: &str, content: String) -> anyhow::Result<Self> {
+    let content = Cow::Owned(content);
+
+    let message = match role {
+      "system" => ChatMessage::System {
+        content: Some(content),
+        name: None,
+      },
+      "user" => ChatMessage::User {
+        content: Either::Left(content),
+        name: None,
+      },
+      "assistant" => ChatMessage::Assistant {
+        content: Some(content),
+        name: None,
+        tool_calls: None,
+      },
+      _ => anyhow::bail!("unsupported message role {}", role),
+    };
+
+    Ok(message)
+  }
+}
+
 impl<'a> Display for ChatMessage<'a> {
   fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
     match self {
@@ -243,6 +286,9 @@ pub struct ChatCompletion<'a> {
   pub system_fingerprint: Cow<'a, str>,
   pub object: Cow<'a, str>,
   pub usage: ChatCompletionUsage,
+  /// Knowledge base chat only, the chunks the answer was grounded on.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub sources: Option<Vec<ChatCompletionSource<'a>>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -259,6 +305,16 @@ pub struct ChatCompletionUsage {
   pub total_tokens: u64,
 }
 
+impl From<TextGenerationUsage> for ChatCompletionUsage {
+  fn from(usage: TextGenerationUsage) -> Self {
+    Self {
+      completion_tokens: usage.completion_tokens as u64,
+      prompt_tokens: usage.prompt_tokens as u64,
+      total_tokens: usage.total_tokens() as u64,
+    }
+  }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ChatCompletionChunk<'a> {
   pub id: Cow<'a, str>,
@@ -267,6 +323,38 @@ pub struct ChatCompletionChunk<'a> {
   pub model: Cow<'a, str>,
   pub system_fingerprint: Cow<'a, str>,
   pub object: Cow<'a, str>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub usage: Option<ChatCompletionUsage>,
+  /// Knowledge base chat only, set on the first chunk of the stream, which carries no choices.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub sources: Option<Vec<ChatCompletionSource<'a>>>,
+}
+
+/// A retrieved chunk given to the model, which cites it in the answer as `[index]`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ChatCompletionSource<'a> {
+  pub index: usize,
+  /// Id of the embedding row the chunk was read from.
+  pub id: Cow<'a, str>,
+  pub file_id: i64,
+  pub filename: Cow<'a, str>,
+  pub heading_path: Cow<'a, str>,
+  pub text: Cow<'a, str>,
+  pub score: f32,
+}
+
+impl ChatCompletionSource<'static> {
+  pub fn new(index: usize, chunk: &RetrievedChunk) -> Self {
+    Self {
+      index,
+      id: Cow::Owned(chunk.id.clone()),
+      file_id: chunk.file_id,
+      filename: Cow::Owned(chunk.file_name.clone()),
+      heading_path: Cow::Owned(chunk.heading_path.clone()),
+      text: Cow::Owned(chunk.text.clone()),
+      score: chunk.score,
+    }
+  }
 }
 
 #[derive(Serialize, Deserialize, Default)]
@@ -313,6 +401,60 @@ pub struct EmbeddingsUsage {
   pub total_tokens: usize,
 }
 
+/// Rerank request in the schema shared by the Jina and Cohere rerank APIs.
+#[derive(Serialize, Deserialize)]
+pub struct RerankRequest<'a> {
+  pub model: Option<Cow<'a, str>>,
+  pub query: Cow<'a, str>,
+  pub documents: Vec<RerankInput<'a>>,
+  /// Results returned, all documents when unset.
+  pub top_n: Option<usize>,
+  /// Whether results echo the document text, defaults to true.
+  pub return_documents: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RerankInput<'a> {
+  Text(Cow<'a, str>),
+  Document(RerankDocument<'a>),
+}
+
+impl<'a> RerankInput<'a> {
+  pub fn text(&self) -> &str {
+    match self {
+      RerankInput::Text(text) => text,
+      RerankInput::Document(document) => &document.text,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RerankDocument<'a> {
+  pub text: Cow<'a, str>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RerankResponse<'a> {
+  pub id: Cow<'a, str>,
+  pub model: Cow<'a, str>,
+  pub results: Vec<RerankResult<'a>>,
+  pub usage: RerankUsage,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RerankResult<'a> {
+  pub index: usize,
+  pub relevance_score: f32,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub document: Option<RerankDocument<'a>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RerankUsage {
+  pub total_tokens: usize,
+}
+
 #[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
 pub struct ModelsResponse<'a> {
   pub object: Cow<'a, str>,
@@ -349,3 +491,144 @@ pub struct DeleteFileResponse<'a> {
   pub object: Cow<'a, str>,
   pub deleted: bool,
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokenizers::models::wordlevel::WordLevel;
+  use tokenizers::pre_tokenizers::whitespace::Whitespace;
+
+  /// Counts words, every one of them unknown.
+  fn word_tokenizer() -> Tokenizer {
+    let model = WordLevel::builder()
+      .vocab(HashMap::from([("<unk>".to_string(), 0)]))
+      .unk_token("<unk>".to_string())
+      .build()
+      .unwrap();
+
+    let mut tokenizer = Tokenizer::new(model);
+    tokenizer.with_pre_tokenizer(Whitespace {});
+    tokenizer
+  }
+
+  fn message(role: &str, content: &str) -> ChatMessage<'static> {
+    ChatMessage::from_text(role, content.to_string()).unwrap()
+  }
+
+  fn conversation() -> ChatMessages<'static> {
+    vec![
+      message("system", "be brief"),
+      message("user", "first question about a long topic"),
+      message("assistant", "first answer that goes on and on"),
+      message("user", "second question"),
+      message("assistant", "second answer"),
+      message("user", "third question"),
+    ]
+    .into()
+  }
+
+  fn roles(messages: &ChatMessages) -> Vec<&'static str> {
+    messages.iter().map(|message| message.role()).collect()
+  }
+
+  fn tokens(prompt: &str) -> usize {
+    word_tokenizer().encode(prompt, false).unwrap().len()
+  }
+
+  #[test]
+  fn fitted_prompt_keeps_everything_within_budget() {
+    let mut messages = conversation();
+    let prompt = messages.to_prompt(ModelId::Zephyr7bBeta).unwrap();
+
+    let fitted = messages
+      .to_fitted_prompt(ModelId::Zephyr7bBeta, &word_tokenizer(), tokens(&prompt))
+      .unwrap();
+
+    assert_eq!(fitted, prompt);
+    assert_eq!(messages.len(), 6);
+  }
+
+  #[test]
+  fn fitted_prompt_drops_the_oldest_turns_first() {
+    let expected: ChatMessages = vec![
+      message("system", "be brief"),
+      message("user", "second question"),
+      message("assistant", "second answer"),
+      message("user", "third question"),
+    ]
+    .into();
+    let budget = tokens(&expected.to_prompt(ModelId::Zephyr7bBeta).unwrap());
+
+    let mut messages = conversation();
+    let fitted = messages
+      .to_fitted_prompt(ModelId::Zephyr7bBeta, &word_tokenizer(), budget)
+      .unwrap();
+
+    assert_eq!(roles(&messages), roles(&expected));
+    assert_eq!(fitted, expected.to_prompt(ModelId::Zephyr7bBeta).unwrap());
+  }
+
+  #[test]
+  fn fitted_prompt_keeps_system_messages_and_the_last_question() {
+    let mut messages = conversation();
+
+    messages
+      .to_fitted_prompt(ModelId::Zephyr7bBeta, &word_tokenizer(), 0)
+      .unwrap();
+
+    assert_eq!(roles(&messages), vec!["system", "user"]);
+    assert_eq!(messages[1].to_string(), "third question");
+  }
+
+  fn source() -> ChatCompletionSource<'static> {
+    ChatCompletionSource::new(
+      1,
+      &RetrievedChunk {
+        id: "e1".to_string(),
+        kb_id: 3,
+        file_id: 7,
+        file_name: "guide.md".to_string(),
+        text: "Run the installer.".to_string(),
+        heading_path: "Guide > Install".to_string(),
+        score: 0.5,
+      },
+    )
+  }
+
+  #[test]
+  fn sources_carry_the_retrieved_chunks() {
+    assert_eq!(
+      serde_json::to_value(source()).unwrap(),
+      serde_json::json!({
+        "index": 1,
+        "id": "e1",
+        "file_id": 7,
+        "filename": "guide.md",
+        "heading_path": "Guide > Install",
+        "text": "Run the installer.",
+        "score": 0.5,
+      })
+    );
+  }
+
+  #[test]
+  fn sources_are_only_serialized_when_set() {
+    let chunk = |sources| ChatCompletionChunk {
+      id: Cow::Borrowed("id"),
+      choices: TinyVec::default(),
+      created: 0,
+      model: Cow::Borrowed("main"),
+      system_fingerprint: Cow::Borrowed("fp"),
+      object: Cow::Borrowed("text_completion"),
+      usage: None,
+      sources,
+    };
+
+    let with_sources = serde_json::to_value(chunk(Some(vec![source()]))).unwrap();
+    let without_sources = serde_json::to_value(chunk(None)).unwrap();
+
+    assert_eq!(with_sources["choices"], serde_json::json!([]));
+    assert_eq!(with_sources["sources"][0]["id"], "e1");
+    assert!(without_sources.get("sources").is_none());
+  }
+}
@@ -0,0 +1,433 @@
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::models::quantized_llama::KvCache;
+use crate::types::handle::{get_llm_model, get_text_gen};
+use crate::types::llm::{
+  LlmModel, TextGenerationChunk, TextGenerationSetting, TextGenerationStream,
+};
+
+pub type ChunkSender = UnboundedSender<anyhow::Result<TextGenerationChunk>>;
+
+struct Sequence {
+  stream: TextGenerationStream,
+  sender: ChunkSender,
+}
+
+impl Sequence {
+  fn send(&mut self, chunk: anyhow::Result<TextGenerationChunk>) {
+    if self.sender.send(chunk).is_err() {
+      tracing::info!("receiver dropped, generation cancelled");
+    }
+  }
+
+  fn is_finished(&self) -> bool {
+    self.stream.is_done() || self.sender.is_closed()
+  }
+}
+
+/// Continuous batching over the sequences in flight on one inference thread. Every step emits one
+/// chunk per sequence: prompts are prefilled one at a time, then all sequences whose model can
+/// decode in batches share a single forward pass. New sequences are admitted between steps, and
+/// finished or abandoned ones leave the batch right away.
+pub struct Scheduler {
+  model: Box<dyn LlmModel + Send + Sync>,
+  sequences: Vec<Sequence>,
+  max_batch_size: usize,
+}
+
+impl Scheduler {
+  pub fn new(max_batch_size: usize) -> anyhow::Result<Self> {
+    Ok(Self {
+      model: get_llm_model()?,
+      sequences: vec![],
+      max_batch_size: max_batch_size.max(1),
+    })
+  }
+
+  pub fn len(&self) -> usize {
+    self.sequences.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.sequences.is_empty()
+  }
+
+  pub fn has_capacity(&self) -> bool {
+    self.sequences.len() < self.max_batch_size
+  }
+
+  /// Admits a sequence capped at [`MAX_SEQ_LEN`](crate::types::llm::MAX_SEQ_LEN) tokens: its
+  /// `sample_len` is shortened to fit after the prompt, and prompts filling it are rejected.
+  pub fn admit(&mut self, setting: TextGenerationSetting, sender: ChunkSender) {
+    match get_text_gen(setting).and_then(TextGenerationStream::new) {
+      Ok(stream) => self.sequences.push(Sequence { stream, sender }),
+      Err(e) => {
+        tracing::error!("generation failed: {}", e);
+
+        let _ = sender.send(Err(e));
+      }
+    }
+  }
+
+  pub fn step(&mut self) {
+    let mut decode = vec![false; self.sequences.len()];
+
+    for (index, seq) in self.sequences.iter_mut().enumerate() {
+      if seq.is_finished() {
+        continue;
+      }
+
+      if let Some(chunk) = seq.stream.pending_chunk() {
+        seq.send(Ok(chunk));
+      } else if seq.stream.is_prefilled() && seq.stream.model_mut().kv_cache().is_some() {
+        decode[index] = true;
+      } else if let Some(chunk) = seq.stream.next() {
+        seq.send(chunk);
+      }
+    }
+
+    self.decode_batch(&decode);
+
+    self.sequences.retain(|seq| !seq.is_finished());
+  }
+
+  /// Fails every sequence in flight, once a step panicked and left their state unknown.
+  pub fn fail_all(&mut self, reason: &str) {
+    for mut seq in self.sequences.drain(..) {
+      let e = seq
+        .stream
+        .fail(anyhow::anyhow!("generation failed: {}", reason));
+
+      seq.send(Err(e));
+    }
+  }
+
+  fn decode_batch(&mut self, decode: &[bool]) {
+    let (tokens, mut caches): (Vec<u32>, Vec<&mut KvCache>) = self
+      .sequences
+      .iter_mut()
+      .zip(decode)
+      .filter(|(_, decode)| **decode)
+      .filter_map(|(seq, _)| {
+        let token = seq.stream.last_token()?;
+        seq
+          .stream
+          .model_mut()
+          .kv_cache()
+          .map(|cache| (token, cache))
+      })
+      .unzip();
+
+    if tokens.is_empty() {
+      return;
+    }
+
+    tracing::info!("decoding batch of {}", tokens.len());
+
+    let logits = self.model.forward_batch(&tokens, &mut caches);
+
+    let batch = self
+      .sequences
+      .iter_mut()
+      .zip(decode)
+      .filter(|(_, decode)| **decode)
+      .map(|(seq, _)| seq);
+
+    match logits {
+      Ok(logits) => {
+        for (row, seq) in batch.enumerate() {
+          let chunk = match logits.get(row) {
+            Ok(logits) => seq.stream.push_logits(&logits),
+            Err(e) => Err(seq.stream.fail(e.into())),
+          };
+
+          seq.send(chunk);
+        }
+      }
+      Err(e) => {
+        tracing::error!("batched decoding failed: {}", e);
+
+        for seq in batch {
+          let e = seq
+            .stream
+            .fail(anyhow::anyhow!("batched decoding failed: {}", e));
+
+          seq.send(Err(e));
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use candle_core::{Device, Tensor};
+  use std::collections::HashMap;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::Arc;
+  use tokenizers::models::wordlevel::WordLevel;
+  use tokenizers::pre_tokenizers::whitespace::Whitespace;
+  use tokenizers::Tokenizer;
+  use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+  use super::*;
+  use crate::types::llm::{FinishReason, TextGeneration};
+  use crate::types::model::{ModelEngine, ModelId};
+
+  const WORD: u32 = 2;
+  const EOS: u32 = 1;
+
+  /// Emits `words` times the same word then its EOS token, decoding in batches when it has a
+  /// KV cache.
+  struct FakeModel {
+    tokenizer: Tokenizer,
+    device: Device,
+    words: usize,
+    kv_cache: Option<KvCache>,
+    batches: Arc<AtomicUsize>,
+  }
+
+  impl FakeModel {
+    fn new(words: usize, batched: bool) -> Self {
+      let model = WordLevel::builder()
+        .vocab(HashMap::from([
+          ("<unk>".to_string(), 0),
+          ("</s>".to_string(), EOS),
+          ("word".to_string(), WORD),
+        ]))
+        .unk_token("<unk>".to_string())
+        .build()
+        .unwrap();
+
+      let mut tokenizer = Tokenizer::new(model);
+      tokenizer.with_pre_tokenizer(Whitespace {});
+
+      Self {
+        tokenizer,
+        device: Device::Cpu,
+        words,
+        kv_cache: batched.then(KvCache::default),
+        batches: Arc::default(),
+      }
+    }
+
+    fn logits(token: u32) -> [f32; 3] {
+      let mut logits = [0.0; 3];
+      logits[token as usize] = 1.0;
+
+      logits
+    }
+  }
+
+  impl LlmModel for FakeModel {
+    fn id(&self) -> ModelId {
+      ModelId::Zephyr7bBeta
+    }
+
+    fn engine(&self) -> ModelEngine {
+      ModelEngine::Gguf
+    }
+
+    fn tokenizer(&self) -> &Tokenizer {
+      &self.tokenizer
+    }
+
+    fn device(&self) -> &Device {
+      &self.device
+    }
+
+    fn forward(&mut self, _x: &Tensor, _index_pos: usize) -> anyhow::Result<Tensor> {
+      let token = if self.words > 0 {
+        self.words -= 1;
+        WORD
+      } else {
+        EOS
+      };
+
+      Ok(Tensor::new(&[Self::logits(token)], &self.device)?)
+    }
+
+    fn kv_cache(&mut self) -> Option<&mut KvCache> {
+      self.kv_cache.as_mut()
+    }
+
+    fn forward_batch(
+      &self,
+      tokens: &[u32],
+      _caches: &mut [&mut KvCache],
+    ) -> anyhow::Result<Tensor> {
+      self.batches.fetch_add(1, Ordering::SeqCst);
+
+      let logits = Tensor::new(&[Self::logits(WORD)], &self.device)?;
+
+      Ok(logits.repeat((tokens.len(), 1))?)
+    }
+  }
+
+  fn scheduler(max_batch_size: usize) -> Scheduler {
+    Scheduler {
+      model: Box::new(FakeModel::new(0, true)),
+      sequences: vec![],
+      max_batch_size,
+    }
+  }
+
+  fn push(
+    scheduler: &mut Scheduler,
+    model: FakeModel,
+    sample_len: usize,
+  ) -> UnboundedReceiver<anyhow::Result<TextGenerationChunk>> {
+    let setting = TextGenerationSetting {
+      repeat_penalty: 1.,
+      sample_len,
+      prompt: "word word".to_string(),
+      ..Default::default()
+    };
+
+    let text_gen = TextGeneration::new(Box::new(model), setting).unwrap();
+    let (sender, receiver) = unbounded_channel();
+
+    scheduler.sequences.push(Sequence {
+      stream: TextGenerationStream::new(text_gen).unwrap(),
+      sender,
+    });
+
+    receiver
+  }
+
+  fn run(scheduler: &mut Scheduler) -> usize {
+    let mut steps = 0;
+
+    while !scheduler.is_empty() {
+      scheduler.step();
+      steps += 1;
+
+      assert!(steps < 100, "the scheduler never emptied");
+    }
+
+    steps
+  }
+
+  fn chunks(
+    receiver: &mut UnboundedReceiver<anyhow::Result<TextGenerationChunk>>,
+  ) -> Vec<TextGenerationChunk> {
+    std::iter::from_fn(|| receiver.try_recv().ok())
+      .map(Result::unwrap)
+      .collect()
+  }
+
+  #[test]
+  fn sequences_stop_at_sample_len_then_report_usage() {
+    let mut scheduler = scheduler(4);
+
+    let mut first = push(&mut scheduler, FakeModel::new(10, false), 3);
+    let mut second = push(&mut scheduler, FakeModel::new(10, false), 1);
+
+    run(&mut scheduler);
+
+    let first = chunks(&mut first);
+    let second = chunks(&mut second);
+
+    // One chunk per token, the final chunk, then usage
+    assert_eq!(first.len(), 5);
+    assert_eq!(second.len(), 3);
+    assert_eq!(first[3].finish_reason, Some(FinishReason::Length));
+    assert_eq!(first[4].usage.unwrap().prompt_tokens, 2);
+    assert_eq!(first[4].usage.unwrap().completion_tokens, 3);
+    assert_eq!(second[2].usage.unwrap().completion_tokens, 1);
+  }
+
+  #[test]
+  fn eos_finishes_a_sequence_with_stop() {
+    let mut scheduler = scheduler(4);
+
+    let mut receiver = push(&mut scheduler, FakeModel::new(1, false), 10);
+
+    run(&mut scheduler);
+
+    let chunks = chunks(&mut receiver);
+
+    assert_eq!(chunks.len(), 3);
+    assert_eq!(chunks[1].finish_reason, Some(FinishReason::Stop));
+    assert_eq!(chunks[2].usage.unwrap().completion_tokens, 2);
+  }
+
+  #[test]
+  fn prefilled_sequences_share_one_forward_pass() {
+    let model = FakeModel::new(0, true);
+    let batches = model.batches.clone();
+
+    let mut scheduler = Scheduler {
+      model: Box::new(model),
+      sequences: vec![],
+      max_batch_size: 4,
+    };
+
+    let mut receivers = (0..3)
+      .map(|_| push(&mut scheduler, FakeModel::new(10, true), 3))
+      .collect::<Vec<_>>();
+
+    scheduler.step();
+
+    // Each prompt is prefilled on its own copy of the model
+    assert_eq!(batches.load(Ordering::SeqCst), 0);
+
+    scheduler.step();
+    scheduler.step();
+
+    assert_eq!(batches.load(Ordering::SeqCst), 2);
+
+    run(&mut scheduler);
+
+    for receiver in &mut receivers {
+      let chunks = chunks(receiver);
+
+      assert_eq!(chunks.len(), 5);
+      assert_eq!(chunks[3].finish_reason, Some(FinishReason::Length));
+    }
+  }
+
+  #[test]
+  fn abandoned_sequences_leave_the_batch() {
+    let mut scheduler = scheduler(4);
+
+    let receiver = push(&mut scheduler, FakeModel::new(10, false), 10);
+    let mut kept = push(&mut scheduler, FakeModel::new(10, false), 10);
+
+    drop(receiver);
+    scheduler.step();
+
+    assert_eq!(scheduler.len(), 1);
+    assert_eq!(chunks(&mut kept).len(), 1);
+  }
+
+  #[test]
+  fn fail_all_fails_and_drops_every_sequence() {
+    let mut scheduler = scheduler(4);
+
+    let mut first = push(&mut scheduler, FakeModel::new(10, false), 10);
+    let mut second = push(&mut scheduler, FakeModel::new(10, false), 10);
+
+    scheduler.fail_all("panicked");
+
+    assert!(scheduler.is_empty());
+
+    for receiver in [&mut first, &mut second] {
+      let e = receiver.try_recv().unwrap().unwrap_err();
+
+      assert!(e.to_string().contains("panicked"));
+    }
+  }
+
+  #[test]
+  fn capacity_is_bounded_by_max_batch_size() {
+    let mut scheduler = scheduler(2);
+
+    assert!(scheduler.has_capacity());
+
+    let _first = push(&mut scheduler, FakeModel::new(10, false), 10);
+    let _second = push(&mut scheduler, FakeModel::new(10, false), 10);
+
+    assert!(!scheduler.has_capacity());
+  }
+}
@@ -1,12 +1,14 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Default, Serialize, Deserialize, sqlx::FromRow)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize, sqlx::FromRow)]
 pub struct File {
   pub id: i64,
   pub kb_id: i64,
   pub filename: String,
   pub bytes: i64,
   pub purpose: String,
+  /// Hex SHA-256 of the stored bytes.
+  pub content_hash: String,
   pub created_at: i64,
   pub updated_at: i64,
 }
@@ -18,3 +20,58 @@ pub struct KnowledgeBase {
   pub created_at: i64,
   pub updated_at: i64,
 }
+
+#[derive(Debug, Default, Serialize, Deserialize, sqlx::FromRow)]
+pub struct KnowledgeBaseSetting {
+  pub kb_id: i64,
+  /// JSON encoded `RetrievalSetting`.
+  pub retrieval: String,
+  pub created_at: i64,
+  pub updated_at: i64,
+}
+
+/// Parameters of the last index built for a knowledge base, reused when ingestion rebuilds it.
+#[derive(Debug, Default, Serialize, Deserialize, sqlx::FromRow)]
+pub struct VectorIndex {
+  pub kb_id: i64,
+  /// JSON encoded `VectorIndexParams`.
+  pub params: String,
+  pub created_at: i64,
+  pub updated_at: i64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Session {
+  pub id: i64,
+  /// Knowledge base the session chats with, `None` for plain chat.
+  pub kb_id: Option<i64>,
+  pub title: String,
+  pub created_at: i64,
+  pub updated_at: i64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Message {
+  pub id: i64,
+  pub session_id: i64,
+  pub role: String,
+  pub content: String,
+  pub created_at: i64,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Job {
+  pub id: i64,
+  pub kb_id: i64,
+  pub file_id: i64,
+  /// A `JobStatus`.
+  pub status: String,
+  /// Why the job failed, empty otherwise.
+  pub error: String,
+  /// Chunks the file was split into, known once the job is running.
+  pub total: i64,
+  /// Chunks embedded and stored so far.
+  pub processed: i64,
+  pub created_at: i64,
+  pub updated_at: i64,
+}
@@ -2,12 +2,19 @@ use candle_core::Result;
 
 /// This is a wrapper around a tokenizer to ensure that tokens can be returned to the user in a
 /// streaming way rather than having to wait for the full decoding.
+///
+/// Stop sequences are matched on the decoded text, so they are found even when they span several
+/// tokens. Text that could still turn into a stop sequence is held back until it can't, which keeps
+/// stop text from ever reaching the caller.
 #[derive(Debug, Clone)]
 pub struct TokenOutputStream {
   tokenizer: tokenizers::Tokenizer,
   tokens: Vec<u32>,
   prev_index: usize,
   current_index: usize,
+  stop_sequences: Vec<String>,
+  pending: String,
+  stopped: bool,
 }
 
 impl TokenOutputStream {
@@ -17,9 +24,20 @@ impl TokenOutputStream {
       tokens: Vec::new(),
       prev_index: 0,
       current_index: 0,
+      stop_sequences: Vec::new(),
+      pending: String::new(),
+      stopped: false,
     }
   }
 
+  pub fn with_stop_sequences(mut self, stop_sequences: Vec<String>) -> Self {
+    self.stop_sequences = stop_sequences
+      .into_iter()
+      .filter(|s| !s.is_empty())
+      .collect();
+    self
+  }
+
   pub fn into_inner(self) -> tokenizers::Tokenizer {
     self.tokenizer
   }
@@ -31,8 +49,36 @@ impl TokenOutputStream {
     }
   }
 
-  // https://github.com/huggingface/text-generation-inference/blob/5ba53d44a18983a4de32d122f4cb46f4a17d9ef6/server/text_generation_server/models/model.py#L68
   pub fn next_token(&mut self, token: u32) -> Result<Option<String>> {
+    if self.stopped {
+      return Ok(None);
+    }
+
+    let text = self.decode_next(token)?.unwrap_or_default();
+
+    Ok(self.hold_back(&text, false))
+  }
+
+  pub fn decode_rest(&mut self) -> Result<Option<String>> {
+    if self.stopped {
+      return Ok(None);
+    }
+
+    let text = self.decode_remaining()?.unwrap_or_default();
+
+    self.prev_index = self.tokens.len();
+    self.current_index = self.tokens.len();
+
+    Ok(self.hold_back(&text, true))
+  }
+
+  /// Whether a stop sequence has been generated. Nothing is returned after that.
+  pub fn is_stopped(&self) -> bool {
+    self.stopped
+  }
+
+  // https://github.com/huggingface/text-generation-inference/blob/5ba53d44a18983a4de32d122f4cb46f4a17d9ef6/server/text_generation_server/models/model.py#L68
+  fn decode_next(&mut self, token: u32) -> Result<Option<String>> {
     let prev_text = if self.tokens.is_empty() {
       String::new()
     } else {
@@ -51,7 +97,7 @@ impl TokenOutputStream {
     }
   }
 
-  pub fn decode_rest(&self) -> Result<Option<String>> {
+  fn decode_remaining(&self) -> Result<Option<String>> {
     let prev_text = if self.tokens.is_empty() {
       String::new()
     } else {
@@ -67,6 +113,33 @@ impl TokenOutputStream {
     }
   }
 
+  /// Appends newly decoded text to the pending buffer and returns what can be released: everything
+  /// before a complete stop sequence, or everything that can't be the start of one. `flush`
+  /// releases the whole buffer since no more text will follow.
+  fn hold_back(&mut self, text: &str, flush: bool) -> Option<String> {
+    self.pending.push_str(text);
+
+    if let Some(pos) = self
+      .stop_sequences
+      .iter()
+      .filter_map(|s| self.pending.find(s.as_str()))
+      .min()
+    {
+      self.pending.truncate(pos);
+      self.stopped = true;
+    }
+
+    let release = if flush || self.stopped {
+      self.pending.len()
+    } else {
+      partial_stop_start(&self.pending, &self.stop_sequences)
+    };
+
+    let text: String = self.pending.drain(..release).collect();
+
+    (!text.is_empty()).then_some(text)
+  }
+
   pub fn decode_all(&self) -> Result<String> {
     self.decode(&self.tokens)
   }
@@ -83,5 +156,97 @@ impl TokenOutputStream {
     self.tokens.clear();
     self.prev_index = 0;
     self.current_index = 0;
+    self.pending.clear();
+    self.stopped = false;
+  }
+}
+
+/// Returns where the longest suffix of `text` that is a prefix of some stop sequence begins.
+fn partial_stop_start(text: &str, stop_sequences: &[String]) -> usize {
+  text
+    .char_indices()
+    .map(|(i, _)| i)
+    .find(|&i| stop_sequences.iter().any(|s| s.starts_with(&text[i..])))
+    .unwrap_or(text.len())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokenizers::models::wordlevel::WordLevel;
+
+  /// Text is fed to `hold_back` directly, so the tokenizer is never asked to decode anything.
+  fn new_stream(stop_sequences: &[&str]) -> TokenOutputStream {
+    TokenOutputStream::new(tokenizers::Tokenizer::new(WordLevel::default()))
+      .with_stop_sequences(stop_sequences.iter().map(|s| s.to_string()).collect())
+  }
+
+  fn stops(stop_sequences: &[&str]) -> Vec<String> {
+    stop_sequences.iter().map(|s| s.to_string()).collect()
+  }
+
+  #[test]
+  fn stop_sequence_split_across_tokens() {
+    let mut stream = new_stream(&["</s>"]);
+
+    assert_eq!(
+      stream.hold_back("Hello <", false).as_deref(),
+      Some("Hello ")
+    );
+    assert_eq!(stream.hold_back("/", false), None);
+    assert_eq!(stream.hold_back("s> more", false), None);
+    assert!(stream.is_stopped());
+    assert_eq!(stream.next_token(0).unwrap(), None);
+    assert_eq!(stream.decode_rest().unwrap(), None);
+  }
+
+  #[test]
+  fn partial_match_that_diverges_is_released() {
+    let mut stream = new_stream(&["###"]);
+
+    assert_eq!(stream.hold_back("a #", false).as_deref(), Some("a "));
+    assert_eq!(stream.hold_back("#", false), None);
+    assert_eq!(stream.hold_back("x", false).as_deref(), Some("##x"));
+    assert!(!stream.is_stopped());
+  }
+
+  #[test]
+  fn earliest_of_several_stop_sequences_wins() {
+    let mut stream = new_stream(&["</s>", "\nUser:"]);
+
+    assert_eq!(stream.hold_back("ok \nUs", false).as_deref(), Some("ok "));
+    assert_eq!(
+      stream.hold_back("ed</s>\nUser:", false).as_deref(),
+      Some("\nUsed")
+    );
+    assert!(stream.is_stopped());
+
+    let mut stream = new_stream(&["</s>", "\nUser:"]);
+
+    assert_eq!(
+      stream.hold_back("x\nUser: y</s>", false).as_deref(),
+      Some("x")
+    );
+  }
+
+  #[test]
+  fn decode_rest_flushes_held_back_text() {
+    let mut stream = new_stream(&["</s>"]);
+
+    assert_eq!(stream.hold_back("end <", false).as_deref(), Some("end "));
+    assert_eq!(stream.decode_rest().unwrap().as_deref(), Some("<"));
+    assert!(!stream.is_stopped());
+  }
+
+  #[test]
+  fn partial_stop_start_finds_the_longest_prefix() {
+    let stop_sequences = stops(&["</s>", "<|end|>"]);
+
+    assert_eq!(partial_stop_start("abc", &stop_sequences), 3);
+    assert_eq!(partial_stop_start("abc<", &stop_sequences), 3);
+    assert_eq!(partial_stop_start("a<|e", &stop_sequences), 1);
+    assert_eq!(partial_stop_start("é</", &stop_sequences), "é".len());
+    assert_eq!(partial_stop_start("", &stop_sequences), 0);
+    assert_eq!(partial_stop_start("abc", &[]), 3);
   }
 }
@@ -1,5 +1,6 @@
 use candle_core::utils::{cuda_is_available, metal_is_available};
 use candle_core::Device;
+use std::any::Any;
 use std::path::Path;
 
 use crate::types::model::ModelId;
@@ -72,6 +73,19 @@ pub fn local_load_safetensors(
 pub fn eos_token(model_id: ModelId) -> &'static str {
   match model_id {
     ModelId::PhiV2 => "<|endoftext|>",
+    // Instruction-tuned Gemma closes its turn before it would ever emit `<eos>`
+    ModelId::CodeGemma7bIt => "<end_of_turn>",
     _ => "</s>",
   }
 }
+
+/// Message carried by a caught panic payload.
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+  if let Some(message) = payload.downcast_ref::<&str>() {
+    message
+  } else if let Some(message) = payload.downcast_ref::<String>() {
+    message
+  } else {
+    "unknown panic"
+  }
+}
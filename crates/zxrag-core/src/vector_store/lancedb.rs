@@ -0,0 +1,504 @@
+use arrow_array::RecordBatchIterator;
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use vectordb::index::MetricType;
+use vectordb::table::NativeTable;
+use vectordb::{Connection, TableRef};
+
+use crate::retriever::{DistanceMetric, RetrievedChunk};
+use crate::types::lancedb::{
+  check_embedding_schema, chunks_record_batch, embedding_model, get_embedding_schema,
+  record_batch_chunks, record_batch_vectors, vector_dimension,
+};
+use crate::vector_store::{
+  Chunk, ChunkFilter, VectorIndexKind, VectorIndexParams, VectorIndexStats, VectorQuery,
+  VectorStore,
+};
+
+/// Rows an IVF-PQ index needs to train the 256 centroids of each sub-vector.
+const MIN_INDEX_ROWS: usize = 256;
+
+/// The LanceDB database holding a `kb_{id}` table per knowledge base, connected once and shared
+/// by every handler, along with the tables opened so far.
+#[derive(Clone)]
+pub struct LanceDbVectorStore {
+  db: Arc<dyn Connection>,
+  tables: Arc<RwLock<HashMap<i64, TableRef>>>,
+}
+
+impl LanceDbVectorStore {
+  pub async fn connect(lancedb_path: &str) -> anyhow::Result<Self> {
+    let db = vectordb::connect(lancedb_path).await?;
+
+    Ok(Self {
+      db,
+      tables: Arc::new(RwLock::new(HashMap::new())),
+    })
+  }
+
+  pub fn table_name(kb_id: i64) -> String {
+    format!("kb_{}", kb_id)
+  }
+
+  /// The table of a knowledge base, opened on first use.
+  pub async fn open_table(&self, kb_id: i64) -> anyhow::Result<TableRef> {
+    if let Some(tbl) = self.tables.read().await.get(&kb_id) {
+      return Ok(tbl.clone());
+    }
+
+    // Opened under the write lock, so a table being dropped can't be cached again
+    let mut tables = self.tables.write().await;
+
+    if let Some(tbl) = tables.get(&kb_id) {
+      return Ok(tbl.clone());
+    }
+
+    let tbl = self.db.open_table(&Self::table_name(kb_id)).await?;
+
+    tables.insert(kb_id, tbl.clone());
+
+    Ok(tbl)
+  }
+
+  async fn table_exists(&self, kb_id: i64) -> anyhow::Result<bool> {
+    Ok(
+      self
+        .db
+        .table_names()
+        .await?
+        .contains(&Self::table_name(kb_id)),
+    )
+  }
+
+  async fn create_table(&self, kb_id: i64) -> anyhow::Result<TableRef> {
+    let schema = get_embedding_schema()?;
+
+    let batches = RecordBatchIterator::new(vec![], schema);
+
+    Ok(
+      self
+        .db
+        .create_table(&Self::table_name(kb_id), Box::new(batches), None)
+        .await?,
+    )
+  }
+
+  fn native_table(kb_id: i64, tbl: &TableRef) -> anyhow::Result<&NativeTable> {
+    tbl
+      .as_native()
+      .ok_or(anyhow::anyhow!("kb_{} is not a local table", kb_id))
+  }
+
+  /// The filter as a predicate of the rows of a knowledge base, `text` excepted.
+  fn predicate(kb_id: i64, filter: &ChunkFilter) -> String {
+    match filter.to_sql() {
+      Some(sql) => format!("kb_id = {} AND {}", kb_id, sql),
+      None => format!("kb_id = {}", kb_id),
+    }
+  }
+}
+
+#[async_trait]
+impl VectorStore for LanceDbVectorStore {
+  async fn create_collection(&self, kb_id: i64) -> anyhow::Result<()> {
+    let mut tables = self.tables.write().await;
+
+    if tables.contains_key(&kb_id) || self.table_exists(kb_id).await? {
+      return Ok(());
+    }
+
+    let tbl = self.create_table(kb_id).await?;
+
+    tables.insert(kb_id, tbl);
+
+    Ok(())
+  }
+
+  async fn recreate_collection(&self, kb_id: i64) -> anyhow::Result<()> {
+    let mut tables = self.tables.write().await;
+
+    tables.remove(&kb_id);
+
+    if self.table_exists(kb_id).await? {
+      self.db.drop_table(&Self::table_name(kb_id)).await?;
+    }
+
+    let tbl = self.create_table(kb_id).await?;
+
+    tables.insert(kb_id, tbl);
+
+    Ok(())
+  }
+
+  async fn drop_collection(&self, kb_id: i64) -> anyhow::Result<()> {
+    let mut tables = self.tables.write().await;
+
+    tables.remove(&kb_id);
+
+    if self.table_exists(kb_id).await? {
+      self.db.drop_table(&Self::table_name(kb_id)).await?;
+    }
+
+    Ok(())
+  }
+
+  async fn collections(&self) -> anyhow::Result<Vec<i64>> {
+    Ok(
+      self
+        .db
+        .table_names()
+        .await?
+        .iter()
+        .filter_map(|name| name.strip_prefix("kb_")?.parse().ok())
+        .collect(),
+    )
+  }
+
+  async fn check_collection(&self, kb_id: i64) -> anyhow::Result<()> {
+    check_embedding_schema(&self.open_table(kb_id).await?.schema())
+  }
+
+  async fn embedding_model(&self, kb_id: i64) -> anyhow::Result<Option<String>> {
+    Ok(embedding_model(&self.open_table(kb_id).await?.schema()).map(str::to_string))
+  }
+
+  async fn dimension(&self, kb_id: i64) -> anyhow::Result<usize> {
+    vector_dimension(&self.open_table(kb_id).await?.schema())
+      .ok_or(anyhow::anyhow!("kb_{} has no vector column", kb_id))
+  }
+
+  async fn count(&self, kb_id: i64) -> anyhow::Result<usize> {
+    Ok(self.open_table(kb_id).await?.count_rows(None).await?)
+  }
+
+  async fn upsert(&self, kb_id: i64, chunks: Vec<Chunk>) -> anyhow::Result<()> {
+    if chunks.is_empty() {
+      return Ok(());
+    }
+
+    let tbl = self.open_table(kb_id).await?;
+
+    let ids = ChunkFilter {
+      ids: chunks.iter().map(|chunk| chunk.id.clone()).collect(),
+      ..Default::default()
+    };
+
+    tbl.delete(&Self::predicate(kb_id, &ids)).await?;
+
+    let batch = chunks_record_batch(&chunks, tbl.schema())?;
+
+    let schema = batch.schema();
+
+    tbl
+      .add(
+        Box::new(RecordBatchIterator::new(vec![Ok(batch)], schema)),
+        None,
+      )
+      .await?;
+
+    Ok(())
+  }
+
+  async fn delete(&self, kb_id: i64, filter: &ChunkFilter) -> anyhow::Result<()> {
+    let tbl = self.open_table(kb_id).await?;
+
+    let predicate = match filter.text {
+      // Text can't be matched by the predicate, so the matching rows are deleted by id
+      Some(_) => {
+        let ids: Vec<String> = self
+          .list(kb_id, filter, 0, None, false)
+          .await?
+          .into_iter()
+          .map(|chunk| chunk.id)
+          .collect();
+
+        if ids.is_empty() {
+          return Ok(());
+        }
+
+        Self::predicate(
+          kb_id,
+          &ChunkFilter {
+            ids,
+            ..Default::default()
+          },
+        )
+      }
+      None => Self::predicate(kb_id, filter),
+    };
+
+    tbl.delete(&predicate).await?;
+
+    Ok(())
+  }
+
+  async fn search(&self, kb_id: i64, query: &VectorQuery) -> anyhow::Result<Vec<RetrievedChunk>> {
+    let tbl = self.open_table(kb_id).await?;
+
+    let mut search = tbl
+      .search(&query.vector)
+      .metric_type(metric_type(query.metric))
+      .nprobes(query.nprobes)
+      .limit(query.limit);
+
+    if let Some(refine_factor) = query.refine_factor {
+      search = search.refine_factor(refine_factor);
+    }
+
+    if let Some(filter) = query.filter.to_sql() {
+      search = search.filter(filter).prefilter(true);
+    }
+
+    let chunks = search
+      .execute_stream()
+      .await?
+      .try_collect::<Vec<_>>()
+      .await?
+      .iter()
+      .map(record_batch_chunks)
+      .collect::<anyhow::Result<Vec<_>>>()?
+      .concat();
+
+    Ok(chunks)
+  }
+
+  async fn list(
+    &self,
+    kb_id: i64,
+    filter: &ChunkFilter,
+    offset: usize,
+    limit: Option<usize>,
+    with_vectors: bool,
+  ) -> anyhow::Result<Vec<Chunk>> {
+    let tbl = self.open_table(kb_id).await?;
+
+    let mut stream = tbl
+      .query()
+      .filter(Self::predicate(kb_id, filter))
+      .execute_stream()
+      .await?;
+
+    let limit = limit.unwrap_or(usize::MAX);
+
+    let mut skipped = 0;
+    let mut chunks = vec![];
+
+    // Read a batch at a time, so a page near the start doesn't read the whole table
+    while let Some(batch) = stream.try_next().await? {
+      let vectors = if with_vectors {
+        record_batch_vectors(&batch)?
+      } else {
+        vec![vec![]; batch.num_rows()]
+      };
+
+      for (chunk, vector) in record_batch_chunks(&batch)?.into_iter().zip(vectors) {
+        let chunk = Chunk {
+          id: chunk.id,
+          kb_id: chunk.kb_id,
+          file_id: chunk.file_id,
+          file_name: chunk.file_name,
+          text: chunk.text,
+          heading_path: chunk.heading_path,
+          vector,
+        };
+
+        if !filter.matches(&chunk) {
+          continue;
+        }
+
+        if skipped < offset {
+          skipped += 1;
+          continue;
+        }
+
+        if chunks.len() == limit {
+          return Ok(chunks);
+        }
+
+        chunks.push(chunk);
+      }
+    }
+
+    Ok(chunks)
+  }
+
+  async fn create_index(&self, kb_id: i64, params: &VectorIndexParams) -> anyhow::Result<()> {
+    if params.kind != VectorIndexKind::IvfPq {
+      anyhow::bail!("LanceDB can't build {} indexes, only ivf_pq", params.kind);
+    }
+
+    let tbl = self.open_table(kb_id).await?;
+
+    let rows = tbl.count_rows(None).await?;
+
+    if rows < MIN_INDEX_ROWS {
+      anyhow::bail!(
+        "kb_{} holds {} rows, an ivf_pq index needs at least {}",
+        kb_id,
+        rows,
+        MIN_INDEX_ROWS
+      );
+    }
+
+    let mut builder = tbl.create_index(&["vector"]);
+
+    builder
+      .ivf_pq()
+      .metric_type(metric_type(params.metric))
+      .replace(true);
+
+    if params.num_partitions > 0 {
+      builder.num_partitions(params.num_partitions as u32);
+    }
+
+    if params.num_sub_vectors > 0 {
+      builder.num_sub_vectors(params.num_sub_vectors as u32);
+    }
+
+    builder.build().await?;
+
+    Ok(())
+  }
+
+  async fn index_stats(&self, kb_id: i64) -> anyhow::Result<Option<VectorIndexStats>> {
+    let tbl = self.open_table(kb_id).await?;
+
+    let native = Self::native_table(kb_id, &tbl)?;
+
+    let Some(index) = native
+      .load_indices()
+      .await?
+      .into_iter()
+      .find(|index| index.columns.iter().any(|column| column == "vector"))
+    else {
+      return Ok(None);
+    };
+
+    // LanceDB 0.4 doesn't report the type of an index, and only builds IVF-PQ vector indexes
+    Ok(Some(VectorIndexStats {
+      index_type: VectorIndexKind::IvfPq.to_string(),
+      indexed_rows: native
+        .count_indexed_rows(&index.index_uuid)
+        .await?
+        .unwrap_or_default(),
+      unindexed_rows: native
+        .count_unindexed_rows(&index.index_uuid)
+        .await?
+        .unwrap_or_default(),
+      name: index.index_name,
+    }))
+  }
+
+  async fn drop_index(&self, kb_id: i64) -> anyhow::Result<()> {
+    // LanceDB can't drop an index, and copying a table this large to one without it would hold
+    // the whole collection at risk for the length of the copy
+    if self.index_stats(kb_id).await?.is_some() {
+      anyhow::bail!(
+        "LanceDB can't drop the index of kb_{}, reindex the knowledge base to rebuild it unindexed",
+        kb_id
+      );
+    }
+
+    Ok(())
+  }
+}
+
+fn metric_type(metric: DistanceMetric) -> MetricType {
+  match metric {
+    DistanceMetric::L2 => MetricType::L2,
+    DistanceMetric::Cosine => MetricType::Cosine,
+    DistanceMetric::Dot => MetricType::Dot,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::types::lancedb::set_embedding_schema;
+  use crate::types::model::ModelId;
+
+  async fn store(name: &str) -> LanceDbVectorStore {
+    // The schema is global, every test uses the same dimension
+    let _ = set_embedding_schema(ModelId::default(), 2);
+
+    let path = std::env::temp_dir().join(format!("zxrag-lancedb-{}-{}", std::process::id(), name));
+
+    LanceDbVectorStore::connect(&path.display().to_string())
+      .await
+      .unwrap()
+  }
+
+  fn chunk(id: &str) -> Chunk {
+    Chunk {
+      id: id.to_string(),
+      kb_id: 1,
+      file_id: 1,
+      file_name: "a.md".to_string(),
+      text: id.to_string(),
+      heading_path: String::new(),
+      vector: vec![1.0, 0.0],
+    }
+  }
+
+  #[tokio::test]
+  async fn tables_are_opened_once_for_every_handle() {
+    let store = store("shared").await;
+
+    store.create_collection(1).await.unwrap();
+
+    let tbl = store.open_table(1).await.unwrap();
+
+    assert!(Arc::ptr_eq(&tbl, &store.open_table(1).await.unwrap()));
+    assert!(Arc::ptr_eq(
+      &tbl,
+      &store.clone().open_table(1).await.unwrap()
+    ));
+  }
+
+  #[tokio::test]
+  async fn recreated_tables_replace_the_cached_one() {
+    let store = store("recreated").await;
+
+    store.create_collection(1).await.unwrap();
+    store.upsert(1, vec![chunk("a")]).await.unwrap();
+
+    let tbl = store.open_table(1).await.unwrap();
+
+    store.recreate_collection(1).await.unwrap();
+
+    assert!(!Arc::ptr_eq(&tbl, &store.open_table(1).await.unwrap()));
+    assert_eq!(store.count(1).await.unwrap(), 0);
+  }
+
+  #[tokio::test]
+  async fn dropped_tables_leave_the_cache() {
+    let store = store("dropped").await;
+
+    store.create_collection(1).await.unwrap();
+    store.open_table(1).await.unwrap();
+    store.drop_collection(1).await.unwrap();
+
+    assert!(!store.has_collection(1).await.unwrap());
+    assert!(store.open_table(1).await.is_err());
+  }
+
+  #[test]
+  fn predicates_are_scoped_to_the_knowledge_base() {
+    let filter = ChunkFilter {
+      file_ids: vec![2, 3],
+      ..Default::default()
+    };
+
+    assert_eq!(
+      LanceDbVectorStore::predicate(1, &ChunkFilter::default()),
+      "kb_id = 1"
+    );
+    assert_eq!(
+      LanceDbVectorStore::predicate(1, &filter),
+      "kb_id = 1 AND file_id IN (2, 3)"
+    );
+  }
+}
@@ -0,0 +1,369 @@
+use arrow_schema::Schema;
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::retriever::RetrievedChunk;
+use crate::types::lancedb::{
+  check_embedding_schema, embedding_model, get_embedding_schema, vector_dimension,
+};
+use crate::vector_store::{Chunk, ChunkFilter, VectorQuery, VectorStore};
+
+/// Collections held in memory and searched by comparing the query with every vector, so they have
+/// no indexes. Distances are those LanceDB reports, so scores compare across stores.
+#[derive(Default, Clone)]
+pub struct MemoryVectorStore {
+  collections: Arc<RwLock<HashMap<i64, MemoryCollection>>>,
+}
+
+struct MemoryCollection {
+  /// The embedding schema when the collection was created, as a LanceDB table would keep it.
+  schema: Arc<Schema>,
+  chunks: Vec<Chunk>,
+}
+
+impl MemoryVectorStore {
+  fn read(&self) -> anyhow::Result<RwLockReadGuard<'_, HashMap<i64, MemoryCollection>>> {
+    self
+      .collections
+      .read()
+      .map_err(|_| anyhow::anyhow!("memory vector store lock poisoned"))
+  }
+
+  fn write(&self) -> anyhow::Result<RwLockWriteGuard<'_, HashMap<i64, MemoryCollection>>> {
+    self
+      .collections
+      .write()
+      .map_err(|_| anyhow::anyhow!("memory vector store lock poisoned"))
+  }
+}
+
+#[async_trait]
+impl VectorStore for MemoryVectorStore {
+  async fn create_collection(&self, kb_id: i64) -> anyhow::Result<()> {
+    let schema = get_embedding_schema()?;
+
+    self
+      .write()?
+      .entry(kb_id)
+      .or_insert_with(|| MemoryCollection {
+        schema,
+        chunks: vec![],
+      });
+
+    Ok(())
+  }
+
+  async fn recreate_collection(&self, kb_id: i64) -> anyhow::Result<()> {
+    let schema = get_embedding_schema()?;
+
+    self.write()?.insert(
+      kb_id,
+      MemoryCollection {
+        schema,
+        chunks: vec![],
+      },
+    );
+
+    Ok(())
+  }
+
+  async fn drop_collection(&self, kb_id: i64) -> anyhow::Result<()> {
+    self.write()?.remove(&kb_id);
+
+    Ok(())
+  }
+
+  async fn collections(&self) -> anyhow::Result<Vec<i64>> {
+    Ok(self.read()?.keys().copied().collect())
+  }
+
+  async fn check_collection(&self, kb_id: i64) -> anyhow::Result<()> {
+    let collections = self.read()?;
+
+    check_embedding_schema(&collection(&collections, kb_id)?.schema)
+  }
+
+  async fn embedding_model(&self, kb_id: i64) -> anyhow::Result<Option<String>> {
+    let collections = self.read()?;
+
+    Ok(embedding_model(&collection(&collections, kb_id)?.schema).map(str::to_string))
+  }
+
+  async fn dimension(&self, kb_id: i64) -> anyhow::Result<usize> {
+    let collections = self.read()?;
+
+    vector_dimension(&collection(&collections, kb_id)?.schema)
+      .ok_or(anyhow::anyhow!("kb_{} has no vector dimension", kb_id))
+  }
+
+  async fn count(&self, kb_id: i64) -> anyhow::Result<usize> {
+    let collections = self.read()?;
+
+    Ok(collection(&collections, kb_id)?.chunks.len())
+  }
+
+  async fn upsert(&self, kb_id: i64, chunks: Vec<Chunk>) -> anyhow::Result<()> {
+    let mut collections = self.write()?;
+
+    let collection = collections
+      .get_mut(&kb_id)
+      .ok_or(anyhow::anyhow!("kb_{} does not exist", kb_id))?;
+
+    let dimension = vector_dimension(&collection.schema).unwrap_or_default();
+
+    if let Some(chunk) = chunks.iter().find(|chunk| chunk.vector.len() != dimension) {
+      anyhow::bail!(
+        "chunk {} has {} dims but kb_{} holds {} dims",
+        chunk.id,
+        chunk.vector.len(),
+        kb_id,
+        dimension
+      );
+    }
+
+    let ids: HashSet<String> = chunks.iter().map(|chunk| chunk.id.clone()).collect();
+
+    collection.chunks.retain(|stored| !ids.contains(&stored.id));
+    collection.chunks.extend(chunks);
+
+    Ok(())
+  }
+
+  async fn delete(&self, kb_id: i64, filter: &ChunkFilter) -> anyhow::Result<()> {
+    if let Some(collection) = self.write()?.get_mut(&kb_id) {
+      collection.chunks.retain(|chunk| !filter.matches(chunk));
+    }
+
+    Ok(())
+  }
+
+  async fn search(&self, kb_id: i64, query: &VectorQuery) -> anyhow::Result<Vec<RetrievedChunk>> {
+    let collections = self.read()?;
+
+    let mut ranked: Vec<(f32, &Chunk)> = collection(&collections, kb_id)?
+      .chunks
+      .iter()
+      .filter(|chunk| query.filter.matches(chunk))
+      .map(|chunk| (query.metric.distance(&query.vector, &chunk.vector), chunk))
+      .collect();
+
+    ranked.sort_by(|a, b| a.0.total_cmp(&b.0));
+    ranked.truncate(query.limit);
+
+    Ok(
+      ranked
+        .into_iter()
+        .map(|(distance, chunk)| chunk.clone().into_retrieved(distance))
+        .collect(),
+    )
+  }
+
+  async fn list(
+    &self,
+    kb_id: i64,
+    filter: &ChunkFilter,
+    offset: usize,
+    limit: Option<usize>,
+    with_vectors: bool,
+  ) -> anyhow::Result<Vec<Chunk>> {
+    let collections = self.read()?;
+
+    Ok(
+      collection(&collections, kb_id)?
+        .chunks
+        .iter()
+        .filter(|chunk| filter.matches(chunk))
+        .skip(offset)
+        .take(limit.unwrap_or(usize::MAX))
+        .map(|chunk| Chunk {
+          vector: if with_vectors {
+            chunk.vector.clone()
+          } else {
+            vec![]
+          },
+          ..chunk.clone()
+        })
+        .collect(),
+    )
+  }
+}
+
+fn collection(
+  collections: &HashMap<i64, MemoryCollection>,
+  kb_id: i64,
+) -> anyhow::Result<&MemoryCollection> {
+  collections
+    .get(&kb_id)
+    .ok_or(anyhow::anyhow!("kb_{} does not exist", kb_id))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::retriever::DistanceMetric;
+  use crate::types::lancedb::set_embedding_schema;
+  use crate::types::model::ModelId;
+
+  const KB_ID: i64 = 1;
+
+  async fn store() -> MemoryVectorStore {
+    // The schema is global, every test uses the same dimension
+    let _ = set_embedding_schema(ModelId::default(), 2);
+
+    let store = MemoryVectorStore::default();
+    store.create_collection(KB_ID).await.unwrap();
+    store
+  }
+
+  fn chunk(id: &str, file_id: i64, text: &str, vector: [f32; 2]) -> Chunk {
+    Chunk {
+      id: id.to_string(),
+      kb_id: KB_ID,
+      file_id,
+      file_name: format!("{}.txt", file_id),
+      text: text.to_string(),
+      heading_path: String::new(),
+      vector: vector.to_vec(),
+    }
+  }
+
+  fn ids(chunks: &[RetrievedChunk]) -> Vec<&str> {
+    chunks.iter().map(|chunk| chunk.id.as_str()).collect()
+  }
+
+  #[tokio::test]
+  async fn upsert_replaces_chunks_with_the_same_id() {
+    let store = store().await;
+
+    store
+      .upsert(KB_ID, vec![chunk("a", 1, "old", [1.0, 0.0])])
+      .await
+      .unwrap();
+    store
+      .upsert(
+        KB_ID,
+        vec![
+          chunk("a", 1, "new", [0.0, 1.0]),
+          chunk("b", 1, "b", [1.0, 1.0]),
+        ],
+      )
+      .await
+      .unwrap();
+
+    assert_eq!(store.count(KB_ID).await.unwrap(), 2);
+
+    let filter = ChunkFilter {
+      ids: vec!["a".to_string()],
+      ..Default::default()
+    };
+    let listed = store.list(KB_ID, &filter, 0, None, true).await.unwrap();
+
+    assert_eq!(listed, vec![chunk("a", 1, "new", [0.0, 1.0])]);
+  }
+
+  #[tokio::test]
+  async fn upsert_rejects_other_dimensions() {
+    let store = store().await;
+
+    let mut wide = chunk("a", 1, "a", [1.0, 0.0]);
+    wide.vector.push(0.0);
+
+    assert!(store.upsert(KB_ID, vec![wide]).await.is_err());
+    assert_eq!(store.count(KB_ID).await.unwrap(), 0);
+  }
+
+  #[tokio::test]
+  async fn delete_honours_the_filter() {
+    let store = store().await;
+
+    store
+      .upsert(
+        KB_ID,
+        vec![
+          chunk("a", 1, "Alpha", [1.0, 0.0]),
+          chunk("b", 1, "beta", [1.0, 0.0]),
+          chunk("c", 2, "alpha", [1.0, 0.0]),
+        ],
+      )
+      .await
+      .unwrap();
+
+    let filter = ChunkFilter {
+      file_ids: vec![1],
+      text: Some("ALPHA".to_string()),
+      ..Default::default()
+    };
+    store.delete(KB_ID, &filter).await.unwrap();
+
+    let listed = store
+      .list(KB_ID, &ChunkFilter::default(), 0, None, false)
+      .await
+      .unwrap();
+    let listed: Vec<&str> = listed.iter().map(|chunk| chunk.id.as_str()).collect();
+
+    assert_eq!(listed, vec!["b", "c"]);
+  }
+
+  #[tokio::test]
+  async fn search_orders_by_distance_for_each_metric() {
+    let store = store().await;
+
+    store
+      .upsert(
+        KB_ID,
+        vec![
+          chunk("a", 1, "a", [1.0, 0.0]),
+          chunk("b", 1, "b", [3.0, 1.0]),
+          chunk("c", 2, "c", [0.0, 1.0]),
+        ],
+      )
+      .await
+      .unwrap();
+
+    for (metric, expected) in [
+      (DistanceMetric::L2, vec!["a", "c", "b"]),
+      (DistanceMetric::Cosine, vec!["a", "b", "c"]),
+      (DistanceMetric::Dot, vec!["b", "a", "c"]),
+    ] {
+      let query = VectorQuery {
+        vector: vec![1.0, 0.0],
+        limit: 3,
+        metric,
+        ..Default::default()
+      };
+      let found = store.search(KB_ID, &query).await.unwrap();
+
+      assert_eq!(ids(&found), expected, "{}", metric);
+      assert!(found.windows(2).all(|w| w[0].score <= w[1].score));
+    }
+
+    let query = VectorQuery {
+      vector: vec![1.0, 0.0],
+      limit: 1,
+      filter: ChunkFilter {
+        file_ids: vec![2],
+        ..Default::default()
+      },
+      ..Default::default()
+    };
+
+    assert_eq!(ids(&store.search(KB_ID, &query).await.unwrap()), vec!["c"]);
+  }
+
+  #[test]
+  fn to_sql_escapes_quotes() {
+    let filter = ChunkFilter {
+      ids: vec!["it's".to_string()],
+      file_ids: vec![1, 2],
+      filenames: vec!["a'b.txt".to_string(), "c.txt".to_string()],
+      text: Some("ignored".to_string()),
+    };
+
+    assert_eq!(
+      filter.to_sql().as_deref(),
+      Some("id IN ('it''s') AND file_id IN (1, 2) AND file_name IN ('a''b.txt', 'c.txt')")
+    );
+    assert_eq!(ChunkFilter::default().to_sql(), None);
+  }
+}
@@ -0,0 +1,278 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use strum::{Display, EnumString};
+
+use crate::retriever::{DistanceMetric, RetrievalFilter, RetrievedChunk};
+use crate::types::conf::BackendConf;
+
+pub mod lancedb;
+pub mod memory;
+
+pub use lancedb::LanceDbVectorStore;
+pub use memory::MemoryVectorStore;
+
+/// A row of a knowledge base collection: a chunk of one of its files and the chunk's embedding.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Chunk {
+  pub id: String,
+  pub kb_id: i64,
+  pub file_id: i64,
+  pub file_name: String,
+  pub text: String,
+  pub heading_path: String,
+  /// Empty when listed without vectors.
+  pub vector: Vec<f32>,
+}
+
+impl Chunk {
+  pub fn into_retrieved(self, score: f32) -> RetrievedChunk {
+    RetrievedChunk {
+      id: self.id,
+      kb_id: self.kb_id,
+      file_id: self.file_id,
+      file_name: self.file_name,
+      text: self.text,
+      heading_path: self.heading_path,
+      score,
+    }
+  }
+}
+
+/// Selects chunks of a collection. Fields left empty don't restrict the selection.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChunkFilter {
+  pub ids: Vec<String>,
+  pub file_ids: Vec<i64>,
+  pub filenames: Vec<String>,
+  /// Text the chunk contains, ignoring case.
+  pub text: Option<String>,
+}
+
+impl ChunkFilter {
+  pub fn matches(&self, chunk: &Chunk) -> bool {
+    (self.ids.is_empty() || self.ids.contains(&chunk.id))
+      && (self.file_ids.is_empty() || self.file_ids.contains(&chunk.file_id))
+      && (self.filenames.is_empty() || self.filenames.contains(&chunk.file_name))
+      && self
+        .text
+        .as_ref()
+        .is_none_or(|text| chunk.text.to_lowercase().contains(&text.to_lowercase()))
+  }
+
+  /// The filter as a LanceDB SQL predicate, `text` excepted, `None` when nothing else is set.
+  pub fn to_sql(&self) -> Option<String> {
+    let mut predicates = vec![];
+
+    if !self.ids.is_empty() {
+      predicates.push(format!("id IN ({})", quote_all(&self.ids)));
+    }
+
+    if !self.file_ids.is_empty() {
+      let file_ids: Vec<String> = self.file_ids.iter().map(|id| id.to_string()).collect();
+
+      predicates.push(format!("file_id IN ({})", file_ids.join(", ")));
+    }
+
+    if !self.filenames.is_empty() {
+      predicates.push(format!("file_name IN ({})", quote_all(&self.filenames)));
+    }
+
+    if predicates.is_empty() {
+      None
+    } else {
+      Some(predicates.join(" AND "))
+    }
+  }
+}
+
+impl From<&RetrievalFilter> for ChunkFilter {
+  fn from(filter: &RetrievalFilter) -> Self {
+    Self {
+      file_ids: filter.file_ids.clone(),
+      filenames: filter.filenames.clone(),
+      ..Default::default()
+    }
+  }
+}
+
+/// A nearest neighbour search of a collection.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VectorQuery {
+  pub vector: Vec<f32>,
+  pub limit: usize,
+  pub metric: DistanceMetric,
+  /// Applied before the search, so `limit` chunks are returned when that many match. Stores may
+  /// leave `text` out.
+  pub filter: ChunkFilter,
+  /// Index partitions searched, more are slower and miss fewer of the nearest chunks.
+  pub nprobes: usize,
+  /// Candidates rescored with their full vectors, as a multiple of `limit`. `None` keeps the
+  /// approximate distances of the index.
+  pub refine_factor: Option<u32>,
+}
+
+/// Approximate nearest neighbour index of a collection, searched instead of every vector.
+#[derive(
+  Clone, Default, Debug, Copy, PartialEq, Eq, Deserialize, Serialize, EnumString, Display,
+)]
+pub enum VectorIndexKind {
+  /// Vectors clustered into `num_partitions` lists and compressed into `num_sub_vectors` codes.
+  #[default]
+  #[serde(rename = "ivf_pq")]
+  #[strum(serialize = "ivf_pq")]
+  IvfPq,
+  /// Hierarchical navigable small world graph, which LanceDB 0.4 can't build yet.
+  #[serde(rename = "hnsw")]
+  #[strum(serialize = "hnsw")]
+  Hnsw,
+}
+
+/// How to build the index of a collection. Unset fields fall back to `vector_index_conf`, and the
+/// metric to `retrieval_conf.metric`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct VectorIndexSetting {
+  pub kind: Option<VectorIndexKind>,
+  /// Searches with another metric rank chunks by the distance the index was built for.
+  pub metric: Option<DistanceMetric>,
+  pub num_partitions: Option<usize>,
+  pub num_sub_vectors: Option<usize>,
+}
+
+impl VectorIndexSetting {
+  pub fn params(self, conf: &BackendConf) -> VectorIndexParams {
+    let index_conf = &conf.vector_index_conf;
+
+    VectorIndexParams {
+      kind: self.kind.unwrap_or(index_conf.kind),
+      metric: self.metric.unwrap_or(conf.retrieval_conf.metric),
+      num_partitions: self.num_partitions.unwrap_or(index_conf.num_partitions),
+      num_sub_vectors: self.num_sub_vectors.unwrap_or(index_conf.num_sub_vectors),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct VectorIndexParams {
+  pub kind: VectorIndexKind,
+  pub metric: DistanceMetric,
+  /// 0 lets the store pick from the number of rows.
+  pub num_partitions: usize,
+  /// 0 lets the store pick from the dimension.
+  pub num_sub_vectors: usize,
+}
+
+/// The index of a collection and how much of the collection it covers.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct VectorIndexStats {
+  pub name: String,
+  /// A [`VectorIndexKind`], such as `ivf_pq`.
+  pub index_type: String,
+  pub indexed_rows: usize,
+  /// Rows added since the index was built, which searches scan on top of it.
+  pub unindexed_rows: usize,
+}
+
+/// Storage of the chunks of knowledge bases, one collection per knowledge base. A collection is
+/// built for the embedding model loaded when it was created.
+#[async_trait]
+pub trait VectorStore: Send + Sync {
+  /// Creates the empty collection of a knowledge base, unless it exists.
+  async fn create_collection(&self, kb_id: i64) -> anyhow::Result<()>;
+
+  /// Replaces the collection of a knowledge base with an empty one.
+  async fn recreate_collection(&self, kb_id: i64) -> anyhow::Result<()>;
+
+  /// Drops the collection of a knowledge base, if there is one.
+  async fn drop_collection(&self, kb_id: i64) -> anyhow::Result<()>;
+
+  /// Ids of the knowledge bases that have a collection.
+  async fn collections(&self) -> anyhow::Result<Vec<i64>>;
+
+  async fn has_collection(&self, kb_id: i64) -> anyhow::Result<bool> {
+    Ok(self.collections().await?.contains(&kb_id))
+  }
+
+  /// Fails when the collection was built with another embedding model than the loaded one.
+  async fn check_collection(&self, kb_id: i64) -> anyhow::Result<()>;
+
+  /// Embedding model recorded by a collection, `None` for collections created before models were
+  /// recorded. Recreating such a collection records the loaded model.
+  async fn embedding_model(&self, kb_id: i64) -> anyhow::Result<Option<String>>;
+
+  /// Dimension of the vectors of a collection.
+  async fn dimension(&self, kb_id: i64) -> anyhow::Result<usize>;
+
+  /// Rows of a collection.
+  async fn count(&self, kb_id: i64) -> anyhow::Result<usize>;
+
+  /// Stores chunks, replacing those with the same ids.
+  async fn upsert(&self, kb_id: i64, chunks: Vec<Chunk>) -> anyhow::Result<()>;
+
+  async fn delete(&self, kb_id: i64, filter: &ChunkFilter) -> anyhow::Result<()>;
+
+  /// Up to `query.limit` chunks nearest to `query.vector`, nearest first, scored by their distance.
+  async fn search(&self, kb_id: i64, query: &VectorQuery) -> anyhow::Result<Vec<RetrievedChunk>>;
+
+  /// Chunks matching `filter` in storage order, after skipping `offset` of them and up to `limit`.
+  /// Their vectors are left empty unless `with_vectors` is set.
+  async fn list(
+    &self,
+    kb_id: i64,
+    filter: &ChunkFilter,
+    offset: usize,
+    limit: Option<usize>,
+    with_vectors: bool,
+  ) -> anyhow::Result<Vec<Chunk>>;
+
+  /// Builds the index of a collection, replacing the one it has. Stores without indexes fail.
+  async fn create_index(&self, kb_id: i64, _params: &VectorIndexParams) -> anyhow::Result<()> {
+    anyhow::bail!(
+      "kb_{} can't be indexed, the vector store only searches exhaustively",
+      kb_id
+    )
+  }
+
+  /// The index of a collection, `None` when it has none.
+  async fn index_stats(&self, _kb_id: i64) -> anyhow::Result<Option<VectorIndexStats>> {
+    Ok(None)
+  }
+
+  /// Drops the index of a collection, if it has one, so searches scan every vector again. Stores
+  /// that can't drop an index fail.
+  async fn drop_index(&self, _kb_id: i64) -> anyhow::Result<()> {
+    Ok(())
+  }
+}
+
+#[derive(
+  Clone, Default, Debug, Copy, PartialEq, Eq, Deserialize, Serialize, EnumString, Display,
+)]
+pub enum VectorStoreKind {
+  /// LanceDB tables under `lancedb_path`, one `kb_{id}` table per knowledge base.
+  #[default]
+  #[serde(rename = "lancedb")]
+  #[strum(serialize = "lancedb")]
+  LanceDb,
+  /// Brute-force search over chunks held in memory, lost on exit.
+  #[serde(rename = "memory")]
+  #[strum(serialize = "memory")]
+  Memory,
+}
+
+pub async fn new_vector_store(conf: &BackendConf) -> anyhow::Result<Arc<dyn VectorStore>> {
+  let vector_store: Arc<dyn VectorStore> = match conf.vector_store {
+    VectorStoreKind::LanceDb => Arc::new(LanceDbVectorStore::connect(&conf.lancedb_path).await?),
+    VectorStoreKind::Memory => Arc::new(MemoryVectorStore::default()),
+  };
+
+  Ok(vector_store)
+}
+
+fn quote_all(values: &[String]) -> String {
+  values
+    .iter()
+    .map(|value| format!("'{}'", value.replace('\'', "''")))
+    .collect::<Vec<String>>()
+    .join(", ")
+}
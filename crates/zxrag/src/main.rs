@@ -9,14 +9,20 @@ use std::sync::Arc;
 use time::format_description::well_known;
 use time::UtcOffset;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use zxrag_backend::gc::run_gc;
+use zxrag_backend::index::{run_index, IndexAction};
 use zxrag_backend::run_backend;
+use zxrag_core::retriever::DistanceMetric;
 use zxrag_core::types::conf::{init_backend_conf, BackendConf, LlmConf};
 use zxrag_core::types::handle::{
   get_embedding_model, get_text_gen, set_embedding_model_handle, set_llm_model_handle,
+  set_reranker_model_handle,
 };
+use zxrag_core::types::inference::set_inference_worker;
 use zxrag_core::types::lancedb::set_embedding_schema;
 use zxrag_core::types::llm::TextGenerationSetting;
 use zxrag_core::types::model::{ModelEngine, ModelId};
+use zxrag_core::vector_store::{VectorIndexKind, VectorIndexSetting};
 
 #[derive(Debug, Default, Args)]
 pub struct CliConfig {
@@ -32,6 +38,8 @@ pub struct CliConfig {
   pub tokenizer_path: String,
   #[clap(long, default_value_t = String::from("cpu"))]
   pub device: String,
+  #[clap(long, default_value_t = String::new())]
+  pub chat_template_path: String,
   #[arg(long, default_value_t = 1.1)]
   repeat_penalty: f32,
   #[arg(long, default_value_t = 64)]
@@ -54,6 +62,49 @@ pub struct BackendConfig {
   pub config: String,
 }
 
+#[derive(Debug, Default, Args)]
+pub struct GcConfig {
+  #[clap(long, default_value_t = String::from("zxrag.toml"))]
+  pub config: String,
+  /// Only report what would be removed
+  #[clap(long)]
+  pub dry_run: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct IndexConfig {
+  #[clap(long, default_value_t = String::from("zxrag.toml"))]
+  pub config: String,
+  /// Knowledge base whose vector index is managed
+  #[clap(long)]
+  pub kb_id: i64,
+  #[command(subcommand)]
+  pub action: IndexCommands,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum IndexCommands {
+  #[clap(about = "Build the index, replacing the current one")]
+  Build(BuildIndexConfig),
+  #[clap(about = "Show the index and the rows it covers")]
+  Stats,
+  #[clap(about = "Drop the index, searches scan every vector again")]
+  Drop,
+}
+
+/// Unset options fall back to vector_index_conf, and the metric to retrieval_conf
+#[derive(Debug, Default, Args)]
+pub struct BuildIndexConfig {
+  #[clap(long)]
+  pub kind: Option<VectorIndexKind>,
+  #[clap(long)]
+  pub metric: Option<DistanceMetric>,
+  #[clap(long)]
+  pub num_partitions: Option<usize>,
+  #[clap(long)]
+  pub num_sub_vectors: Option<usize>,
+}
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
@@ -70,6 +121,10 @@ enum Commands {
   Cli(CliConfig),
   #[clap(about = "Run the Test")]
   Test(BackendConfig),
+  #[clap(about = "Remove files, vectors and blobs left behind by deletions")]
+  Gc(GcConfig),
+  #[clap(about = "Build, inspect or drop the vector index of a knowledge base")]
+  Index(IndexConfig),
 }
 
 fn main() -> Result<(), anyhow::Error> {
@@ -85,6 +140,9 @@ fn main() -> Result<(), anyhow::Error> {
         repo_id: cli_config.repo_id,
         tokenizer_path: cli_config.tokenizer_path,
         device: cli_config.device,
+        chat_template_path: cli_config.chat_template_path,
+        worker_threads: 1,
+        max_batch_size: 8,
       };
 
       let text_gen_setting = TextGenerationSetting{
@@ -95,6 +153,7 @@ fn main() -> Result<(), anyhow::Error> {
         repeat_last_n: cli_config.repeat_last_n,
         sample_len: cli_config.sample_len,
         prompt: cli_config.prompt,
+        stop: vec![],
       };
 
       tracing::info!("llm_conf={:?}", llm_conf);
@@ -133,12 +192,45 @@ fn main() -> Result<(), anyhow::Error> {
 
       set_llm_model_handle(config.llm_conf.model_id, &config.llm_conf)?;
 
+      set_inference_worker(&config.llm_conf)?;
+
       set_embedding_model_handle(config.embedding_conf.model_id, &config.embedding_conf)?;
 
-      set_embedding_schema()?;
+      let bert_model = get_embedding_model(config.embedding_conf.model_id)?;
+
+      set_embedding_schema(bert_model.id(), bert_model.dimension())?;
+
+      if config.reranker_conf.enabled {
+        set_reranker_model_handle(config.reranker_conf.model_id, &config.reranker_conf)?;
+      }
 
       run_backend(config)?;
     }
+    Commands::Gc(cli_config) => {
+      let config: BackendConf = init_backend_conf(&cli_config.config)?;
+
+      tracing_subscriber::fmt().with_target(false).init();
+
+      run_gc(config, cli_config.dry_run)?;
+    }
+    Commands::Index(cli_config) => {
+      let config: BackendConf = init_backend_conf(&cli_config.config)?;
+
+      tracing_subscriber::fmt().with_target(false).init();
+
+      let action = match cli_config.action {
+        IndexCommands::Build(build_config) => IndexAction::Build(VectorIndexSetting {
+          kind: build_config.kind,
+          metric: build_config.metric,
+          num_partitions: build_config.num_partitions,
+          num_sub_vectors: build_config.num_sub_vectors,
+        }),
+        IndexCommands::Stats => IndexAction::Stats,
+        IndexCommands::Drop => IndexAction::Drop,
+      };
+
+      run_index(config, cli_config.kb_id, action)?;
+    }
     Commands::Test(cli_config) => {
       let config: BackendConf = init_backend_conf(&cli_config.config)?;
 
@@ -163,13 +255,14 @@ fn main() -> Result<(), anyhow::Error> {
         // prompt: "<s>[INST] Hello! [/INST]".to_string(),
         // prompt: "<|user|>\nHello!</s>\n<|assistant|>".to_string(),
         prompt: "Alice: Hello!\nBob: ".to_string(),
+        stop: vec![],
       };
 
       let mut text_gen = get_text_gen(text_gen_setting)?;
 
       let output = text_gen.generate()?;
 
-      tracing::info!("{output}");
+      tracing::info!("{}", output.text);
 
       set_embedding_model_handle(config.embedding_conf.model_id, &config.embedding_conf)?;
 
@@ -186,7 +279,7 @@ fn main() -> Result<(), anyhow::Error> {
         "Do you like pizza?",
       ];
 
-      const DIM: usize = 1024;
+      let dim = bert_model.dimension();
 
       let runtime = tokio::runtime::Runtime::new()?;
 
@@ -208,7 +301,7 @@ fn main() -> Result<(), anyhow::Error> {
             "vector",
             DataType::FixedSizeList(
               Arc::new(Field::new("item", DataType::Float32, true)),
-              DIM as i32,
+              dim as i32,
             ),
             true,
           ),
@@ -243,7 +336,7 @@ fn main() -> Result<(), anyhow::Error> {
                   .collect::<Vec<Option<&str>>>(),
               )),
               Arc::new(
-                FixedSizeListArray::from_iter_primitive::<Float32Type, _, _>(vectors, DIM as i32),
+                FixedSizeListArray::from_iter_primitive::<Float32Type, _, _>(vectors, dim as i32),
               ),
             ],
           )?]